@@ -0,0 +1,220 @@
+use crate::board::BoardProfile;
+use crate::events::DeviceRef;
+use crate::flash::FlashOptions;
+use crate::jobs::{select_devices, DeviceSelection, JobManager};
+use crate::preflight::{self, has_errors};
+use crate::FlashInfo;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+// REST endpoints, all JSON:
+//   GET  /api/devices              connected devices
+//   GET  /api/versions             versions in the version directory
+//   GET  /api/boards               board profiles
+//   GET  /api/jobs                 job reports, without event logs
+//   POST /api/jobs                 start a job, body is `JobRequest`. 400 with
+//                                  the preflight `findings` when it can't run
+//   GET  /api/jobs/{id}            full report of a job, without progress events
+//   POST /api/jobs/{id}/stop       stop a running job
+//   GET  /api/events               WebSocket, every job event as it happens
+
+#[derive(Debug, Deserialize)]
+struct JobRequest {
+    board: String,
+    version: String,
+    /// `["all"]` or LocationIDs.
+    devices: Vec<String>,
+    /// "all" flashes every image, "erase" erases the flash. The default of
+    /// the board profile when not given.
+    #[serde(default)]
+    recipe: Option<String>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    skip_prepare: bool,
+    /// Values of the rootfs templates for this batch.
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceEntry {
+    #[serde(flatten)]
+    device: DeviceRef,
+    mode: String,
+}
+
+fn error_response(status: StatusCode, message: impl ToString) -> Response {
+    (status, Json(json!({ "error": message.to_string() }))).into_response()
+}
+
+async fn devices(State(manager): State<Arc<JobManager>>) -> Response {
+    let config = manager.config().clone();
+    match tokio::task::spawn_blocking(move || {
+        let mut flash_info = FlashInfo::default();
+        flash_info.update_device_list(&config);
+        flash_info.devices
+    })
+    .await
+    {
+        Ok(devices) => Json(
+            devices
+                .iter()
+                .map(|d| DeviceEntry {
+                    device: DeviceRef::from(d),
+                    mode: d.mode.clone(),
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn versions(State(manager): State<Arc<JobManager>>) -> Json<Vec<String>> {
+    Json(FlashInfo::load_versions(manager.config()))
+}
+
+async fn boards(State(manager): State<Arc<JobManager>>) -> Json<Vec<BoardProfile>> {
+    Json(manager.config().boards.values().cloned().collect())
+}
+
+async fn list_jobs(State(manager): State<Arc<JobManager>>) -> Response {
+    Json(manager.jobs()).into_response()
+}
+
+async fn start_job(
+    State(manager): State<Arc<JobManager>>,
+    Json(request): Json<JobRequest>,
+) -> Response {
+    let config = manager.config().clone();
+    let recipe = request
+        .recipe
+        .unwrap_or_else(|| config.default_recipe(&request.board));
+    let options = FlashOptions {
+        dry_run: request.dry_run,
+        skip_prepare: request.skip_prepare,
+        variables: request.variables,
+    };
+    let findings = {
+        let config = config.clone();
+        let (board, version, recipe, options) = (
+            request.board.clone(),
+            request.version.clone(),
+            recipe.clone(),
+            options.clone(),
+        );
+        match tokio::task::spawn_blocking(move || {
+            preflight::check(&config, &board, &version, &recipe, &options)
+        })
+        .await
+        {
+            Ok(findings) => findings,
+            Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+        }
+    };
+    if has_errors(&findings) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "preflight checks failed", "findings": findings })),
+        )
+            .into_response();
+    }
+    let selection = DeviceSelection::from_list(request.devices);
+    let mut flash_info =
+        match tokio::task::spawn_blocking(move || select_devices(&config, &selection))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|selected| selected)
+        {
+            Ok(flash_info) => flash_info,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+        };
+    flash_info.board_type = request.board;
+    flash_info.version_selected = request.version;
+
+    match manager.start(flash_info, &recipe, options) {
+        Ok(job_id) => (StatusCode::ACCEPTED, Json(json!({ "job_id": job_id }))).into_response(),
+        Err(e) => error_response(StatusCode::CONFLICT, e),
+    }
+}
+
+async fn job_report(State(manager): State<Arc<JobManager>>, Path(id): Path<String>) -> Response {
+    match manager.report(&id) {
+        Some(report) => Json(report).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, format!("no job `{}`", id)),
+    }
+}
+
+async fn stop_job(State(manager): State<Arc<JobManager>>, Path(id): Path<String>) -> Response {
+    if manager.stop(&id) {
+        Json(json!({ "job_id": id })).into_response()
+    } else {
+        error_response(StatusCode::NOT_FOUND, format!("no running job `{}`", id))
+    }
+}
+
+async fn events(State(manager): State<Arc<JobManager>>, ws: WebSocketUpgrade) -> Response {
+    let events = manager.subscribe();
+    ws.on_upgrade(move |socket| forward_events(socket, events))
+}
+
+async fn forward_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<crate::events::Event>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if socket
+            .send(Message::Text(event.to_json().into()))
+            .await
+            .is_err()
+        {
+            // Client went away.
+            break;
+        }
+    }
+}
+
+fn router(manager: Arc<JobManager>) -> Router {
+    Router::new()
+        .route("/api/devices", get(devices))
+        .route("/api/versions", get(versions))
+        .route("/api/boards", get(boards))
+        .route("/api/jobs", get(list_jobs).post(start_job))
+        .route("/api/jobs/{id}", get(job_report))
+        .route("/api/jobs/{id}/stop", post(stop_job))
+        .route("/api/events", get(events))
+        .with_state(manager)
+}
+
+/// Serve the control API on `addr` until the process exits.
+pub async fn serve(addr: String, manager: Arc<JobManager>) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    info!("Control API listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(manager)).await
+}
+
+/// Run `serve` in the background, logging instead of failing when the
+/// address can't be bound.
+pub fn spawn(addr: String, manager: Arc<JobManager>) {
+    tokio::spawn(async move {
+        if let Err(e) = serve(addr.clone(), manager).await {
+            error!("Control API on {} failed: {}", addr, e);
+        }
+    });
+}
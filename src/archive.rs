@@ -1,37 +1,831 @@
+use crate::ext4::{Inode, Metadata};
+use flate2::read::GzDecoder;
+use libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tar::{Archive, EntryType};
 use zip::ZipArchive;
 
-fn extract_zip(file_path: &str, destination: &str) -> io::Result<()> {
-    // 打开 ZIP 文件
-    let zip_file = File::open(file_path)?;
-    let mut archive = ZipArchive::new(std::io::BufReader::new(zip_file))?;
+// Version packages, the update-rootfs overlay and the filesystem archive
+// inside a package are unpacked in-process. zip, tar, tar.gz, tar.xz and
+// tar.zst are recognised by their first bytes, whatever the file is called.
+//
+// Every entry has to stay inside the destination: absolute paths and `..`
+// are rejected, so are symlinks whose relative target climbs out of it and
+// entries that would be written through a symlink unpacked earlier. Absolute
+// symlink targets are kept, they point into the rootfs being built, not into
+// the host. A corrupt archive fails the whole extraction.
+//
+// Unpacked files and directories stay readable and writable for us. Their
+// real modes, owners, xattrs and the device nodes we can't create come back
+// from `extract` as ext4 `Metadata`, to be applied when the image is built.
 
-    // 解压 ZIP 文件中的每个文件
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let outpath = std::path::Path::new(destination).join(file.sanitized_name());
+// Stripped from file names to get the package name, e.g. `v2.0.tar.zst`.
+const EXTENSIONS: &[&str] = &[
+    ".tar.gz", ".tar.xz", ".tar.zst", ".tgz", ".txz", ".tzst", ".tar", ".zip",
+];
 
-        if file.is_dir() {
-            // 创建文件夹
-            std::fs::create_dir_all(&outpath)?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+impl Format {
+    fn detect_bytes(head: &[u8]) -> Option<Format> {
+        if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            Some(Format::Zip)
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            Some(Format::TarGz)
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Format::TarXz)
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Format::TarZst)
+        } else if head.get(257..262) == Some(b"ustar") {
+            Some(Format::Tar)
         } else {
-            // 创建文件
-            if let Some(p) = outpath.parent() {
-                if !p.exists() {
-                    std::fs::create_dir_all(p)?;
+            None
+        }
+    }
+
+    /// What kind of archive the file at `path` is.
+    pub fn detect(path: &Path) -> Result<Format, String> {
+        let mut head = Vec::with_capacity(512);
+        File::open(path)
+            .and_then(|file| file.take(512).read_to_end(&mut head))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Format::detect_bytes(&head)
+            .ok_or_else(|| format!("{}: not a zip or tar archive", path.display()))
+    }
+}
+
+/// File name of `path` without its archive extension.
+pub fn package_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let stem = known_extension(name).unwrap_or(name);
+    Some(stem.to_string())
+}
+
+fn known_extension(name: &str) -> Option<&str> {
+    EXTENSIONS.iter().find_map(|ext| name.strip_suffix(ext))
+}
+
+/// Archives in `dir` by package name: files with an archive extension, and
+/// others that are archives by content. The first by file name wins a name
+/// clash.
+pub fn list_packages(dir: &Path) -> Vec<(String, PathBuf)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+    let mut packages: Vec<(String, PathBuf)> = vec![];
+    for path in paths {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        // A damaged package still shows up, and fails when it is unpacked.
+        let name = match known_extension(name) {
+            Some(stem) => stem.to_string(),
+            None if Format::detect(&path).is_ok() => name.to_string(),
+            None => continue,
+        };
+        if packages.iter().all(|(known, _)| *known != name) {
+            packages.push((name, path));
+        }
+    }
+    packages.sort();
+    packages
+}
+
+/// The archive in `dir` whose package name is `name`.
+pub fn find_package(dir: &Path, name: &str) -> Option<PathBuf> {
+    list_packages(dir)
+        .into_iter()
+        .find(|(package, _)| package == name)
+        .map(|(_, path)| path)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Dir,
+    File,
+    Symlink(PathBuf),
+    /// Path of the earlier entry it links to.
+    HardLink(PathBuf),
+    /// Device nodes and fifos, by their `S_IF*` type and device number.
+    Special(u32, Option<(u32, u32)>),
+}
+
+/// One member of an archive.
+pub struct Entry<'a> {
+    /// As stored in the archive, not checked yet.
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Extended attributes by name, from PAX headers.
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    pub size: u64,
+    /// Seconds since the epoch.
+    pub mtime: Option<u64>,
+    pub data: &'a mut dyn Read,
+}
+
+impl Entry<'_> {
+    /// The inode the member describes. Hard links share the one of their
+    /// source, theirs is a placeholder.
+    pub fn inode(&self) -> Inode {
+        let permissions = |default: u32| self.mode.map_or(default, |mode| mode & 0o7777);
+        let (mode, rdev) = match &self.kind {
+            Kind::Dir => (S_IFDIR | permissions(0o755), None),
+            Kind::File | Kind::HardLink(_) => (S_IFREG | permissions(0o644), None),
+            Kind::Symlink(_) => (S_IFLNK | 0o777, None),
+            Kind::Special(kind, rdev) => (kind | permissions(0o644), *rdev),
+        };
+        Inode {
+            mode,
+            uid: self.uid.unwrap_or(0),
+            gid: self.gid.unwrap_or(0),
+            rdev,
+            xattrs: self.xattrs.clone(),
+        }
+    }
+}
+
+pub type Visit<'a> = &'a mut (dyn FnMut(Entry) -> Result<(), String> + Send);
+
+/// Called with the bytes of the archive read so far and its size. An error
+/// stops the walk, e.g. when the job is cancelled.
+pub type Progress<'a> = &'a mut (dyn FnMut(u64, u64) -> Result<(), String> + Send);
+
+// Counts the bytes read from the archive file.
+struct Counted<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+// Progress is also reported while a big entry is read.
+const REPORT_EVERY: u64 = 1024 * 1024;
+
+// Calls `report` with the bytes read from an entry so far, every
+// REPORT_EVERY bytes.
+struct Reporting<'a, R> {
+    inner: R,
+    read: u64,
+    reported: u64,
+    report: &'a mut dyn FnMut(u64) -> Result<(), String>,
+}
+
+impl<'a, R> Reporting<'a, R> {
+    fn new(inner: R, report: &'a mut dyn FnMut(u64) -> Result<(), String>) -> Self {
+        Reporting {
+            inner,
+            read: 0,
+            reported: 0,
+            report,
+        }
+    }
+}
+
+impl<R: Read> Read for Reporting<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.read - self.reported >= REPORT_EVERY {
+            self.reported = self.read;
+            (self.report)(self.read).map_err(io::Error::other)?;
+        }
+        Ok(n)
+    }
+}
+
+/// Call `visit` for every member of the archive at `archive`.
+pub fn walk(archive: &Path, visit: Visit, progress: Progress) -> Result<(), String> {
+    let context = |e: &dyn std::fmt::Display| format!("{}: {}", archive.display(), e);
+    let format = Format::detect(archive)?;
+    let file = File::open(archive).map_err(|e| context(&e))?;
+    let total = file.metadata().map_err(|e| context(&e))?.len();
+    progress(0, total)?;
+
+    if format == Format::Zip {
+        let mut zip = ZipArchive::new(BufReader::new(file)).map_err(|e| context(&e))?;
+        let mut done = 0;
+        for index in 0..zip.len() {
+            let mut member = zip.by_index(index).map_err(|e| context(&e))?;
+            let name = PathBuf::from(member.name());
+            let (before, compressed, size) = (done, member.compressed_size(), member.size());
+            done += compressed;
+            let mode = member.unix_mode();
+            let kind = if member.is_dir() {
+                Kind::Dir
+            } else if mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+                let mut target = String::new();
+                member
+                    .read_to_string(&mut target)
+                    .map_err(|e| context(&format!("{}: {}", name.display(), e)))?;
+                Kind::Symlink(PathBuf::from(target))
+            } else {
+                Kind::File
+            };
+            // Compressed bytes aren't counted while the entry is read, its
+            // share of them is.
+            let mut report = |read: u64| {
+                let share = (read as u128 * compressed as u128)
+                    .checked_div(size as u128)
+                    .unwrap_or(0) as u64;
+                progress(before + share.min(compressed), total)
+            };
+            let entry = Entry {
+                path: name,
+                kind,
+                mode,
+                uid: None,
+                gid: None,
+                xattrs: BTreeMap::new(),
+                size,
+                mtime: zip_mtime(member.last_modified()),
+                data: &mut Reporting::new(&mut member, &mut report),
+            };
+            visit(entry).map_err(|e| context(&e))?;
+            progress(done, total)?;
+        }
+        return progress(total, total);
+    }
+
+    let count = Arc::new(AtomicU64::new(0));
+    let mut reader = Counted {
+        inner: BufReader::new(file),
+        count: count.clone(),
+    };
+    let mut report = |_| progress(count.load(Ordering::Relaxed), total);
+    decode(format, &mut reader, &mut |tar| {
+        walk_tar(tar, visit, &mut report)
+    })
+    .map_err(|e| context(&e))?;
+    progress(total, total)
+}
+
+// Zip members carry a local time without a zone, it is taken as UTC so the
+// same archive unpacks the same everywhere.
+fn zip_mtime(time: Option<zip::DateTime>) -> Option<u64> {
+    let time = time?;
+    let stamp = chrono::NaiveDate::from_ymd_opt(
+        time.year().into(),
+        time.month().into(),
+        time.day().into(),
+    )?
+    .and_hms_opt(
+        time.hour().into(),
+        time.minute().into(),
+        time.second().into(),
+    )?
+    .and_utc()
+    .timestamp();
+    u64::try_from(stamp).ok()
+}
+
+/// Call `visit` for every member of the archive read from `reader`, e.g. an
+/// archive inside another one.
+pub fn walk_reader(reader: &mut dyn Read, visit: Visit) -> Result<(), String> {
+    let mut head = Vec::with_capacity(512);
+    reader
+        .take(512)
+        .read_to_end(&mut head)
+        .map_err(|e| e.to_string())?;
+    let format = Format::detect_bytes(&head).ok_or("not a zip or tar archive")?;
+    let mut reader = Cursor::new(head).chain(reader);
+
+    if format == Format::Zip {
+        // Read front to back, without the central directory.
+        while let Some(mut member) =
+            zip::read::read_zipfile_from_stream(&mut reader).map_err(|e| e.to_string())?
+        {
+            let kind = if member.is_dir() {
+                Kind::Dir
+            } else {
+                Kind::File
+            };
+            let entry = Entry {
+                path: PathBuf::from(member.name()),
+                kind,
+                mode: None,
+                uid: None,
+                gid: None,
+                xattrs: BTreeMap::new(),
+                size: member.size(),
+                mtime: zip_mtime(member.last_modified()),
+                data: &mut member,
+            };
+            visit(entry)?;
+        }
+        return Ok(());
+    }
+    decode(format, &mut reader, &mut |tar| {
+        walk_tar(tar, visit, &mut |_| Ok(()))
+    })
+}
+
+// Run `f` on the decompressed stream of `reader`.
+fn decode(
+    format: Format,
+    reader: &mut dyn Read,
+    f: &mut (dyn FnMut(&mut dyn Read) -> Result<(), String> + Send),
+) -> Result<(), String> {
+    match format {
+        Format::Zip => Err("not a tar archive".to_string()),
+        Format::Tar => f(reader),
+        Format::TarGz => f(&mut GzDecoder::new(reader)),
+        Format::TarZst => f(&mut zstd::Decoder::new(reader).map_err(|e| e.to_string())?),
+        Format::TarXz => {
+            // lzma-rs only writes its output, the tarball is read from a pipe
+            // on another thread.
+            let (mut tar, mut xz) = io::pipe().map_err(|e| e.to_string())?;
+            thread::scope(|scope| {
+                let walked = scope.spawn(move || f(&mut tar));
+                let decoded = lzma_rs::xz_decompress(&mut BufReader::new(reader), &mut xz);
+                drop(xz);
+                let walked = walked
+                    .join()
+                    .unwrap_or_else(|_| Err("tar reader panicked".to_string()));
+                match decoded {
+                    // The walk stopped early and closed the pipe.
+                    Err(lzma_rs::error::Error::IoError(e))
+                        if e.kind() == io::ErrorKind::BrokenPipe && walked.is_err() =>
+                    {
+                        walked
+                    }
+                    // A corrupt stream also breaks the tar reader, this is
+                    // the error that says why.
+                    Err(e) => Err(format!("xz: {:?}", e)),
+                    Ok(()) => walked,
+                }
+            })
+        }
+    }
+}
+
+fn walk_tar(
+    reader: &mut dyn Read,
+    visit: Visit,
+    report: &mut dyn FnMut(u64) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut tar = Archive::new(reader);
+    for member in tar.entries().map_err(|e| e.to_string())? {
+        let mut member = member.map_err(|e| e.to_string())?;
+        let path = member.path().map_err(|e| e.to_string())?.into_owned();
+        let context = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let link = member
+            .link_name()
+            .map_err(|e| context(&e))?
+            .map(|link| link.into_owned());
+        // GNU tar, bsdtar and star all write xattrs as SCHILY.xattr records.
+        let mut xattrs = BTreeMap::new();
+        let (mut uid, mut gid) = (None, None);
+        if let Some(extensions) = member.pax_extensions().map_err(|e| context(&e))? {
+            for extension in extensions {
+                let extension = extension.map_err(|e| context(&e))?;
+                let Ok(key) = extension.key() else {
+                    continue;
+                };
+                let value = extension.value_bytes();
+                let number = || std::str::from_utf8(value).ok()?.parse::<u32>().ok();
+                if let Some(name) = key.strip_prefix("SCHILY.xattr.") {
+                    xattrs.insert(name.to_string(), value.to_vec());
+                } else if key == "uid" {
+                    uid = number();
+                } else if key == "gid" {
+                    gid = number();
                 }
             }
-            let mut outfile = File::create(&outpath)?;
-            io::copy(&mut file, &mut outfile)?;
         }
+        let header = member.header();
+        let rdev = match (header.device_major(), header.device_minor()) {
+            (Ok(Some(major)), Ok(Some(minor))) => Some((major, minor)),
+            _ => None,
+        };
+        let kind = match (header.entry_type(), link) {
+            (EntryType::Directory, _) => Kind::Dir,
+            (EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse, _) => Kind::File,
+            (EntryType::Symlink, Some(link)) => Kind::Symlink(link),
+            (EntryType::Link, Some(link)) => Kind::HardLink(link),
+            (EntryType::Symlink | EntryType::Link, None) => {
+                return Err(context(&"link without a target"))
+            }
+            (EntryType::Char, _) => Kind::Special(S_IFCHR, rdev),
+            (EntryType::Block, _) => Kind::Special(S_IFBLK, rdev),
+            (EntryType::Fifo, _) => Kind::Special(S_IFIFO, None),
+            // PAX and GNU headers are read by the tar crate.
+            _ => continue,
+        };
+        let entry = Entry {
+            mode: header.mode().ok(),
+            uid: uid.or_else(|| header.uid().ok().and_then(|uid| uid.try_into().ok())),
+            gid: gid.or_else(|| header.gid().ok().and_then(|gid| gid.try_into().ok())),
+            xattrs,
+            mtime: header.mtime().ok(),
+            size: member.size(),
+            path: path.clone(),
+            kind,
+            data: &mut Reporting::new(&mut member, report),
+        };
+        visit(entry)?;
+        report(0)?;
+    }
+    // Read to the end so the checksum of the compressed stream is checked.
+    io::copy(tar.into_inner(), &mut io::sink()).map_err(|e| e.to_string())?;
+    Ok(())
+}
 
-        // 设置解压文件的权限
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            if let Some(mode) = file.unix_mode() {
-                std::fs::set_permissions(&outpath, std::fs::Permissions::from_mode(mode))?;
+/// `name` as a path below the destination, None for the destination itself.
+pub fn entry_path(name: &Path) -> Result<Option<PathBuf>, String> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(format!("{} points outside the archive", name.display()));
             }
         }
     }
+    Ok((!path.as_os_str().is_empty()).then_some(path))
+}
+
+// Directories stay writable for us, the rest of the archive goes into them,
+// and files stay readable to be copied on.
+fn dir_mode(mode: u32) -> u32 {
+    (mode & 0o7777) | 0o700
+}
+
+fn file_mode(mode: u32) -> u32 {
+    (mode & 0o7777) | 0o600
+}
+
+/// Set the modification and access time of `path` without following a
+/// symlink there.
+pub fn set_mtime(path: &Path, seconds: i64, nanoseconds: i64) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let time = libc::timespec {
+        tv_sec: seconds as libc::time_t,
+        tv_nsec: nanoseconds as _,
+    };
+    let times = [time, time];
+    // SAFETY: `path` is NUL terminated and `times` holds two timespecs.
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Extended attributes of `path` itself, not of a symlink's target.
+pub fn read_xattrs(path: &Path) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let mut xattrs = BTreeMap::new();
+    // SAFETY: each call gets a NUL terminated path and a buffer of the
+    // length it is told, or none to ask for the size.
+    let names = unsafe {
+        let size = libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0);
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            // Filesystems without xattrs.
+            if e.raw_os_error() == Some(libc::ENOTSUP) {
+                return Ok(xattrs);
+            }
+            return Err(e);
+        }
+        let mut names = vec![0u8; size as usize];
+        let size = libc::llistxattr(c_path.as_ptr(), names.as_mut_ptr().cast(), names.len());
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        names.truncate(size as usize);
+        names
+    };
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name).map_err(io::Error::other)?;
+        // SAFETY: as above.
+        let value = unsafe {
+            let size = libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut value = vec![0u8; size as usize];
+            let size = libc::lgetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            );
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            value.truncate(size as usize);
+            value
+        };
+        xattrs.insert(String::from_utf8_lossy(name).into_owned(), value);
+    }
+    Ok(xattrs)
+}
+
+// A relative symlink target must not climb above the destination.
+fn check_link_target(link: &Path, target: &Path) -> Result<(), String> {
+    if target.is_absolute() {
+        return Ok(());
+    }
+    let mut depth = link.components().count() - 1;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            _ if depth == 0 => {
+                return Err(format!(
+                    "symlink {} -> {} points outside the archive",
+                    link.display(),
+                    target.display()
+                ));
+            }
+            _ => depth -= 1,
+        }
+    }
     Ok(())
 }
+
+/// Where `entry` goes below the destination, None for the destination
+/// itself. Paths and relative symlink targets that climb out of it are
+/// errors.
+pub fn member_path(entry: &Entry) -> Result<Option<PathBuf>, String> {
+    let path = entry_path(&entry.path)?;
+    if let (Some(path), Kind::Symlink(target)) = (&path, &entry.kind) {
+        check_link_target(path, target)?;
+    }
+    Ok(path)
+}
+
+// Make sure nothing on the way to `path` is a symlink, and that an old
+// symlink at `path` itself is replaced instead of followed.
+fn prepare_target(dest: &Path, path: &Path) -> Result<PathBuf, String> {
+    let mut current = dest.to_path_buf();
+    if let Some(parent) = path.parent() {
+        for component in parent.components() {
+            current.push(component);
+            if let Ok(metadata) = fs::symlink_metadata(&current) {
+                if metadata.file_type().is_symlink() {
+                    return Err(format!(
+                        "{} would be written through the symlink {}",
+                        path.display(),
+                        current.display()
+                    ));
+                }
+            }
+        }
+    }
+    let target = dest.join(path);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+    }
+    if let Ok(metadata) = fs::symlink_metadata(&target) {
+        if !metadata.is_dir() {
+            fs::remove_file(&target).map_err(|e| format!("{}: {}", target.display(), e))?;
+        }
+    }
+    Ok(target)
+}
+
+// The existing file a hard link points to, which must not be reached through
+// a symlink either.
+fn link_source(dest: &Path, path: &Path) -> Result<PathBuf, String> {
+    let mut current = dest.to_path_buf();
+    for component in path.components() {
+        current.push(component);
+        let metadata =
+            fs::symlink_metadata(&current).map_err(|e| format!("{}: {}", current.display(), e))?;
+        if metadata.file_type().is_symlink() {
+            return Err(format!(
+                "hard link to {} goes through the symlink {}",
+                path.display(),
+                current.display()
+            ));
+        }
+    }
+    Ok(current)
+}
+
+/// Unpack the entries of the archive at `archive` that `wanted` says yes to
+/// by their path below `dest`, whatever its format. Returns the modes, owners
+/// and xattrs of the entries by path below `dest`, and the device nodes and
+/// fifos that weren't created.
+pub fn extract(
+    archive: &Path,
+    dest: &Path,
+    wanted: &(dyn Fn(&Path) -> bool + Sync),
+    progress: Progress,
+) -> Result<Metadata, String> {
+    fs::create_dir_all(dest).map_err(|e| format!("{}: {}", dest.display(), e))?;
+    let mut metadata = Metadata::new();
+    let mut dirs = vec![];
+    walk(
+        archive,
+        &mut |entry| match entry_path(&entry.path)? {
+            Some(path) if wanted(&path) => write_entry(dest, entry, &mut metadata, &mut dirs),
+            _ => Ok(()),
+        },
+        progress,
+    )?;
+    // Unpacking into a directory changes its time, so they come last.
+    for (dir, mtime) in dirs.iter().rev() {
+        let _ = set_mtime(dir, *mtime as i64, 0);
+    }
+    Ok(metadata)
+}
+
+fn write_entry(
+    dest: &Path,
+    entry: Entry,
+    metadata: &mut Metadata,
+    dirs: &mut Vec<(PathBuf, u64)>,
+) -> Result<(), String> {
+    let Some(path) = entry_path(&entry.path)? else {
+        return Ok(());
+    };
+    let context = |e: &dyn std::fmt::Display| format!("{}: {}", entry.path.display(), e);
+    let inode = entry.inode();
+
+    let target = prepare_target(dest, &path)?;
+    match &entry.kind {
+        Kind::Dir => {
+            fs::create_dir_all(&target).map_err(|e| context(&e))?;
+            fs::set_permissions(&target, fs::Permissions::from_mode(dir_mode(inode.mode)))
+                .map_err(|e| context(&e))?;
+            if let Some(mtime) = entry.mtime {
+                dirs.push((target, mtime));
+            }
+        }
+        Kind::File => {
+            let mut out = File::create(&target).map_err(|e| context(&e))?;
+            // CRC errors of a damaged archive show up while reading.
+            io::copy(entry.data, &mut out).map_err(|e| context(&e))?;
+            drop(out);
+            fs::set_permissions(&target, fs::Permissions::from_mode(file_mode(inode.mode)))
+                .map_err(|e| context(&e))?;
+            if let Some(mtime) = entry.mtime {
+                let _ = set_mtime(&target, mtime as i64, 0);
+            }
+        }
+        Kind::Symlink(link) => {
+            check_link_target(&path, link)?;
+            symlink(link, &target).map_err(|e| context(&e))?;
+            if let Some(mtime) = entry.mtime {
+                let _ = set_mtime(&target, mtime as i64, 0);
+            }
+        }
+        Kind::HardLink(link) => {
+            let source =
+                entry_path(link)?.ok_or_else(|| context(&"hard link to the archive root"))?;
+            let source_path = link_source(dest, &source)?;
+            fs::hard_link(&source_path, &target).map_err(|e| context(&e))?;
+            // One inode, whatever the header of the link says.
+            if let Some(linked) = metadata.get(&source) {
+                metadata.insert(path, linked.clone());
+            }
+            return Ok(());
+        }
+        Kind::Special(..) => {
+            // Can't be created without root, `ext4::build` makes them.
+            metadata.insert(path, inode);
+            return Ok(());
+        }
+    }
+    metadata.insert(path, inode);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{tar_archive, Member, TempDir};
+
+    fn extract_all(dir: &TempDir, archive: &[u8]) -> Result<Metadata, String> {
+        let path = dir.path().join("archive.tar");
+        fs::write(&path, archive).unwrap();
+        extract(&path, &dir.path().join("dest"), &|_| true, &mut |_, _| {
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn entry_paths() {
+        let path = |name: &str| entry_path(Path::new(name));
+        assert_eq!(path("etc/passwd"), Ok(Some(PathBuf::from("etc/passwd"))));
+        assert_eq!(
+            path("./etc/./passwd"),
+            Ok(Some(PathBuf::from("etc/passwd")))
+        );
+        assert_eq!(path("./"), Ok(None));
+        assert_eq!(path("."), Ok(None));
+        assert!(path("../etc/passwd").is_err());
+        assert!(path("etc/../../passwd").is_err());
+        assert!(path("etc/../passwd").is_err());
+        assert!(path("/etc/passwd").is_err());
+        assert!(path("./../etc").is_err());
+    }
+
+    #[test]
+    fn link_targets() {
+        let check =
+            |link: &str, target: &str| check_link_target(Path::new(link), Path::new(target));
+        assert!(check("usr/lib/libc.so", "../../lib/libc.so").is_ok());
+        assert!(check("usr/lib/libc.so", "./libc.so.6").is_ok());
+        assert!(check("bin", "usr/bin").is_ok());
+        assert!(check("lib", "/usr/lib").is_ok());
+        assert!(check("bin", "../usr/bin").is_err());
+        assert!(check("usr/lib/libc.so", "../../../lib/libc.so").is_err());
+        assert!(check("usr/lib/x", "a/../../../../etc").is_err());
+        assert!(check("usr/x", "./../../etc").is_err());
+    }
+
+    #[test]
+    fn symlinks_escaping_are_refused() {
+        let dir = TempDir::new();
+        let archive = tar_archive(&[("usr/up", Member::Symlink("../../etc"))]);
+        let error = extract_all(&dir, &archive).unwrap_err();
+        assert!(error.contains("points outside the archive"), "{}", error);
+        assert!(fs::symlink_metadata(dir.path().join("dest/usr/up")).is_err());
+    }
+
+    #[test]
+    fn paths_escaping_are_refused() {
+        for name in ["../evil", "/evil", "usr/../../evil"] {
+            let dir = TempDir::new();
+            let archive = tar_archive(&[(name, Member::File("evil"))]);
+            let error = extract_all(&dir, &archive).unwrap_err();
+            assert!(error.contains("points outside the archive"), "{}", error);
+            assert!(!dir.path().join("evil").exists());
+        }
+    }
+
+    #[test]
+    fn nothing_is_written_through_a_symlink() {
+        let dir = TempDir::new();
+        let archive = tar_archive(&[
+            ("usr/lib/", Member::Dir),
+            ("lib", Member::Symlink("usr/lib")),
+            ("lib/evil", Member::File("evil")),
+        ]);
+        let error = extract_all(&dir, &archive).unwrap_err();
+        assert!(
+            error.contains("would be written through the symlink"),
+            "{}",
+            error
+        );
+        assert!(!dir.path().join("dest/usr/lib/evil").exists());
+    }
+
+    #[test]
+    fn symlinks_are_replaced_not_followed() {
+        let dir = TempDir::new();
+        let archive = tar_archive(&[
+            ("target", Member::File("target")),
+            ("link", Member::Symlink("target")),
+            ("link", Member::File("link")),
+        ]);
+        extract_all(&dir, &archive).unwrap();
+        let dest = dir.path().join("dest");
+        assert!(fs::symlink_metadata(dest.join("link")).unwrap().is_file());
+        assert_eq!(fs::read_to_string(dest.join("link")).unwrap(), "link");
+        assert_eq!(fs::read_to_string(dest.join("target")).unwrap(), "target");
+    }
+}
@@ -0,0 +1,114 @@
+use crate::rootfs::FsType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// A board profile says how the rootfs of a board is put together from the
+// version package. Profiles live in `[boards.<id>]` tables of the station
+// config, the id is what `--board` and the API take:
+//
+//   [boards.dc21scu]
+//   name = "DC21 SCU"               # shown in the Board combo box
+//   subtree = "scu/filesystem"      # directory of the version package
+//   bin = "board/dc21scu.bin"       # installed to mnt/build/
+//   hostname = "dc21scu"
+//   overlays = ["overlays/scu"]     # station directories copied on top
+//   manifest = "layouts/scu.toml"   # rootfs layout, see manifest.rs
+//   chip = "RK3568"
+//   recipe = "all"
+//   filesystem = "squashfs"         # of the rootfs, the base image's by default
+//   sparse = true                   # ext4 rootfs as an Android sparse image
+//   fs_uuid = "0b7e…"               # of the rootfs, derived from board and version
+//
+//   [boards.dc21scu.variables]      # filled into the rootfs templates
+//   ntp_server = "ntp.example.com"
+//
+// Only `subtree` is needed, everything else has a default derived from the id.
+
+/// Used when the station config doesn't define any board.
+const BUILTIN_BOARDS: &str = r#"
+[boards.dc11p626]
+subtree = "board/filesystem"
+
+[boards.dc21scu]
+subtree = "scu/filesystem"
+"#;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BoardProfile {
+    #[serde(skip_deserializing)]
+    pub id: String,
+    /// The id by default.
+    #[serde(default)]
+    pub name: String,
+    /// Directory of the unpacked version package whose etc, mnt, root and usr
+    /// are copied onto the rootfs.
+    pub subtree: PathBuf,
+    /// Board binary in the version package, `board/<id>.bin` by default.
+    #[serde(default)]
+    pub bin: PathBuf,
+    /// The id by default.
+    #[serde(default)]
+    pub hostname: String,
+    /// Directories copied onto the rootfs root after the version contents,
+    /// relative to the config file they are defined in.
+    #[serde(default)]
+    pub overlays: Vec<PathBuf>,
+    /// What goes where in the rootfs, relative to the config file it is
+    /// defined in. The version package's or the built-in one otherwise.
+    #[serde(default)]
+    pub manifest: Option<PathBuf>,
+    /// Chip the board is built around, e.g. `RK3568`.
+    #[serde(default)]
+    pub chip: Option<String>,
+    /// Recipe used when none is asked for, the station default otherwise.
+    #[serde(default)]
+    pub recipe: Option<String>,
+    /// Filesystem of the rootfs built, the one of `rockdev/rootfs.img` by
+    /// default. Another one gets the default settings of its tools, and
+    /// ubifs is only built from a ubifs base image.
+    #[serde(default)]
+    pub filesystem: Option<FsType>,
+    /// Write the rootfs as an Android sparse image, only the blocks in use
+    /// go over USB.
+    #[serde(default)]
+    pub sparse: bool,
+    /// UUID of the rootfs, instead of one derived from board and version.
+    #[serde(default)]
+    pub fs_uuid: Option<String>,
+    /// Values of the rootfs templates, the batch can override them.
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+impl BoardProfile {
+    // Fill in what the config left out.
+    fn resolve(&mut self, id: &str) {
+        self.id = id.to_string();
+        if self.name.is_empty() {
+            self.name = id.to_string();
+        }
+        if self.hostname.is_empty() {
+            self.hostname = id.to_string();
+        }
+        if self.bin.as_os_str().is_empty() {
+            self.bin = PathBuf::from(format!("board/{}.bin", id));
+        }
+    }
+}
+
+pub fn builtin_boards() -> BTreeMap<String, BoardProfile> {
+    #[derive(Deserialize)]
+    struct Builtin {
+        boards: BTreeMap<String, BoardProfile>,
+    }
+    let builtin: Builtin = toml::from_str(BUILTIN_BOARDS).expect("built-in boards parse");
+    builtin.boards
+}
+
+/// Give every profile its id and defaults.
+pub fn resolve_boards(boards: &mut BTreeMap<String, BoardProfile>) {
+    for (id, profile) in boards.iter_mut() {
+        profile.resolve(id);
+    }
+}
@@ -0,0 +1,1039 @@
+use crate::archive;
+use crate::board::BoardProfile;
+use crate::changes::{self, Listing, Report};
+use crate::config::Config;
+use crate::reproducible;
+use chrono::{DateTime, FixedOffset};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use slint::{ComponentHandle, ModelRc, SharedString, StandardListViewItem, VecModel};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use walkdir::WalkDir;
+
+use crate::ui::*;
+
+// Prepared rootfs images are cached in `work_dir/cache`, one per board,
+// version and set of inputs:
+//
+//   cache/dc11p626-v2.0-1f3a9c0b5e2d4f67.img
+//   cache/dc11p626-v2.0-1f3a9c0b5e2d4f67.json   what it was built from
+//   cache/dc11p626-v2.0-1f3a9c0b5e2d4f67.files.json, .changes.json
+//                                               what it holds, see `changes`
+//   cache/hashes.json                           file hashes by size and mtime
+//   cache/.lock                                 held while entries are written
+//
+// `build-image` exports an image with its sidecar, listing and change report
+// to a directory, with a `SHA256SUMS` of them as `sha256sum` writes it, and
+// `cache import` takes them into the cache of a station. The station uses
+// them as long as its own inputs hash the same.
+//
+// The key hashes the board profile, the version, the batch variables and the
+// SHA-256 of every input, so a changed rootfs.img, update-rootfs archive,
+// version package, overlay or variable gives a new key and the image is
+// built again. The entry it replaces, of the same batch variables, is
+// removed once the new one is stored.
+//
+// Limits, in the `[cache]` table of the station config. The least recently
+// used images go first, after every build and with `rk_flash cache prune`:
+//
+//   [cache]
+//   max_size_mib = 8192
+//   max_age_days = 30
+
+const HASHES_FILE: &str = "hashes.json";
+const LOCK_FILE: &str = ".lock";
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+/// What a cached image is built from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub board: String,
+    pub version: String,
+    /// SHA-256 of each input by name, e.g. `rootfs.img`.
+    pub inputs: BTreeMap<String, String>,
+    /// Template variables given for the batch.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+    /// SHA-256 over the board, the version, the inputs and the variables.
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Total size of the cached images.
+    pub max_size_mib: Option<u64>,
+    /// Images not used for this long are removed.
+    pub max_age_days: Option<u64>,
+}
+
+/// Sidecar of a cached image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    #[serde(flatten)]
+    pub key: CacheKey,
+    pub created: String,
+    /// Last time a job used the image, `created` until then.
+    #[serde(default)]
+    pub last_used: String,
+    /// Size and SHA-256 of the image when it was stored.
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub sha256: String,
+    /// Summary of the filesystem check of the new image.
+    #[serde(default)]
+    pub fsck: String,
+    /// Every template variable the image was built with.
+    #[serde(default)]
+    pub values: BTreeMap<String, String>,
+}
+
+impl CacheEntry {
+    pub fn last_used(&self) -> DateTime<FixedOffset> {
+        let time = if self.last_used.is_empty() {
+            &self.created
+        } else {
+            &self.last_used
+        };
+        DateTime::parse_from_rfc3339(time).unwrap_or_default()
+    }
+}
+
+/// An image in the cache, as listed by `cache list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedImage {
+    /// File name without extension, what `cache delete` takes.
+    pub id: String,
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub entry: CacheEntry,
+}
+
+// Hash of a file as long as its size and mtime don't change, so the inputs
+// aren't read again for every job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct KnownHash {
+    size: u64,
+    mtime_ns: i128,
+    sha256: String,
+}
+
+pub fn cache_dir(config: &Config) -> PathBuf {
+    config.paths.work_dir.join("cache")
+}
+
+impl CacheKey {
+    /// Hash the inputs `board` and `version` are built from with the batch
+    /// `variables`.
+    pub fn compute(
+        config: &Config,
+        board: &BoardProfile,
+        version: &str,
+        variables: &BTreeMap<String, String>,
+    ) -> Result<Self, String> {
+        let mut hashes = HashStore::open(config);
+        let mut inputs = BTreeMap::new();
+        let update_rootfs = archive::find_package(&config.paths.image_dir, "update-rootfs")
+            .ok_or_else(|| {
+                format!(
+                    "no update-rootfs archive in {}",
+                    config.paths.image_dir.display()
+                )
+            })?;
+        let package = archive::find_package(&config.paths.version_dir, version)
+            .ok_or_else(|| format!("version `{}` not found", version))?;
+        for path in [
+            config.paths.image_dir.join("rootfs.img"),
+            update_rootfs,
+            package,
+        ] {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            inputs.insert(name.to_string(), hashes.file(&path)?);
+        }
+
+        let profile = serde_json::to_vec(board).map_err(|e| e.to_string())?;
+        inputs.insert("board".to_string(), hex(&Sha256::digest(&profile)));
+        if !board.overlays.is_empty() {
+            let mut overlays = Sha256::new();
+            for overlay in &board.overlays {
+                hash_tree(overlay, &mut hashes, &mut overlays)?;
+            }
+            inputs.insert("overlays".to_string(), hex(&overlays.finalize()));
+        }
+        if let Some(manifest) = &board.manifest {
+            inputs.insert("manifest".to_string(), hashes.file(manifest)?);
+        }
+        // Derived from the inputs above unless it is set.
+        if let Some(epoch) = reproducible::configured_epoch(config)? {
+            inputs.insert("source_date_epoch".to_string(), epoch.to_string());
+        }
+        hashes.save();
+
+        let mut key = Sha256::new();
+        for field in [&board.id, version] {
+            key.update(field.as_bytes());
+            key.update([0]);
+        }
+        for (name, hash) in &inputs {
+            key.update(name.as_bytes());
+            key.update([0]);
+            key.update(hash.as_bytes());
+            key.update([0]);
+        }
+        for (name, value) in variables {
+            key.update(b"var:");
+            key.update(name.as_bytes());
+            key.update([0]);
+            key.update(value.as_bytes());
+            key.update([0]);
+        }
+        Ok(Self {
+            board: board.id.clone(),
+            version: version.to_string(),
+            inputs,
+            variables: variables.clone(),
+            key: hex(&key.finalize()),
+        })
+    }
+
+    /// Id of the image of this key in the cache, e.g.
+    /// `dc11p626-v2.0-1f3a9c0b5e2d4f67`.
+    pub fn image_id(&self) -> String {
+        format!("{}-{}-{}", self.board, self.version, &self.key[..16])
+    }
+
+    /// Where the image of this key is cached.
+    pub fn image_path(&self, config: &Config) -> PathBuf {
+        cache_dir(config).join(format!("{}.img", self.image_id()))
+    }
+
+    fn entry_path(&self, config: &Config) -> PathBuf {
+        cache_dir(config).join(format!("{}.json", self.image_id()))
+    }
+}
+
+// Listing and change report of a cached image.
+fn files_path(image: &Path) -> PathBuf {
+    image.with_extension("files.json")
+}
+
+fn changes_path(image: &Path) -> PathBuf {
+    image.with_extension("changes.json")
+}
+
+/// The cached image of `key`, if there is one.
+pub fn lookup(config: &Config, key: &CacheKey) -> Option<PathBuf> {
+    let image = key.image_path(config);
+    let entry = fs::read(key.entry_path(config))
+        .ok()
+        .and_then(|data| serde_json::from_slice::<CacheEntry>(&data).ok())?;
+    (image.is_file() && entry.key == *key).then_some(image)
+}
+
+/// The cached image of `board` and `version` built from the current inputs
+/// and the batch `variables`.
+pub fn cached_image(
+    config: &Config,
+    board: &str,
+    version: &str,
+    variables: &BTreeMap<String, String>,
+) -> Result<PathBuf, String> {
+    let profile = config.board(board)?;
+    let key = CacheKey::compute(config, profile, version, variables)?;
+    lookup(config, &key).ok_or_else(|| {
+        format!(
+            "no rootfs of board `{}` and version {} built from the current inputs in {}",
+            board,
+            version,
+            cache_dir(config).display()
+        )
+    })
+}
+
+// Processes writing to the cache hold a shared lock on `.lock` in `dir`,
+// `remove_incomplete` an exclusive one, so it never sees what another
+// process is writing. The lock goes with the file, also when a process
+// crashes.
+fn lock_cache(dir: &Path, operation: libc::c_int) -> io::Result<File> {
+    fs::create_dir_all(dir)?;
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(dir.join(LOCK_FILE))?;
+    // SAFETY: flock only takes the descriptor, which `file` keeps open.
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Move the image built for `key` with the template `values` into the cache,
+/// with its `files` and the `changes` from the base, and drop the entries of
+/// the same board and version it replaces.
+pub fn store(
+    config: &Config,
+    key: &CacheKey,
+    built: &Path,
+    fsck: &str,
+    values: &BTreeMap<String, String>,
+    files: &Listing,
+    changes: &Report,
+) -> Result<PathBuf, String> {
+    let dir = cache_dir(config);
+    let _lock = lock_cache(&dir, libc::LOCK_SH).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let size = fs::metadata(built).map(|m| m.len()).unwrap_or(0);
+    let sha256 = sha256_file(built).map_err(|e| format!("{}: {}", built.display(), e))?;
+    let now = chrono::Local::now().to_rfc3339();
+    let entry = CacheEntry {
+        key: key.clone(),
+        created: now.clone(),
+        last_used: now,
+        size,
+        sha256,
+        fsck: fsck.to_string(),
+        values: values.clone(),
+    };
+    let data = serde_json::to_vec_pretty(&entry).map_err(|e| e.to_string())?;
+
+    // The sidecar goes in last, an image without one is incomplete.
+    let image = key.image_path(config);
+    for (path, data) in [
+        (files_path(&image), serde_json::to_vec(files)),
+        (changes_path(&image), serde_json::to_vec_pretty(changes)),
+    ] {
+        let data = data.map_err(|e| e.to_string())?;
+        fs::write(&path, data).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    let sidecar = key.entry_path(config);
+    let tmp = sidecar.with_extension("json.tmp");
+    fs::write(&tmp, data).map_err(|e| format!("{}: {}", tmp.display(), e))?;
+    fs::rename(built, &image)
+        .and_then(|_| fs::rename(&tmp, &sidecar))
+        .map_err(|e| {
+            for path in [&tmp, &image, &files_path(&image), &changes_path(&image)] {
+                let _ = fs::remove_file(path);
+            }
+            format!("{}: {}", image.display(), e)
+        })?;
+
+    for cached in list(config) {
+        if cached.entry.key.board == key.board
+            && cached.entry.key.version == key.version
+            && cached.entry.key.variables == key.variables
+            && cached.entry.key.key != key.key
+        {
+            info!("Removing stale cached rootfs {}", cached.path.display());
+            remove_entry(&cached.path);
+        }
+    }
+    prune(config, Some(&image));
+    Ok(image)
+}
+
+/// Record that a job is using the cached `image`.
+pub fn mark_used(image: &Path) {
+    let sidecar = image.with_extension("json");
+    let dir = image.parent().unwrap_or(Path::new("."));
+    let _lock = match lock_cache(dir, libc::LOCK_SH) {
+        Ok(lock) => lock,
+        Err(e) => {
+            debug!("Can't lock {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    let Some(mut entry) = read_entry(&sidecar) else {
+        return;
+    };
+    entry.last_used = chrono::Local::now().to_rfc3339();
+    // Readers see the old sidecar or the new one, never a part of it. Other
+    // processes may be marking the same image.
+    let tmp = sidecar.with_extension(format!("json.{}.tmp", std::process::id()));
+    let written = serde_json::to_vec_pretty(&entry)
+        .map_err(io::Error::other)
+        .and_then(|data| fs::write(&tmp, data))
+        .and_then(|_| fs::rename(&tmp, &sidecar));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        debug!("Can't update {}: {}", sidecar.display(), e);
+    }
+}
+
+/// Sidecar of the cached `image`.
+pub fn entry(image: &Path) -> Option<CacheEntry> {
+    read_entry(&image.with_extension("json"))
+}
+
+fn read_entry(sidecar: &Path) -> Option<CacheEntry> {
+    match fs::read(sidecar).map(|data| serde_json::from_slice::<CacheEntry>(&data)) {
+        Ok(Ok(entry)) => Some(entry),
+        Ok(Err(e)) => {
+            warn!("Ignoring {}: {}", sidecar.display(), e);
+            None
+        }
+        Err(e) => {
+            debug!("Can't read {}: {}", sidecar.display(), e);
+            None
+        }
+    }
+}
+
+/// Cached images, the most recently used first.
+pub fn list(config: &Config) -> Vec<CachedImage> {
+    let Ok(dir) = fs::read_dir(cache_dir(config)) else {
+        return vec![];
+    };
+    let mut images = vec![];
+    for path in dir.flatten().map(|entry| entry.path()) {
+        if path.extension().is_none_or(|ext| ext != "img") {
+            continue;
+        }
+        let Some(mut entry) = read_entry(&path.with_extension("json")) else {
+            continue;
+        };
+        if entry.size == 0 {
+            entry.size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        }
+        let id = path.file_stem().unwrap_or_default().to_string_lossy();
+        images.push(CachedImage {
+            id: id.to_string(),
+            path,
+            entry,
+        });
+    }
+    images.sort_by(|a, b| {
+        b.entry
+            .last_used()
+            .cmp(&a.entry.last_used())
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    images
+}
+
+fn find(config: &Config, id: &str) -> Result<CachedImage, String> {
+    list(config)
+        .into_iter()
+        .find(|image| image.id == id)
+        .ok_or_else(|| format!("no cached image `{}`", id))
+}
+
+/// An image and what is wrong with it, if anything.
+pub type Verified = (CachedImage, Result<(), String>);
+
+/// Check the cached image `id`, or all of them, against the hash recorded
+/// when it was stored.
+pub fn verify(config: &Config, id: Option<&str>) -> Result<Vec<Verified>, String> {
+    let images = match id {
+        Some(id) => vec![find(config, id)?],
+        None => list(config),
+    };
+    Ok(images
+        .into_iter()
+        .map(|image| {
+            let result = verify_image(&image);
+            (image, result)
+        })
+        .collect())
+}
+
+fn verify_image(image: &CachedImage) -> Result<(), String> {
+    if image.entry.sha256.is_empty() {
+        return Err("no hash recorded".to_string());
+    }
+    let size = fs::metadata(&image.path).map_err(|e| e.to_string())?.len();
+    if size != image.entry.size {
+        return Err(format!("size {} instead of {}", size, image.entry.size));
+    }
+    let sha256 = sha256_file(&image.path).map_err(|e| e.to_string())?;
+    if sha256 != image.entry.sha256 {
+        return Err(format!(
+            "SHA-256 {} instead of {}",
+            sha256, image.entry.sha256
+        ));
+    }
+    Ok(())
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path, id: &str) -> Result<T, String> {
+    let data = fs::read(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => {
+            format!("no change report of `{}`, it was prepared without one", id)
+        }
+        _ => format!("{}: {}", path.display(), e),
+    })?;
+    serde_json::from_slice(&data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// What the merge changed in the base rootfs for the cached image `id`.
+pub fn changes(config: &Config, id: &str) -> Result<Report, String> {
+    let image = find(config, id)?;
+    read_json(&changes_path(&image.path), id)
+}
+
+/// What differs from the cached image `from` to the cached image `to`.
+pub fn diff(config: &Config, from: &str, to: &str) -> Result<Report, String> {
+    let files = |id| -> Result<Listing, String> {
+        let image = find(config, id)?;
+        read_json(&files_path(&image.path), id)
+    };
+    Ok(changes::diff(from, &files(from)?, to, &files(to)?))
+}
+
+// The files of a cached image, the sidecar last, and whether each has to be
+// there. Images prepared before reports were kept have none.
+fn entry_files(image: &Path) -> [(PathBuf, bool); 4] {
+    [
+        (image.to_path_buf(), true),
+        (files_path(image), false),
+        (changes_path(image), false),
+        (image.with_extension("json"), true),
+    ]
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+// `SHA256SUMS` of `dir` by file name, empty if there is none yet.
+fn read_checksums(dir: &Path) -> Result<BTreeMap<String, String>, String> {
+    let path = dir.join(CHECKSUMS_FILE);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            // `<hash>  <name>`, `<hash> *<name>` in binary mode.
+            let (hash, name) = line
+                .split_once(' ')
+                .ok_or_else(|| format!("{}: bad line `{}`", path.display(), line))?;
+            let name = name.strip_prefix([' ', '*']).unwrap_or(name);
+            // The file may come from anywhere, only names of files in `dir`
+            // are taken.
+            if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                return Err(format!("{}: `{}` isn't a file name", path.display(), name));
+            }
+            Ok((name.to_string(), hash.to_string()))
+        })
+        .collect()
+}
+
+/// Copy the cached `image` with its sidecar, listing and change report to
+/// `dir` and add them to `dir/SHA256SUMS`. Returns the copy of the image.
+pub fn export(image: &Path, dir: &Path) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut checksums = read_checksums(dir)?;
+    for (path, required) in entry_files(image) {
+        if !required && !path.exists() {
+            continue;
+        }
+        let copy = dir.join(file_name(&path));
+        info!("Writing {}", copy.display());
+        fs::copy(&path, &copy).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let sha256 = sha256_file(&copy).map_err(|e| format!("{}: {}", copy.display(), e))?;
+        checksums.insert(file_name(&copy), sha256);
+    }
+    let text: String = checksums
+        .iter()
+        .map(|(name, hash)| format!("{}  {}\n", hash, name))
+        .collect();
+    let path = dir.join(CHECKSUMS_FILE);
+    fs::write(&path, text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(dir.join(file_name(image)))
+}
+
+/// Take the images `build-image` exported to `dir` into the cache, once
+/// they match `dir/SHA256SUMS`. Returns the ids of the images.
+pub fn import(config: &Config, dir: &Path) -> Result<Vec<String>, String> {
+    let checksums = read_checksums(dir)?;
+    if checksums.is_empty() {
+        return Err(format!("no {} in {}", CHECKSUMS_FILE, dir.display()));
+    }
+    let cache = cache_dir(config);
+    let _lock =
+        lock_cache(&cache, libc::LOCK_SH).map_err(|e| format!("{}: {}", cache.display(), e))?;
+    // Everything is checked before anything is copied.
+    let mut images = vec![];
+    for name in checksums.keys().filter(|name| name.ends_with(".img")) {
+        let image = dir.join(name);
+        let mut files = vec![];
+        for (path, required) in entry_files(&image) {
+            let name = file_name(&path);
+            if !path.exists() {
+                if required {
+                    return Err(format!("{} not found", path.display()));
+                }
+                continue;
+            }
+            let expected = checksums
+                .get(&name)
+                .ok_or_else(|| format!("{} isn't in {}", name, CHECKSUMS_FILE))?;
+            let sha256 = sha256_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            if sha256 != *expected {
+                return Err(format!(
+                    "{}: SHA-256 {} instead of {}",
+                    path.display(),
+                    sha256,
+                    expected
+                ));
+            }
+            files.push((path, cache.join(name)));
+        }
+        let id = image.file_stem().unwrap_or_default().to_string_lossy();
+        images.push((id.into_owned(), files));
+    }
+
+    let mut imported = vec![];
+    for (id, files) in images {
+        info!("Importing {}", id);
+        // Through a temporary name, the sidecar last.
+        for (from, to) in &files {
+            let tmp = to.with_extension("tmp");
+            fs::copy(from, &tmp)
+                .and_then(|_| fs::rename(&tmp, to))
+                .map_err(|e| {
+                    let _ = fs::remove_file(&tmp);
+                    format!("{}: {}", to.display(), e)
+                })?;
+        }
+        imported.push(id);
+    }
+    Ok(imported)
+}
+
+/// Remove the cached image `id`.
+pub fn delete(config: &Config, id: &str) -> Result<(), String> {
+    let image = find(config, id)?;
+    info!("Removing cached rootfs {}", image.path.display());
+    remove_entry(&image.path);
+    Ok(())
+}
+
+/// Remove the images over the age limit, then the least recently used ones
+/// until the cache fits the size limit. `keep` is never removed. Returns what
+/// was removed.
+pub fn prune(config: &Config, keep: Option<&Path>) -> Vec<CachedImage> {
+    let limits = &config.cache;
+    let now = chrono::Local::now().fixed_offset();
+    let mut images = list(config);
+    let mut removed = vec![];
+
+    if let Some(days) = limits.max_age_days {
+        let max_age = chrono::Duration::days(days as i64);
+        let (old, kept): (Vec<_>, Vec<_>) = images.into_iter().partition(|image| {
+            Some(image.path.as_path()) != keep && now - image.entry.last_used() > max_age
+        });
+        removed.extend(old);
+        images = kept;
+    }
+
+    if let Some(mib) = limits.max_size_mib {
+        let max_size = mib * 1024 * 1024;
+        let mut total: u64 = images.iter().map(|image| image.entry.size).sum();
+        // Least recently used last in the list.
+        while total > max_size {
+            let Some(index) = images
+                .iter()
+                .rposition(|image| Some(image.path.as_path()) != keep)
+            else {
+                break;
+            };
+            let image = images.remove(index);
+            total -= image.entry.size;
+            removed.push(image);
+        }
+    }
+
+    for image in &removed {
+        info!("Pruning cached rootfs {}", image.path.display());
+        remove_entry(&image.path);
+    }
+    removed
+}
+
+fn size_text(bytes: u64) -> String {
+    format!("{} MiB", bytes.div_ceil(1024 * 1024))
+}
+
+// Verify results of the Cache page by image id.
+type Statuses = Arc<Mutex<HashMap<String, String>>>;
+
+// Fill the Cache page, returns the listed images in row order.
+fn show_cache(window: &MainWindow, config: &Config, statuses: &Statuses) -> Vec<CachedImage> {
+    let images = list(config);
+    let statuses = statuses.lock().unwrap();
+    let rows: Vec<ModelRc<StandardListViewItem>> = images
+        .iter()
+        .map(|image| {
+            let cells: Vec<StandardListViewItem> = [
+                image.id.clone(),
+                image.entry.key.board.clone(),
+                image.entry.key.version.clone(),
+                size_text(image.entry.size),
+                image.entry.last_used().format("%Y-%m-%d %H:%M").to_string(),
+                statuses.get(&image.id).cloned().unwrap_or_default(),
+            ]
+            .into_iter()
+            .map(|text| StandardListViewItem::from(SharedString::from(text)))
+            .collect();
+            ModelRc::new(VecModel::from(cells))
+        })
+        .collect();
+
+    let total: u64 = images.iter().map(|image| image.entry.size).sum();
+    let mut summary = format!("{} image(s), {}", images.len(), size_text(total));
+    if let Some(max) = config.cache.max_size_mib {
+        summary.push_str(&format!(" of {} MiB", max));
+    }
+    if let Some(days) = config.cache.max_age_days {
+        summary.push_str(&format!(", unused images kept {} days", days));
+    }
+
+    let ids: Vec<SharedString> = images.iter().map(|image| image.id.clone().into()).collect();
+    let adapter = CachePageAdapter::get(window);
+    adapter.set_rows(ModelRc::new(VecModel::from(rows)));
+    adapter.set_images(ModelRc::new(VecModel::from(ids)));
+    adapter.set_summary(summary.into());
+    images
+}
+
+/// Wire up the Cache page.
+pub fn cache_page(window: &MainWindow, config: Arc<Config>) {
+    let statuses: Statuses = Arc::default();
+    // Images in the order of the table rows.
+    let shown: Arc<Mutex<Vec<CachedImage>>> = Arc::default();
+    let adapter = CachePageAdapter::get(window);
+
+    let refresh = {
+        let window_weak = window.as_weak();
+        let config = config.clone();
+        let statuses = statuses.clone();
+        let shown = shown.clone();
+        move || {
+            if let Some(window) = window_weak.upgrade() {
+                *shown.lock().unwrap() = show_cache(&window, &config, &statuses);
+            }
+        }
+    };
+    let selected = {
+        let shown = shown.clone();
+        move |row: i32| -> Vec<String> {
+            let shown = shown.lock().unwrap();
+            match usize::try_from(row) {
+                Ok(row) => shown
+                    .get(row)
+                    .map(|image| image.id.clone())
+                    .into_iter()
+                    .collect(),
+                Err(_) => shown.iter().map(|image| image.id.clone()).collect(),
+            }
+        }
+    };
+
+    adapter.on_refresh(refresh.clone());
+
+    adapter.on_delete({
+        let config = config.clone();
+        let refresh = refresh.clone();
+        let selected = selected.clone();
+        move |row| {
+            for id in selected(row) {
+                if let Err(e) = delete(&config, &id) {
+                    log::error!("{}", e);
+                }
+            }
+            refresh();
+        }
+    });
+
+    adapter.on_prune({
+        let config = config.clone();
+        let refresh = refresh.clone();
+        move || {
+            prune(&config, None);
+            refresh();
+        }
+    });
+
+    let show_report = {
+        let window_weak = window.as_weak();
+        move |report: Result<Report, String>| {
+            let text = match report {
+                Ok(report) => report.to_string(),
+                Err(e) => e,
+            };
+            if let Some(window) = window_weak.upgrade() {
+                CachePageAdapter::get(&window).set_report(text.into());
+            }
+        }
+    };
+    let id = {
+        let shown = shown.clone();
+        move |row: i32| -> Option<String> {
+            let shown = shown.lock().unwrap();
+            let image = shown.get(usize::try_from(row).ok()?)?;
+            Some(image.id.clone())
+        }
+    };
+
+    adapter.on_changes({
+        let config = config.clone();
+        let show_report = show_report.clone();
+        let id = id.clone();
+        move |row| {
+            if let Some(id) = id(row) {
+                show_report(changes(&config, &id));
+            }
+        }
+    });
+
+    adapter.on_diff({
+        let config = config.clone();
+        move |from, to| {
+            if let (Some(from), Some(to)) = (id(from), id(to)) {
+                show_report(diff(&config, &from, &to));
+            }
+        }
+    });
+
+    adapter.on_verify({
+        let window_weak = window.as_weak();
+        move |row| {
+            let ids = selected(row);
+            for id in &ids {
+                statuses
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), "verifying...".to_string());
+            }
+            CachePageAdapter::get(&window_weak.unwrap()).set_busy(true);
+            refresh();
+
+            // Hashing takes a while for big images, keep the GUI responsive.
+            let config = config.clone();
+            let statuses = statuses.clone();
+            let refresh = refresh.clone();
+            let window_weak = window_weak.clone();
+            thread::spawn(move || {
+                for id in ids {
+                    let status = match verify(&config, Some(&id)) {
+                        Ok(results) => match results.into_iter().next() {
+                            Some((_, Ok(()))) => "ok".to_string(),
+                            Some((_, Err(e))) => format!("BAD: {}", e),
+                            None => continue,
+                        },
+                        Err(e) => e,
+                    };
+                    statuses.lock().unwrap().insert(id, status);
+                    let refresh = refresh.clone();
+                    let _ = slint::invoke_from_event_loop(refresh);
+                }
+                let _ = window_weak.upgrade_in_event_loop(|window| {
+                    CachePageAdapter::get(&window).set_busy(false);
+                });
+            });
+        }
+    });
+}
+
+/// Remove images a crash left without a sidecar, and unfinished sidecars,
+/// unless another process is writing to the cache.
+pub fn remove_incomplete(config: &Config) {
+    let cache = cache_dir(config);
+    if !cache.is_dir() {
+        return;
+    }
+    let _lock = match lock_cache(&cache, libc::LOCK_EX | libc::LOCK_NB) {
+        Ok(lock) => lock,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            debug!("The cache is in use, leaving it as it is");
+            return;
+        }
+        Err(e) => {
+            warn!("Can't lock {}: {}", cache.display(), e);
+            return;
+        }
+    };
+    let Ok(dir) = fs::read_dir(&cache) else {
+        return;
+    };
+    for path in dir.flatten().map(|entry| entry.path()) {
+        let incomplete = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tmp") => true,
+            Some("img") => !path.with_extension("json").exists(),
+            // `<image>.files.json` and `<image>.changes.json`.
+            Some("json") => {
+                let stem = path.with_extension("");
+                matches!(
+                    stem.extension().and_then(|ext| ext.to_str()),
+                    Some("files" | "changes")
+                ) && !stem.with_extension("json").exists()
+            }
+            _ => false,
+        };
+        if incomplete {
+            info!("Removing incomplete {}", path.display());
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Can't remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn remove_entry(image: &Path) {
+    for (path, _) in entry_files(image) {
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Can't remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-256 of the file at `path`.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+// Every file below `dir` by relative path, with its mode, owner, xattrs and
+// content hash.
+fn hash_tree(dir: &Path, hashes: &mut HashStore, tree: &mut Sha256) -> Result<(), String> {
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path();
+        let metadata = entry
+            .metadata()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        tree.update(relative.as_os_str().as_encoded_bytes());
+        tree.update(metadata.mode().to_le_bytes());
+        tree.update(metadata.uid().to_le_bytes());
+        tree.update(metadata.gid().to_le_bytes());
+        let xattrs =
+            archive::read_xattrs(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        for (name, value) in xattrs {
+            tree.update(name.as_bytes());
+            tree.update([0]);
+            tree.update((value.len() as u64).to_le_bytes());
+            tree.update(value);
+        }
+        if metadata.is_file() {
+            tree.update(hashes.file(path)?.as_bytes());
+        } else if metadata.is_symlink() {
+            let target = fs::read_link(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            tree.update(target.as_os_str().as_encoded_bytes());
+        }
+        tree.update([0]);
+    }
+    Ok(())
+}
+
+struct HashStore {
+    path: PathBuf,
+    known: BTreeMap<PathBuf, KnownHash>,
+    changed: bool,
+}
+
+impl HashStore {
+    fn open(config: &Config) -> Self {
+        let path = cache_dir(config).join(HASHES_FILE);
+        let known = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            known,
+            changed: false,
+        }
+    }
+
+    fn file(&mut self, path: &Path) -> Result<String, String> {
+        let metadata = fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let size = metadata.len();
+        let mtime_ns = metadata.mtime() as i128 * 1_000_000_000 + metadata.mtime_nsec() as i128;
+        let absolute = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(known) = self.known.get(&absolute) {
+            if known.size == size && known.mtime_ns == mtime_ns {
+                return Ok(known.sha256.clone());
+            }
+        }
+        debug!("Hashing {}", path.display());
+        let sha256 = sha256_file(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.known.insert(
+            absolute,
+            KnownHash {
+                size,
+                mtime_ns,
+                sha256: sha256.clone(),
+            },
+        );
+        self.changed = true;
+        Ok(sha256)
+    }
+
+    // Best effort, a lost store only means hashing again.
+    fn save(&self) {
+        if !self.changed {
+            return;
+        }
+        let Some(dir) = self.path.parent() else {
+            return;
+        };
+        let data = match serde_json::to_vec(&self.known) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+        let tmp = self.path.with_extension("json.tmp");
+        let saved = lock_cache(dir, libc::LOCK_SH).and_then(|_lock| {
+            fs::write(&tmp, data)?;
+            fs::rename(&tmp, &self.path)
+        });
+        if let Err(e) = saved {
+            debug!("Can't save {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn checksums_name_files_in_their_directory() {
+        let dir = TempDir::new();
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let write = |text: String| fs::write(dir.path().join(CHECKSUMS_FILE), text).unwrap();
+
+        write(format!("{hash}  a.img\n{hash} *a.json\n\n"));
+        let checksums = read_checksums(dir.path()).unwrap();
+        assert_eq!(checksums.keys().collect::<Vec<_>>(), ["a.img", "a.json"]);
+
+        for name in ["../../x.img", "/etc/x.img", "sub/x.img", "..", ".", ""] {
+            write(format!("{hash}  {name}\n"));
+            assert!(read_checksums(dir.path()).is_err(), "{:?}", name);
+        }
+        write(format!("{hash}\n"));
+        assert!(read_checksums(dir.path()).is_err());
+    }
+}
@@ -0,0 +1,344 @@
+use crate::archive::Progress;
+use crate::cache::{hex, sha256_file};
+use crate::ext4::Wanted;
+use libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+// What a prepared image holds and what the merge changed in the base rootfs.
+// Every path of the tree is listed with its type, mode, owner, size, content
+// hash, link target, device number and xattrs, next to the image in the
+// cache:
+//
+//   cache/dc11p626-v2.0-1f3a9c0b5e2d4f67.files.json    every path of the image
+//   cache/dc11p626-v2.0-1f3a9c0b5e2d4f67.changes.json  added, removed and
+//                                                      modified against the base
+//
+// Two prepared images are compared by their listings. Directories don't
+// count as modified for new or removed entries, only for their own mode,
+// owner and xattrs.
+
+/// One path of a rootfs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    /// Type and permission bits, as in `st_mode`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Bytes of a regular file.
+    #[serde(default)]
+    pub size: u64,
+    /// SHA-256 of a regular file.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sha256: String,
+    /// Target of a symlink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Major and minor number of a device node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rdev: Option<(u32, u32)>,
+    /// Extended attributes by name, the values in hex.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+/// Every path of a rootfs by absolute path, the root is `/`.
+pub type Listing = BTreeMap<String, FileInfo>;
+
+/// Content hashes of staged files by inode, size, mtime and ctime, so files
+/// the merge didn't touch aren't read twice.
+pub type KnownHashes = HashMap<(u64, u64, i64, i64), String>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A path that differs between two listings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub path: String,
+    pub change: ChangeKind,
+    /// What differs of a modified path: type, content, target, device, mode,
+    /// owner or xattrs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub differs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<FileInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<FileInfo>,
+}
+
+/// The changes from one rootfs to another.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    /// What was changed, e.g. `rootfs.img` or the id of a cached image.
+    pub from: String,
+    /// What it was changed into.
+    pub to: String,
+    pub changes: Vec<Change>,
+}
+
+/// List the tree `wanted` describes, with the contents staged in `staging`.
+/// Progress is in bytes of regular files.
+pub fn listing(
+    staging: &Path,
+    wanted: &[Wanted],
+    known: &mut KnownHashes,
+    progress: Progress,
+) -> Result<Listing, String> {
+    let mut staged = Vec::with_capacity(wanted.len());
+    let mut total = 0;
+    for entry in wanted {
+        let full = staging.join(&entry.path);
+        let metadata = match entry.current {
+            Some(_) => {
+                let metadata = fs::symlink_metadata(&full)
+                    .map_err(|e| format!("{}: {}", full.display(), e))?;
+                if metadata.is_file() {
+                    total += metadata.len();
+                }
+                Some(metadata)
+            }
+            None => None,
+        };
+        staged.push((entry, full, metadata));
+    }
+
+    let mut listing = Listing::new();
+    let mut done = 0;
+    for (entry, full, metadata) in staged {
+        let inode = &entry.inode;
+        let mut info = FileInfo {
+            mode: inode.mode,
+            uid: inode.uid,
+            gid: inode.gid,
+            size: 0,
+            sha256: String::new(),
+            target: None,
+            rdev: inode.rdev,
+            xattrs: inode
+                .xattrs
+                .iter()
+                .map(|(name, value)| (name.clone(), hex(value)))
+                .collect(),
+        };
+        match &metadata {
+            Some(metadata) if metadata.is_file() => {
+                let id = (
+                    metadata.ino(),
+                    metadata.len(),
+                    metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+                    metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec(),
+                );
+                info.size = metadata.len();
+                info.sha256 = match known.get(&id) {
+                    Some(sha256) => sha256.clone(),
+                    None => {
+                        let sha256 =
+                            sha256_file(&full).map_err(|e| format!("{}: {}", full.display(), e))?;
+                        known.insert(id, sha256.clone());
+                        sha256
+                    }
+                };
+                done += metadata.len();
+                progress(done, total)?;
+            }
+            Some(metadata) if metadata.is_symlink() => {
+                let target =
+                    fs::read_link(&full).map_err(|e| format!("{}: {}", full.display(), e))?;
+                info.target = Some(target.to_string_lossy().into_owned());
+            }
+            _ => {}
+        }
+        listing.insert(format!("/{}", entry.path.to_string_lossy()), info);
+    }
+    progress(total, total)?;
+    Ok(listing)
+}
+
+// What differs between two versions of a path.
+fn differs(before: &FileInfo, after: &FileInfo) -> Vec<String> {
+    let mut differs = vec![];
+    if before.mode & S_IFMT != after.mode & S_IFMT {
+        differs.push("type");
+    }
+    if before.size != after.size || before.sha256 != after.sha256 {
+        differs.push("content");
+    }
+    if before.target != after.target {
+        differs.push("target");
+    }
+    if before.rdev != after.rdev {
+        differs.push("device");
+    }
+    if before.mode & 0o7777 != after.mode & 0o7777 && after.mode & S_IFMT != S_IFLNK {
+        differs.push("mode");
+    }
+    if (before.uid, before.gid) != (after.uid, after.gid) {
+        differs.push("owner");
+    }
+    if before.xattrs != after.xattrs {
+        differs.push("xattrs");
+    }
+    differs.into_iter().map(String::from).collect()
+}
+
+/// The paths added, removed and modified from `before` to `after`, in path
+/// order.
+pub fn diff(from: &str, before: &Listing, to: &str, after: &Listing) -> Report {
+    let mut changes = vec![];
+    for (path, old) in before {
+        match after.get(path) {
+            None => changes.push(Change {
+                path: path.clone(),
+                change: ChangeKind::Removed,
+                differs: vec![],
+                before: Some(old.clone()),
+                after: None,
+            }),
+            Some(new) => {
+                let differs = differs(old, new);
+                if !differs.is_empty() {
+                    changes.push(Change {
+                        path: path.clone(),
+                        change: ChangeKind::Modified,
+                        differs,
+                        before: Some(old.clone()),
+                        after: Some(new.clone()),
+                    });
+                }
+            }
+        }
+    }
+    for (path, new) in after {
+        if !before.contains_key(path) {
+            changes.push(Change {
+                path: path.clone(),
+                change: ChangeKind::Added,
+                differs: vec![],
+                before: None,
+                after: Some(new.clone()),
+            });
+        }
+    }
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Report {
+        from: from.to_string(),
+        to: to.to_string(),
+        changes,
+    }
+}
+
+// `ls -l` style type and permission bits, e.g. `-rwsr-xr-x`.
+fn mode_text(mode: u32) -> String {
+    let kind = match mode & S_IFMT {
+        S_IFDIR => 'd',
+        S_IFLNK => 'l',
+        S_IFCHR => 'c',
+        S_IFBLK => 'b',
+        S_IFIFO => 'p',
+        S_IFSOCK => 's',
+        _ => '-',
+    };
+    let mut text = String::from(kind);
+    for (shift, special, set) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        text.push(if bits & 4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 2 != 0 { 'w' } else { '-' });
+        text.push(match (bits & 1 != 0, mode & special != 0) {
+            (true, true) => set,
+            (false, true) => set.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    text
+}
+
+impl fmt::Display for FileInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}:{}", mode_text(self.mode), self.uid, self.gid)?;
+        match self.mode & S_IFMT {
+            S_IFREG => write!(
+                f,
+                " {} bytes {}",
+                self.size,
+                &self.sha256[..self.sha256.len().min(12)]
+            )?,
+            S_IFLNK => write!(f, " -> {}", self.target.as_deref().unwrap_or_default())?,
+            S_IFCHR | S_IFBLK => {
+                let (major, minor) = self.rdev.unwrap_or_default();
+                write!(f, " {},{}", major, minor)?
+            }
+            _ => {}
+        }
+        if !self.xattrs.is_empty() {
+            let names: Vec<&str> = self.xattrs.keys().map(String::as_str).collect();
+            write!(f, " [{}]", names.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Report {
+    /// Counts of the changes and the growth of the regular files, e.g.
+    /// `3 added, 1 removed, 5 modified, +1200 bytes`.
+    pub fn summary(&self) -> String {
+        let count = |kind| {
+            self.changes
+                .iter()
+                .filter(|change| change.change == kind)
+                .count()
+        };
+        let bytes = |info: &Option<FileInfo>| info.as_ref().map_or(0, |info| info.size as i64);
+        let growth: i64 = self
+            .changes
+            .iter()
+            .map(|change| bytes(&change.after) - bytes(&change.before))
+            .sum();
+        format!(
+            "{} added, {} removed, {} modified, {:+} bytes",
+            count(ChangeKind::Added),
+            count(ChangeKind::Removed),
+            count(ChangeKind::Modified),
+            growth
+        )
+    }
+}
+
+// A line per change, the summary at the end:
+//
+//   + /etc/app.conf  -rw-r--r-- 0:0 120 bytes 3fa4c2d19b0e
+//   - /usr/bin/old  -rwxr-xr-x 0:0 10240 bytes 1b2c3d4e5f60
+//   ~ /etc/hostname (content, mode)  -rw-r--r-- 0:0 8 bytes ... => -rw------- ...
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} => {}", self.from, self.to)?;
+        for change in &self.changes {
+            match (change.change, &change.before, &change.after) {
+                (ChangeKind::Added, _, Some(after)) => writeln!(f, "+ {}  {}", change.path, after)?,
+                (ChangeKind::Removed, Some(before), _) => {
+                    writeln!(f, "- {}  {}", change.path, before)?
+                }
+                (ChangeKind::Modified, Some(before), Some(after)) => writeln!(
+                    f,
+                    "~ {} ({})  {} => {}",
+                    change.path,
+                    change.differs.join(", "),
+                    before,
+                    after
+                )?,
+                _ => writeln!(f, "? {}", change.path)?,
+            }
+        }
+        write!(f, "{}", self.summary())
+    }
+}
@@ -0,0 +1,1003 @@
+use crate::api;
+use crate::cache;
+use crate::changes::Report;
+use crate::config::{Config, ConfigOverrides};
+use crate::events::{emit, new_job_id, DeviceRef, EventSink, FlashEvent};
+use crate::flash::{prepare_image, rk_flash_start, CancelToken, DeviceResult, FlashOptions};
+use crate::jobs::{select_devices, DeviceSelection, JobManager};
+use crate::manifest;
+use crate::preflight::{self, has_errors, Finding};
+use crate::webhook::{Reporter, WebhookConfig};
+use crate::FlashInfo;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+
+// Exit codes of the headless commands.
+pub const EXIT_OK: i32 = 0;
+/// Bad arguments, or the job couldn't start (missing tool, image build failed, ...).
+pub const EXIT_ERROR: i32 = 1;
+/// The job ran, but at least one device failed.
+pub const EXIT_DEVICE_FAILED: i32 = 2;
+
+const DEFAULT_API_ADDR: &str = "127.0.0.1:7878";
+
+const USAGE: &str = "\
+Usage:
+  rk_flash [--dry-run] [--skip-prepare]          start the GUI
+  rk_flash devices                                list connected devices
+  rk_flash versions                               list available versions
+  rk_flash flash [--board <board>] [--version <version>] [--recipe <recipe>]
+                 [--var <name>=<value>]... --devices all|<loc_id>...
+  rk_flash prepare-image [--board <board>] [--version <version>] [--var <name>=<value>]...
+  rk_flash build-image [--board <board>] [--version <version>] [--var <name>=<value>]...
+                       --output <dir>
+  rk_flash erase --devices all|<loc_id>...
+  rk_flash serve [--api <addr>]                    run the control API without the GUI
+  rk_flash cache [list]                           list cached rootfs images
+  rk_flash cache verify [<id>]                    check cached images against their hashes
+  rk_flash cache changes <id>                     what the merge changed in the base rootfs
+  rk_flash cache diff <id> <id>                   what differs between two cached images
+  rk_flash cache import <dir>                     take images from build-image into the cache
+  rk_flash cache delete <id>                      remove a cached image
+  rk_flash cache prune                            apply the [cache] size and age limits
+
+Options:
+  --dry-run              print the commands that would run, don't touch devices
+  --skip-prepare         don't build the rootfs image, use the cached one
+  --var <name>=<value>   fill {name} in the rootfs templates with <value>,
+                         over the version's and the board's value
+  --recipe <recipe>      what `flash` writes, `all` or `erase`, the board's
+                         recipe by default
+  --output <dir>         write the image, its manifest and SHA256SUMS here
+  --json                 write newline-delimited JSON events to stdout
+  --api <addr>           serve the HTTP/WebSocket control API on <addr>,
+                         e.g. 127.0.0.1:7878 (default for `serve`)
+  --config <file>        read this station config after the system and user ones
+  --tool <path>          upgrade_tool to run
+  --image-dir <dir>      directory with loader.bin, parameter.txt and the images
+  --version-dir <dir>    directory with the version packages
+  --work-dir <dir>       directory for prepared images and scratch files
+  -v, --version          print the software version (without a command)
+  -h, --help             print this help
+
+--board and --version default to the [defaults] of the station config, read
+from /etc/rk_flash/config.toml and ~/.config/rk_flash/config.toml.
+
+Exit codes: 0 success, 1 error before flashing, 2 one or more devices failed.";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CliCommand {
+    Gui,
+    Devices,
+    Versions,
+    /// Board and version fall back to the station defaults when not given,
+    /// the recipe to the board's.
+    Flash {
+        board: Option<String>,
+        version: Option<String>,
+        recipe: Option<String>,
+        devices: DeviceSelection,
+    },
+    PrepareImage {
+        board: Option<String>,
+        version: Option<String>,
+    },
+    /// Prepare the image and export it to `output`, for other stations.
+    BuildImage {
+        board: Option<String>,
+        version: Option<String>,
+        output: PathBuf,
+    },
+    Erase {
+        devices: DeviceSelection,
+    },
+    Serve,
+    Cache(CacheAction),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheAction {
+    List,
+    /// One image by id, or all of them.
+    Verify(Option<String>),
+    Changes(String),
+    /// From the first image to the second.
+    Diff(String, String),
+    /// A directory `build-image` wrote to.
+    Import(PathBuf),
+    Delete(String),
+    Prune,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cli {
+    pub command: CliCommand,
+    /// Legacy GUI argument, the first positional argument when no command is
+    /// given. The default recipe of the selected board otherwise.
+    pub flash_type: Option<String>,
+    pub options: FlashOptions,
+    /// Machine-readable output, see `events::FlashEvent`.
+    pub json: bool,
+    /// Address of the control API, if it should run.
+    pub api: Option<String>,
+    pub config: ConfigOverrides,
+}
+
+impl CliCommand {
+    /// Whether the command writes to devices and so needs root. Images are
+    /// built in user space.
+    pub fn needs_root(&self, options: &FlashOptions) -> bool {
+        match self {
+            CliCommand::Flash { .. } | CliCommand::Erase { .. } => !options.dry_run,
+            CliCommand::Gui
+            | CliCommand::Serve
+            | CliCommand::Devices
+            | CliCommand::Versions
+            | CliCommand::PrepareImage { .. }
+            | CliCommand::BuildImage { .. }
+            | CliCommand::Cache(_) => false,
+        }
+    }
+
+    /// Whether the command may build images, and so clean up after the
+    /// builds a crash cut short.
+    pub fn builds_images(&self) -> bool {
+        match self {
+            CliCommand::Gui
+            | CliCommand::Serve
+            | CliCommand::Flash { .. }
+            | CliCommand::PrepareImage { .. }
+            | CliCommand::BuildImage { .. } => true,
+            CliCommand::Devices
+            | CliCommand::Versions
+            | CliCommand::Erase { .. }
+            | CliCommand::Cache(_) => false,
+        }
+    }
+}
+
+const COMMANDS: &[&str] = &[
+    "devices",
+    "versions",
+    "flash",
+    "prepare-image",
+    "build-image",
+    "erase",
+    "serve",
+    "cache",
+];
+
+// Options followed by a value.
+const VALUE_OPTIONS: &[&str] = &[
+    "--api",
+    "--output",
+    "--config",
+    "--tool",
+    "--image-dir",
+    "--version-dir",
+    "--work-dir",
+    "--board",
+    "--recipe",
+    "--var",
+];
+
+// The first argument that is neither an option nor the value of one.
+// `--version` takes a value with a command and prints the version without
+// one, and `--devices` takes the values up to the next option.
+fn command_name(args: &[String]) -> Option<&str> {
+    let mut iter = args.iter().skip(1).peekable();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            option if VALUE_OPTIONS.contains(&option) => {
+                iter.next();
+            }
+            "--version" => {
+                iter.next_if(|value| {
+                    !value.starts_with('-') && !COMMANDS.contains(&value.as_str())
+                });
+            }
+            "--devices" => {
+                while iter
+                    .next_if(|value| !value.starts_with('-') && !COMMANDS.contains(&value.as_str()))
+                    .is_some()
+                {}
+            }
+            _ if arg.starts_with('-') => {}
+            _ => return Some(arg),
+        }
+    }
+    None
+}
+
+/// What the command line asks for.
+#[derive(Debug)]
+pub enum Parsed {
+    Run(Box<Cli>),
+    Help,
+    Version,
+}
+
+/// `parse`, printing help, the version or a usage error and exiting when the
+/// arguments don't describe a command to run.
+pub fn parse_or_exit(args: &[String]) -> Cli {
+    match parse(args) {
+        Ok(Parsed::Run(cli)) => *cli,
+        Ok(Parsed::Help) => {
+            println!("{}", USAGE);
+            exit(EXIT_OK);
+        }
+        Ok(Parsed::Version) => {
+            crate::print_version();
+            exit(EXIT_OK);
+        }
+        Err(msg) => {
+            eprintln!("error: {}\n\n{}", msg, USAGE);
+            exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Parse the command line, an error is a usage error.
+pub fn parse(args: &[String]) -> Result<Parsed, String> {
+    let mut options = FlashOptions::default();
+    let mut json = false;
+    let mut api = None;
+    let mut config = ConfigOverrides::default();
+    let mut positional = vec![];
+    let mut board = None;
+    let mut version = None;
+    let mut recipe = None;
+    let mut devices: Option<Vec<String>> = None;
+    let mut output = None;
+
+    // A bare `erase` is still the legacy recipe argument of the GUI, the
+    // command always comes with `--devices`.
+    let is_command = command_name(args).is_some_and(|name| {
+        COMMANDS.contains(&name) && (name != "erase" || args.iter().any(|arg| arg == "--devices"))
+    });
+
+    let mut iter = args.iter().skip(1).peekable();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Parsed::Help),
+            "-v" | "--version" if !is_command => return Ok(Parsed::Version),
+            "--dry-run" => options.dry_run = true,
+            "--skip-prepare" => options.skip_prepare = true,
+            "--json" => json = true,
+            "--api" => match iter.next() {
+                Some(value) => api = Some(value.clone()),
+                None => return Err("--api needs an address".to_string()),
+            },
+            "--output" => match iter.next() {
+                Some(value) => output = Some(PathBuf::from(value)),
+                None => return Err("--output needs a directory".to_string()),
+            },
+            "--config" | "--tool" | "--image-dir" | "--version-dir" | "--work-dir" => {
+                let value = match iter.next() {
+                    Some(value) => PathBuf::from(value),
+                    None => return Err(format!("{} needs a path", arg)),
+                };
+                let slot = match arg.as_str() {
+                    "--config" => &mut config.config_file,
+                    "--tool" => &mut config.upgrade_tool,
+                    "--image-dir" => &mut config.image_dir,
+                    "--version-dir" => &mut config.version_dir,
+                    _ => &mut config.work_dir,
+                };
+                *slot = Some(value);
+            }
+            "--board" => match iter.next() {
+                Some(value) => board = Some(value.clone()),
+                None => return Err("--board needs a value".to_string()),
+            },
+            "--recipe" => match iter.next() {
+                Some(value) => recipe = Some(value.clone()),
+                None => return Err("--recipe needs a value".to_string()),
+            },
+            "--var" => match iter.next().map(|value| manifest::parse_variables(value)) {
+                Some(Ok(variables)) if !variables.is_empty() => options.variables.extend(variables),
+                Some(Err(e)) => return Err(format!("--var: {}", e)),
+                _ => return Err("--var needs <name>=<value>".to_string()),
+            },
+            "--version" => match iter
+                .next_if(|value| !value.starts_with('-') && !COMMANDS.contains(&value.as_str()))
+            {
+                Some(value) => version = Some(value.clone()),
+                None => return Err("--version needs a value".to_string()),
+            },
+            "--devices" => {
+                let mut list = vec![];
+                while let Some(value) = iter
+                    .next_if(|value| !value.starts_with('-') && !COMMANDS.contains(&value.as_str()))
+                {
+                    list.push(value.clone());
+                }
+                if list.is_empty() {
+                    return Err("--devices needs `all` or at least one LocationID".to_string());
+                }
+                devices.get_or_insert_with(Vec::new).extend(list);
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    let device_selection = |devices: Option<Vec<String>>| match devices {
+        None => Err("--devices is required".to_string()),
+        Some(list) => Ok(DeviceSelection::from_list(list)),
+    };
+
+    let command = if !is_command {
+        // Only the legacy recipe argument, and nothing that needs a command.
+        match positional.first().map(String::as_str) {
+            None | Some("all" | "erase") => {}
+            Some(name) => return Err(format!("unknown command `{}`", name)),
+        }
+        if let Some(arg) = positional.get(1) {
+            return Err(format!("unexpected argument `{}`", arg));
+        }
+        if board.is_some() || recipe.is_some() || devices.is_some() || output.is_some() {
+            return Err("--board, --recipe, --devices and --output need a command".to_string());
+        }
+        CliCommand::Gui
+    } else {
+        match positional[0].as_str() {
+            "devices" => CliCommand::Devices,
+            "versions" => CliCommand::Versions,
+            "flash" => CliCommand::Flash {
+                board,
+                version,
+                recipe,
+                devices: device_selection(devices)?,
+            },
+            "prepare-image" => CliCommand::PrepareImage { board, version },
+            "build-image" => CliCommand::BuildImage {
+                board,
+                version,
+                output: output.ok_or("build-image needs --output <dir>")?,
+            },
+            "erase" => CliCommand::Erase {
+                devices: device_selection(devices)?,
+            },
+            "serve" => CliCommand::Serve,
+            "cache" => CliCommand::Cache(cache_action(&positional[1..])?),
+            _ => unreachable!(),
+        }
+    };
+    let arguments = match command {
+        CliCommand::Cache(_) => positional.len().min(4),
+        _ => 1,
+    };
+    if is_command && positional.len() > arguments {
+        return Err(format!("unexpected argument `{}`", positional[arguments]));
+    }
+
+    Ok(Parsed::Run(Box::new(Cli {
+        command,
+        flash_type: positional.first().cloned(),
+        options,
+        json,
+        api,
+        config,
+    })))
+}
+
+fn cache_action(args: &[String]) -> Result<CacheAction, String> {
+    let arguments = if args.first().is_some_and(|action| action == "diff") {
+        3
+    } else {
+        2
+    };
+    if args.len() > arguments {
+        return Err(format!("unexpected argument `{}`", args[arguments]));
+    }
+    let action = match args.first().map(String::as_str) {
+        None | Some("list") if args.len() <= 1 => CacheAction::List,
+        Some("verify") => CacheAction::Verify(args.get(1).cloned()),
+        Some("changes") => match args.get(1) {
+            Some(id) => CacheAction::Changes(id.clone()),
+            None => return Err("cache changes needs an image id".to_string()),
+        },
+        Some("diff") => match (args.get(1), args.get(2)) {
+            (Some(from), Some(to)) => CacheAction::Diff(from.clone(), to.clone()),
+            _ => return Err("cache diff needs two image ids".to_string()),
+        },
+        Some("import") => match args.get(1) {
+            Some(dir) => CacheAction::Import(PathBuf::from(dir)),
+            None => return Err("cache import needs a directory".to_string()),
+        },
+        Some("delete") => match args.get(1) {
+            Some(id) => CacheAction::Delete(id.clone()),
+            None => return Err("cache delete needs an image id".to_string()),
+        },
+        Some("prune") if args.len() == 1 => CacheAction::Prune,
+        Some("list" | "prune") => return Err(format!("unexpected argument `{}`", args[1])),
+        Some(action) => return Err(format!("unknown cache command `{}`", action)),
+        None => unreachable!(),
+    };
+    Ok(action)
+}
+
+fn exit_code(results: &[DeviceResult]) -> i32 {
+    if results.iter().any(|r| !r.success()) {
+        EXIT_DEVICE_FAILED
+    } else {
+        EXIT_OK
+    }
+}
+
+// Events go to stdout, either one JSON object per line or human readable.
+fn event_sink(json: bool) -> EventSink {
+    Arc::new(move |event| {
+        if json {
+            println!("{}", event.to_json());
+        } else if let FlashEvent::Error { .. } | FlashEvent::JobFailed { .. } = event.event {
+            eprintln!("{}", event.event);
+        } else {
+            println!("{}", event.event);
+        }
+    })
+}
+
+fn fail(events: &EventSink, message: impl ToString) -> i32 {
+    emit(
+        events,
+        FlashEvent::Error {
+            message: message.to_string(),
+        },
+    );
+    EXIT_ERROR
+}
+
+// Report the preflight findings, false if the job can't start.
+fn preflight_passed(events: &EventSink, findings: Vec<Finding>) -> bool {
+    let passed = !has_errors(&findings);
+    for finding in findings {
+        emit(events, FlashEvent::Preflight { finding });
+    }
+    passed
+}
+
+fn discovered(events: &EventSink, flash_info: &FlashInfo) {
+    for device in &flash_info.devices {
+        emit(
+            events,
+            FlashEvent::DeviceDiscovered {
+                device: DeviceRef::from(device),
+                mode: device.mode.clone(),
+            },
+        );
+    }
+}
+
+// A value from the command line, or the station default.
+fn or_default(value: Option<String>, default: &str, name: &str) -> Result<String, String> {
+    match value {
+        Some(value) => Ok(value),
+        None if !default.is_empty() => Ok(default.to_string()),
+        None => Err(format!(
+            "--{} is required, no default {} in the station config",
+            name, name
+        )),
+    }
+}
+
+async fn run_job(
+    config: &Config,
+    flash_info: FlashInfo,
+    flash_type: &str,
+    options: FlashOptions,
+    events: EventSink,
+) -> i32 {
+    let reporter = WebhookConfig::from_config(config).map(Reporter::new);
+    let events: EventSink = match &reporter {
+        Some(reporter) => {
+            let reporter = reporter.clone();
+            Arc::new(move |event| {
+                events(event);
+                reporter.handle(event);
+            })
+        }
+        None => events,
+    };
+
+    let cancel = CancelToken::default();
+    let job = rk_flash_start(
+        config,
+        new_job_id(),
+        flash_info,
+        flash_type.to_string(),
+        options,
+        cancel,
+        events.clone(),
+    );
+    // A batch that can't start is reported as `job_failed`.
+    let code = match job.await {
+        Ok(results) => exit_code(&results),
+        Err(_) => EXIT_ERROR,
+    };
+    if let Some(reporter) = reporter {
+        let pending = tokio::task::spawn_blocking(move || reporter.flush())
+            .await
+            .unwrap_or(0);
+        if pending > 0 {
+            log::warn!("{} webhook record(s) kept for the next run", pending);
+        }
+    }
+    code
+}
+
+fn mib(bytes: u64) -> u64 {
+    bytes.div_ceil(1024 * 1024)
+}
+
+fn run_cache(config: &Config, action: CacheAction, json: bool, events: &EventSink) -> i32 {
+    match action {
+        CacheAction::List => {
+            let images = cache::list(config);
+            if json {
+                println!("{}", serde_json::to_string(&images).unwrap());
+                return EXIT_OK;
+            }
+            println!("{:<40} {:>9}  Last used", "Image", "Size");
+            for image in &images {
+                println!(
+                    "{:<40} {:>5} MiB  {}",
+                    image.id,
+                    mib(image.entry.size),
+                    image.entry.last_used().format("%Y-%m-%d %H:%M")
+                );
+                let inputs: Vec<String> = image
+                    .entry
+                    .key
+                    .inputs
+                    .iter()
+                    .map(|(name, hash)| format!("{} {}", name, &hash[..hash.len().min(8)]))
+                    .collect();
+                println!("    {}", inputs.join(", "));
+            }
+            let total: u64 = images.iter().map(|image| image.entry.size).sum();
+            let limit = match config.cache.max_size_mib {
+                Some(max) => format!(" of {} MiB", max),
+                None => String::new(),
+            };
+            println!("{} image(s), {} MiB{}", images.len(), mib(total), limit);
+            EXIT_OK
+        }
+        CacheAction::Verify(id) => {
+            let results = match cache::verify(config, id.as_deref()) {
+                Ok(results) => results,
+                Err(e) => return fail(events, e),
+            };
+            let mut code = EXIT_OK;
+            for (image, result) in results {
+                if result.is_err() {
+                    code = EXIT_ERROR;
+                }
+                if json {
+                    let record = serde_json::json!({
+                        "id": image.id,
+                        "ok": result.is_ok(),
+                        "error": result.err(),
+                    });
+                    println!("{}", record);
+                } else {
+                    match result {
+                        Ok(()) => println!("ok   {}", image.id),
+                        Err(e) => println!("BAD  {}: {}", image.id, e),
+                    }
+                }
+            }
+            code
+        }
+        CacheAction::Changes(id) => print_report(cache::changes(config, &id), json, events),
+        CacheAction::Diff(from, to) => print_report(cache::diff(config, &from, &to), json, events),
+        CacheAction::Import(dir) => match cache::import(config, &dir) {
+            Ok(ids) => {
+                if json {
+                    println!("{}", serde_json::to_string(&ids).unwrap());
+                } else {
+                    for id in &ids {
+                        println!("imported {}", id);
+                    }
+                }
+                EXIT_OK
+            }
+            Err(e) => fail(events, e),
+        },
+        CacheAction::Delete(id) => match cache::delete(config, &id) {
+            Ok(()) => EXIT_OK,
+            Err(e) => fail(events, e),
+        },
+        CacheAction::Prune => {
+            let removed = cache::prune(config, None);
+            if json {
+                println!("{}", serde_json::to_string(&removed).unwrap());
+            } else {
+                for image in &removed {
+                    println!("removed {} ({} MiB)", image.id, mib(image.entry.size));
+                }
+            }
+            EXIT_OK
+        }
+    }
+}
+
+fn print_report(report: Result<Report, String>, json: bool, events: &EventSink) -> i32 {
+    match report {
+        Ok(report) if json => {
+            println!("{}", serde_json::to_string(&report).unwrap());
+            EXIT_OK
+        }
+        Ok(report) => {
+            println!("{}", report);
+            EXIT_OK
+        }
+        Err(e) => fail(events, e),
+    }
+}
+
+// Prepare the image of `board` and `version`, and export it to `output` if
+// that is given.
+fn prepare(
+    config: &Config,
+    board: Option<String>,
+    version: Option<String>,
+    output: Option<&Path>,
+    options: &FlashOptions,
+    events: &EventSink,
+) -> i32 {
+    let (board, version) =
+        match or_default(board, &config.defaults.board, "board").and_then(|board| {
+            or_default(version, &config.defaults.version, "version").map(|version| (board, version))
+        }) {
+            Ok(selected) => selected,
+            Err(e) => return fail(events, e),
+        };
+    if !preflight_passed(
+        events,
+        preflight::check_prepare(config, &board, &version, &options.variables),
+    ) {
+        return fail(events, "preflight checks failed");
+    }
+    let cancel = CancelToken::default();
+    let image = prepare_image(
+        config,
+        &board,
+        &version,
+        &options.variables,
+        &cancel,
+        events,
+    )
+    .and_then(|image| match output {
+        Some(dir) => cache::export(&image, dir),
+        None => Ok(image),
+    });
+    match image {
+        Ok(image) => {
+            emit(
+                events,
+                FlashEvent::ImagePrepared {
+                    board,
+                    version,
+                    path: image.display().to_string(),
+                },
+            );
+            EXIT_OK
+        }
+        Err(e) => fail(events, e),
+    }
+}
+
+/// Run a headless command and return the process exit code.
+pub async fn run(cli: Cli, config: Arc<Config>) -> i32 {
+    let options = cli.options;
+    let events = event_sink(cli.json);
+    match cli.command {
+        CliCommand::Gui => unreachable!("the GUI is started by main"),
+        CliCommand::Devices => {
+            let mut flash_info = FlashInfo::default();
+            flash_info.update_device_list(&config);
+            if cli.json {
+                discovered(&events, &flash_info);
+                return EXIT_OK;
+            }
+            println!("{:<6} {:<12} {:<8} SerialNo", "DevNo", "LocationID", "Mode");
+            for d in &flash_info.devices {
+                println!(
+                    "{:<6} {:<12} {:<8} {}",
+                    d.dev_no, d.loc_id, d.mode, d.serial_no
+                );
+            }
+            EXIT_OK
+        }
+        CliCommand::Versions => {
+            let versions = FlashInfo::load_versions(&config);
+            if cli.json {
+                println!("{}", serde_json::to_string(&versions).unwrap());
+                return EXIT_OK;
+            }
+            for version in versions {
+                println!("{}", version);
+            }
+            EXIT_OK
+        }
+        CliCommand::PrepareImage { board, version } => {
+            prepare(&config, board, version, None, &options, &events)
+        }
+        CliCommand::BuildImage {
+            board,
+            version,
+            output,
+        } => prepare(&config, board, version, Some(&output), &options, &events),
+        CliCommand::Flash {
+            board,
+            version,
+            recipe,
+            devices,
+        } => {
+            let (board, version) = match or_default(board, &config.defaults.board, "board")
+                .and_then(|board| {
+                    or_default(version, &config.defaults.version, "version")
+                        .map(|version| (board, version))
+                }) {
+                Ok(selected) => selected,
+                Err(e) => return fail(&events, e),
+            };
+            let recipe = recipe.unwrap_or_else(|| config.default_recipe(&board));
+            let findings = preflight::check(&config, &board, &version, &recipe, &options);
+            if !preflight_passed(&events, findings) {
+                return fail(&events, "preflight checks failed");
+            }
+            let mut flash_info = match select_devices(&config, &devices) {
+                Ok(flash_info) => flash_info,
+                Err(e) => return fail(&events, e),
+            };
+            discovered(&events, &flash_info);
+            flash_info.board_type = board;
+            flash_info.version_selected = version;
+            run_job(&config, flash_info, &recipe, options, events).await
+        }
+        CliCommand::Erase { devices } => {
+            let findings = preflight::check(&config, "", "", "erase", &options);
+            if !preflight_passed(&events, findings) {
+                return fail(&events, "preflight checks failed");
+            }
+            match select_devices(&config, &devices) {
+                Ok(flash_info) => {
+                    discovered(&events, &flash_info);
+                    run_job(&config, flash_info, "erase", options, events).await
+                }
+                Err(e) => fail(&events, e),
+            }
+        }
+        CliCommand::Cache(action) => run_cache(&config, action, cli.json, &events),
+        CliCommand::Serve => {
+            let addr = cli
+                .api
+                .or_else(|| config.api.listen.clone())
+                .unwrap_or_else(|| DEFAULT_API_ADDR.to_string());
+            let jobs = JobManager::new(config);
+            Reporter::start_for(&jobs);
+            match api::serve(addr, jobs).await {
+                Ok(()) => EXIT_OK,
+                Err(e) => fail(&events, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Parsed, String> {
+        let args: Vec<String> = ["rk_flash"]
+            .iter()
+            .chain(args)
+            .map(|arg| arg.to_string())
+            .collect();
+        parse(&args)
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        match parse_args(args) {
+            Ok(Parsed::Run(cli)) => *cli,
+            other => panic!("{:?}: {:?}", args, other),
+        }
+    }
+
+    fn flash(board: Option<&str>, version: Option<&str>, devices: DeviceSelection) -> CliCommand {
+        CliCommand::Flash {
+            board: board.map(str::to_string),
+            version: version.map(str::to_string),
+            recipe: None,
+            devices,
+        }
+    }
+
+    fn loc_ids(ids: &[&str]) -> DeviceSelection {
+        DeviceSelection::LocIds(ids.iter().map(|id| id.to_string()).collect())
+    }
+
+    #[test]
+    fn commands() {
+        let cases: &[(&[&str], CliCommand)] = &[
+            (&[], CliCommand::Gui),
+            (&["--dry-run", "--skip-prepare"], CliCommand::Gui),
+            (&["devices"], CliCommand::Devices),
+            (&["--json", "versions"], CliCommand::Versions),
+            (
+                &["flash", "--devices", "all"],
+                flash(None, None, DeviceSelection::All),
+            ),
+            (
+                &["flash", "--devices", "1-1", "1-2", "--board", "rk3568"],
+                flash(Some("rk3568"), None, loc_ids(&["1-1", "1-2"])),
+            ),
+            (
+                &["--version", "v1.0", "flash", "--devices", "1-1"],
+                flash(None, Some("v1.0"), loc_ids(&["1-1"])),
+            ),
+            (
+                &["flash", "--devices", "1-1", "--devices", "all"],
+                flash(None, None, DeviceSelection::All),
+            ),
+            (
+                &["flash", "--recipe", "erase", "--devices", "all"],
+                CliCommand::Flash {
+                    board: None,
+                    version: None,
+                    recipe: Some("erase".to_string()),
+                    devices: DeviceSelection::All,
+                },
+            ),
+            (
+                &["erase", "--devices", "1-1"],
+                CliCommand::Erase {
+                    devices: loc_ids(&["1-1"]),
+                },
+            ),
+            (
+                &["build-image", "--output", "out"],
+                CliCommand::BuildImage {
+                    board: None,
+                    version: None,
+                    output: PathBuf::from("out"),
+                },
+            ),
+            (&["serve", "--api", "0.0.0.0:7878"], CliCommand::Serve),
+            (&["cache"], CliCommand::Cache(CacheAction::List)),
+            (
+                &["cache", "verify"],
+                CliCommand::Cache(CacheAction::Verify(None)),
+            ),
+            (
+                &["cache", "diff", "a", "b"],
+                CliCommand::Cache(CacheAction::Diff("a".to_string(), "b".to_string())),
+            ),
+            (&["cache", "prune"], CliCommand::Cache(CacheAction::Prune)),
+        ];
+        for (args, command) in cases {
+            assert_eq!(cli(args).command, *command, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn option_values_that_look_like_commands() {
+        let cases: &[(&[&str], CliCommand)] = &[
+            (
+                &["--board", "flash", "flash", "--devices", "all"],
+                flash(Some("flash"), None, DeviceSelection::All),
+            ),
+            (
+                &["--board", "erase", "prepare-image"],
+                CliCommand::PrepareImage {
+                    board: Some("erase".to_string()),
+                    version: None,
+                },
+            ),
+            (
+                &["--work-dir", "cache", "--output", "serve", "build-image"],
+                CliCommand::BuildImage {
+                    board: None,
+                    version: None,
+                    output: PathBuf::from("serve"),
+                },
+            ),
+        ];
+        for (args, command) in cases {
+            assert_eq!(cli(args).command, *command, "{:?}", args);
+        }
+        assert_eq!(
+            cli(&["--work-dir", "cache", "devices"]).config.work_dir,
+            Some(PathBuf::from("cache"))
+        );
+    }
+
+    #[test]
+    fn legacy_recipe_argument() {
+        for recipe in ["all", "erase"] {
+            let parsed = cli(&["--dry-run", recipe]);
+            assert_eq!(parsed.command, CliCommand::Gui);
+            assert_eq!(parsed.flash_type.as_deref(), Some(recipe));
+            assert!(parsed.options.dry_run);
+        }
+        assert_eq!(cli(&[]).flash_type, None);
+    }
+
+    #[test]
+    fn variables() {
+        let parsed = cli(&["prepare-image", "--var", "site=A", "--var", "line=2"]);
+        let values: Vec<(&str, &str)> = parsed
+            .options
+            .variables
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(values, [("line", "2"), ("site", "A")]);
+    }
+
+    #[test]
+    fn help_and_version() {
+        assert!(matches!(parse_args(&["--help", "flash"]), Ok(Parsed::Help)));
+        assert!(matches!(parse_args(&["flash", "-h"]), Ok(Parsed::Help)));
+        assert!(matches!(parse_args(&["--version"]), Ok(Parsed::Version)));
+        assert!(matches!(parse_args(&["-v"]), Ok(Parsed::Version)));
+    }
+
+    #[test]
+    fn usage_errors() {
+        let cases: &[(&[&str], &str)] = &[
+            (&["flsh"], "unknown command `flsh`"),
+            (&["all", "erase"], "unexpected argument `erase`"),
+            (&["--board", "rk3568"], "need a command"),
+            (&["--devices", "all"], "need a command"),
+            (&["--output", "out"], "need a command"),
+            (&["--bogus"], "unknown option `--bogus`"),
+            (&["flash", "--bogus"], "unknown option `--bogus`"),
+            (&["flash"], "--devices is required"),
+            (&["flash", "--devices"], "--devices needs"),
+            (&["flash", "--devices", "--dry-run"], "--devices needs"),
+            (
+                &["flash", "--devices", "1-1", "versions"],
+                "unexpected argument `versions`",
+            ),
+            (
+                &["flash", "--devices", "all", "--version"],
+                "--version needs a value",
+            ),
+            (
+                &["--version", "flash", "--devices", "all"],
+                "--version needs a value",
+            ),
+            (
+                &["--version", "devices", "devices"],
+                "--version needs a value",
+            ),
+            (&["erase", "--board", "rk3568"], "need a command"),
+            (
+                &["flash", "--devices", "all", "--board"],
+                "--board needs a value",
+            ),
+            (&["devices", "extra"], "unexpected argument `extra`"),
+            (&["build-image"], "build-image needs --output"),
+            (&["prepare-image", "--var", "site"], "--var"),
+            (&["prepare-image", "--var"], "--var needs"),
+            (&["prepare-image", "--var", "board=x"], "built-in"),
+            (&["cache", "frob"], "unknown cache command `frob`"),
+            (&["cache", "list", "x"], "unexpected argument `x`"),
+            (&["cache", "prune", "x"], "unexpected argument `x`"),
+            (&["cache", "diff", "a"], "two image ids"),
+            (&["cache", "diff", "a", "b", "c"], "unexpected argument `c`"),
+            (&["cache", "delete"], "needs an image id"),
+        ];
+        for (args, error) in cases {
+            match parse_args(args) {
+                Err(message) => assert!(message.contains(error), "{:?}: {}", args, message),
+                other => panic!("{:?}: {:?}", args, other),
+            }
+        }
+    }
+}
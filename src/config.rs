@@ -0,0 +1,246 @@
+use crate::board::{builtin_boards, resolve_boards, BoardProfile};
+use crate::cache::CacheConfig;
+use crate::reproducible::BuildConfig;
+use crate::webhook::WebhookConfig;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Station configuration, TOML. Read in this order, later files override
+// single keys of earlier ones:
+//   /etc/rk_flash/config.toml
+//   $XDG_CONFIG_HOME/rk_flash/config.toml (~/.config/rk_flash/config.toml)
+//   the file given with --config
+// Relative paths in a file are relative to the directory of that file. The
+// built-in defaults are relative to the directory of the executable, so the
+// station bundle works wherever it is started from.
+//
+//   [paths]
+//   upgrade_tool = "tools/rk_flash_tools/upgrade_tool"
+//   image_dir = "rockdev"        # loader.bin, parameter.txt, *.img
+//   version_dir = "upgrade"      # version packages
+//   work_dir = "tmp"             # prepared images and scratch space
+//
+//   [defaults]
+//   board = "dc11p626"
+//   version = ""
+//   recipe = "all"
+//
+//   [api]
+//   listen = "127.0.0.1:7878"
+//
+//   [boards.dc11p626]            # see board.rs
+//   subtree = "board/filesystem"
+//
+//   [webhooks]                   # see webhook.rs
+//
+//   [cache]                      # see cache.rs
+//
+//   [build]                      # see reproducible.rs
+
+const SYSTEM_CONFIG: &str = "/etc/rk_flash/config.toml";
+
+// Keys holding paths, resolved against the directory of the file they are in.
+const PATH_KEYS: &[(&str, &str)] = &[
+    ("paths", "upgrade_tool"),
+    ("paths", "image_dir"),
+    ("paths", "version_dir"),
+    ("paths", "work_dir"),
+    ("webhooks", "spool_dir"),
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Paths {
+    pub upgrade_tool: PathBuf,
+    pub image_dir: PathBuf,
+    pub version_dir: PathBuf,
+    pub work_dir: PathBuf,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Self {
+            upgrade_tool: PathBuf::from("tools/rk_flash_tools/upgrade_tool"),
+            image_dir: PathBuf::from("rockdev"),
+            version_dir: PathBuf::from("upgrade"),
+            work_dir: PathBuf::from("tmp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Defaults {
+    pub board: String,
+    pub version: String,
+    pub recipe: String,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Self {
+            board: String::new(),
+            version: String::new(),
+            recipe: "all".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Serve the control API on this address.
+    pub listen: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub paths: Paths,
+    pub defaults: Defaults,
+    pub api: ApiConfig,
+    /// Board profiles by id.
+    pub boards: BTreeMap<String, BoardProfile>,
+    pub webhooks: Option<WebhookConfig>,
+    pub cache: CacheConfig,
+    pub build: BuildConfig,
+}
+
+/// Command line settings that take precedence over the config files.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub config_file: Option<PathBuf>,
+    pub upgrade_tool: Option<PathBuf>,
+    pub image_dir: Option<PathBuf>,
+    pub version_dir: Option<PathBuf>,
+    pub work_dir: Option<PathBuf>,
+}
+
+fn user_config() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("rk_flash/config.toml"))
+}
+
+fn exe_dir() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        .or_else(|| env::current_dir().ok())
+        .unwrap_or_default()
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut table: toml::Table =
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let base = fs::canonicalize(path)
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+    for (section, key) in PATH_KEYS {
+        if let Some(toml::Value::String(value)) = table
+            .get_mut(*section)
+            .and_then(|section| section.get_mut(*key))
+        {
+            *value = base.join(&*value).display().to_string();
+        }
+    }
+    if let Some(toml::Value::Table(boards)) = table.get_mut("boards") {
+        for (_, board) in boards.iter_mut() {
+            if let Some(toml::Value::Array(overlays)) = board.get_mut("overlays") {
+                for overlay in overlays {
+                    if let toml::Value::String(value) = overlay {
+                        *value = base.join(&*value).display().to_string();
+                    }
+                }
+            }
+            if let Some(toml::Value::String(value)) = board.get_mut("manifest") {
+                *value = base.join(&*value).display().to_string();
+            }
+        }
+    }
+    Ok(table)
+}
+
+// Merge `top` into `base`, tables key by key, everything else replaced.
+fn merge(base: &mut toml::Table, top: toml::Table) {
+    for (key, value) in top {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(top_table)) => {
+                merge(base_table, top_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Read the config files and apply the command line overrides. A missing
+    /// system or user file is fine, a missing `--config` file is not.
+    pub fn load(overrides: &ConfigOverrides) -> Result<Config, String> {
+        let mut table = toml::Table::new();
+        let mut files: Vec<PathBuf> = vec![PathBuf::from(SYSTEM_CONFIG)];
+        files.extend(user_config());
+        for file in files.iter().filter(|file| file.exists()) {
+            merge(&mut table, read_table(file)?);
+        }
+        if let Some(file) = &overrides.config_file {
+            merge(&mut table, read_table(file)?);
+        }
+
+        let mut config: Config = table
+            .try_into()
+            .map_err(|e: toml::de::Error| format!("config: {}", e))?;
+
+        let base = exe_dir();
+        let paths = &mut config.paths;
+        for (path, value) in [
+            (&mut paths.upgrade_tool, &overrides.upgrade_tool),
+            (&mut paths.image_dir, &overrides.image_dir),
+            (&mut paths.version_dir, &overrides.version_dir),
+            (&mut paths.work_dir, &overrides.work_dir),
+        ] {
+            if let Some(value) = value {
+                *path = env::current_dir().unwrap_or_default().join(value);
+            } else {
+                *path = base.join(&*path);
+            }
+        }
+        if config.boards.is_empty() {
+            config.boards = builtin_boards();
+        }
+        resolve_boards(&mut config.boards);
+        if let Some(webhooks) = &mut config.webhooks {
+            if webhooks.spool_dir.as_os_str().is_empty() {
+                webhooks.spool_dir = config.paths.work_dir.join("webhook-spool");
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn board(&self, id: &str) -> Result<&BoardProfile, String> {
+        self.boards.get(id).ok_or_else(|| {
+            format!(
+                "unknown board `{}`, configured: {}",
+                id,
+                self.boards.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })
+    }
+
+    /// Recipe for `board` when none is asked for.
+    pub fn default_recipe(&self, board: &str) -> String {
+        self.boards
+            .get(board)
+            .and_then(|profile| profile.recipe.clone())
+            .unwrap_or_else(|| self.defaults.recipe.clone())
+    }
+}
@@ -0,0 +1,330 @@
+use crate::preflight::Finding;
+use crate::DeviceInfo;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Receives every event of a flash job, in order.
+pub type EventSink = Arc<dyn Fn(&Event) + Send + Sync>;
+
+/// Identifies a device in every event that concerns it.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceRef {
+    pub dev_no: String,
+    pub loc_id: String,
+    pub serial_no: String,
+}
+
+impl From<&DeviceInfo> for DeviceRef {
+    fn from(device: &DeviceInfo) -> Self {
+        Self {
+            dev_no: device.dev_no.clone(),
+            loc_id: device.loc_id.clone(),
+            serial_no: device.serial_no.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedStep {
+    pub label: String,
+    pub command: String,
+    pub estimate_secs: u64,
+}
+
+/// The field names are part of the `--json` output format, only add new ones.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum FlashEvent {
+    DeviceDiscovered {
+        #[serde(flatten)]
+        device: DeviceRef,
+        mode: String,
+    },
+    JobStarted {
+        job_id: String,
+        board: String,
+        version: String,
+        recipe: String,
+        dry_run: bool,
+        estimate_secs: u64,
+        devices: Vec<DeviceRef>,
+    },
+    /// Dry runs only: what would run on the device.
+    DevicePlanned {
+        #[serde(flatten)]
+        device: DeviceRef,
+        steps: Vec<PlannedStep>,
+        estimate_secs: u64,
+    },
+    StepStarted {
+        #[serde(flatten)]
+        device: DeviceRef,
+        step: usize,
+        steps: usize,
+        label: String,
+    },
+    StepProgress {
+        #[serde(flatten)]
+        device: DeviceRef,
+        step: usize,
+        label: String,
+        percent: u8,
+    },
+    StepFinished {
+        #[serde(flatten)]
+        device: DeviceRef,
+        step: usize,
+        label: String,
+        success: bool,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    DeviceResult {
+        job_id: String,
+        #[serde(flatten)]
+        device: DeviceRef,
+        success: bool,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    BatchSummary {
+        job_id: String,
+        total: usize,
+        succeeded: usize,
+        failed: usize,
+        duration_ms: u64,
+    },
+    /// The batch failed before any device was flashed, e.g. its rootfs image
+    /// couldn't be prepared. Comes instead of `job_started`.
+    JobFailed {
+        job_id: String,
+        board: String,
+        version: String,
+        recipe: String,
+        dry_run: bool,
+        devices: Vec<DeviceRef>,
+        duration_ms: u64,
+        error: String,
+    },
+    /// The rootfs image is built, or found in the cache, before a job
+    /// starts.
+    PrepareStarted {
+        board: String,
+        version: String,
+        steps: Vec<String>,
+    },
+    PrepareProgress {
+        step: usize,
+        steps: usize,
+        label: String,
+        done_bytes: u64,
+        total_bytes: u64,
+        percent: u8,
+    },
+    PrepareFinished {
+        board: String,
+        version: String,
+        success: bool,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        /// Filesystem check summary of the image.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fsck: Option<String>,
+        /// Template variables the image was built with.
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        variables: BTreeMap<String, String>,
+    },
+    ImagePrepared {
+        board: String,
+        version: String,
+        path: String,
+    },
+    /// Something the preflight checks found, errors keep the job from starting.
+    Preflight {
+        #[serde(flatten)]
+        finding: Finding,
+    },
+    /// The job couldn't start or a command was used wrongly.
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// RFC 3339, UTC, millisecond precision.
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub event: FlashEvent,
+}
+
+/// Current time in the format of `Event::timestamp`.
+pub fn timestamp_now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+impl Event {
+    pub fn new(event: FlashEvent) -> Self {
+        Self {
+            timestamp: timestamp_now(),
+            event,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("events always serialize")
+    }
+}
+
+pub fn emit(sink: &EventSink, event: FlashEvent) {
+    sink(&Event::new(event));
+}
+
+/// A new id for every job, `YYYYmmdd-HHMMSS-xxxx`.
+pub fn new_job_id() -> String {
+    format!(
+        "{}-{:04x}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S"),
+        rand::random::<u16>()
+    )
+}
+
+// Human readable form, used by the CLI without `--json`.
+impl fmt::Display for FlashEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashEvent::DeviceDiscovered { device, mode } => write!(
+                f,
+                "found device {} (LocationID={}, Mode={}, SerialNo={})",
+                device.dev_no, device.loc_id, mode, device.serial_no
+            ),
+            FlashEvent::JobStarted {
+                job_id,
+                board,
+                version,
+                recipe,
+                dry_run,
+                estimate_secs,
+                devices,
+            } => write!(
+                f,
+                "job {}: {} {} on {} for {} device(s), estimated {}s{}",
+                job_id,
+                recipe,
+                version,
+                board,
+                devices.len(),
+                estimate_secs,
+                if *dry_run { " (dry run)" } else { "" }
+            ),
+            FlashEvent::DevicePlanned {
+                device,
+                steps,
+                estimate_secs,
+            } => {
+                write!(
+                    f,
+                    "device {} (LocationID={}), estimated {}s:",
+                    device.dev_no, device.loc_id, estimate_secs
+                )?;
+                for step in steps {
+                    write!(
+                        f,
+                        "\n  [{:>4}s] {:<18} {}",
+                        step.estimate_secs, step.label, step.command
+                    )?;
+                }
+                Ok(())
+            }
+            FlashEvent::StepStarted {
+                device,
+                step,
+                steps,
+                label,
+            } => write!(f, "[{}] ({}/{}) {}", device.loc_id, step, steps, label),
+            FlashEvent::StepProgress {
+                device,
+                label,
+                percent,
+                ..
+            } => write!(f, "[{}] {} {}%", device.loc_id, label, percent),
+            FlashEvent::StepFinished {
+                device,
+                label,
+                success,
+                duration_ms,
+                ..
+            } => write!(
+                f,
+                "[{}] {} {} ({:.1}s)",
+                device.loc_id,
+                label,
+                if *success { "done" } else { "FAILED" },
+                *duration_ms as f64 / 1000.0
+            ),
+            FlashEvent::DeviceResult { device, error, .. } => match error {
+                None => write!(f, "{}: OK", device.loc_id),
+                Some(e) => write!(f, "{}: FAILED ({})", device.loc_id, e),
+            },
+            FlashEvent::BatchSummary {
+                total,
+                succeeded,
+                failed,
+                duration_ms,
+                ..
+            } => write!(
+                f,
+                "{} device(s): {} succeeded, {} failed in {}s",
+                total,
+                succeeded,
+                failed,
+                duration_ms / 1000
+            ),
+            FlashEvent::JobFailed {
+                job_id,
+                board,
+                version,
+                recipe,
+                error,
+                ..
+            } => write!(
+                f,
+                "job {}: {} {} on {} FAILED: {}",
+                job_id, recipe, version, board, error
+            ),
+            FlashEvent::PrepareStarted { board, version, .. } => {
+                write!(f, "[image] preparing the rootfs of {} {}", board, version)
+            }
+            FlashEvent::PrepareProgress {
+                step,
+                steps,
+                label,
+                percent,
+                ..
+            } => write!(f, "[image] ({}/{}) {} {}%", step, steps, label, percent),
+            FlashEvent::PrepareFinished {
+                success,
+                duration_ms,
+                fsck,
+                ..
+            } => {
+                write!(
+                    f,
+                    "[image] {} ({:.1}s)",
+                    if *success { "ready" } else { "FAILED" },
+                    *duration_ms as f64 / 1000.0
+                )?;
+                match fsck {
+                    Some(fsck) => write!(f, ", fsck: {}", fsck),
+                    None => Ok(()),
+                }
+            }
+            FlashEvent::ImagePrepared { path, .. } => write!(f, "{}", path),
+            FlashEvent::Preflight { finding } => write!(f, "preflight {}", finding),
+            FlashEvent::Error { message } => write!(f, "error: {}", message),
+        }
+    }
+}
@@ -0,0 +1,913 @@
+use crate::archive::Progress;
+use libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+// ext4 images are read and written with e2fsprogs in user space, no mount
+// and no root needed. The base image is dumped into a staging directory with
+// `debugfs rdump`, and the merged tree is written back with `mke2fs -d`.
+// Ownership, modes, xattrs and device nodes can't be kept in the staging
+// directory without root, so they are read from the image into `Metadata` and
+// applied to the new image with a debugfs script.
+
+/// Superblock settings carried over from the base image.
+#[derive(Debug, Clone)]
+pub struct Ext4Params {
+    pub block_size: u64,
+    pub block_count: u64,
+    pub inode_size: u64,
+    pub inode_count: u64,
+    pub label: String,
+    pub uuid: String,
+    pub hash_seed: String,
+    pub features: Vec<String>,
+}
+
+impl Ext4Params {
+    /// Settings of a rootfs built without an ext4 base image, the defaults of
+    /// mke2fs. It is sized to the tree when it is built.
+    pub fn standard() -> Self {
+        Self {
+            block_size: 4096,
+            block_count: 0,
+            inode_size: 256,
+            inode_count: 0,
+            label: "rootfs".to_string(),
+            uuid: String::new(),
+            hash_seed: String::new(),
+            features: [
+                "has_journal",
+                "ext_attr",
+                "resize_inode",
+                "dir_index",
+                "filetype",
+                "extent",
+                "64bit",
+                "flex_bg",
+                "sparse_super",
+                "large_file",
+                "huge_file",
+                "dir_nlink",
+                "extra_isize",
+                "metadata_csum",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+        }
+    }
+
+    pub fn read(image: &Path) -> Result<Self, String> {
+        let output = Command::new("dumpe2fs")
+            .arg("-h")
+            .arg(image)
+            .stderr(Stdio::null())
+            .output()
+            .map_err(|e| format!("dumpe2fs: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("{} is not an ext4 image", image.display()));
+        }
+        let text = String::from_utf8_lossy(&output.stdout);
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .map(|value| value.trim().to_string())
+                .unwrap_or_default()
+        };
+        let number = |name: &str| {
+            field(name)
+                .parse::<u64>()
+                .map_err(|_| format!("{}: no `{}` in the superblock", image.display(), name))
+        };
+        let label = field("Filesystem volume name");
+        Ok(Self {
+            block_size: number("Block size")?,
+            block_count: number("Block count")?,
+            inode_size: number("Inode size")?,
+            inode_count: number("Inode count")?,
+            label: if label == "<none>" {
+                String::new()
+            } else {
+                label
+            },
+            uuid: field("Filesystem UUID"),
+            hash_seed: field("Directory Hash Seed"),
+            features: field("Filesystem features")
+                .split_whitespace()
+                // State of the old image, not features to create.
+                .filter(|f| !matches!(*f, "needs_recovery" | "orphan_present"))
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+/// What the staging directory can't hold for an unprivileged user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inode {
+    /// Type and permission bits, as in `st_mode`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Major and minor number of device nodes.
+    pub rdev: Option<(u32, u32)>,
+    /// Extended attributes by name, e.g. `security.capability`.
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+/// Inodes of a tree by path relative to its root, the root itself is "".
+pub type Metadata = BTreeMap<PathBuf, Inode>;
+
+// Paths as debugfs sees them, quoted for its command parser.
+fn quote(path: &Path) -> String {
+    format!("\"/{}\"", path.to_string_lossy().replace('"', "\"\""))
+}
+
+// Run debugfs commands on `image`, writable with `Some` time for new inodes.
+// Returns the output of each command.
+fn debugfs(image: &Path, write: Option<i64>, commands: &[String]) -> Result<Vec<String>, String> {
+    if commands.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut command = Command::new("debugfs");
+    if let Some(time) = write {
+        command
+            .arg("-w")
+            .env("E2FSPROGS_FAKE_TIME", time.to_string());
+    }
+    let mut child = command
+        .arg("-f")
+        .arg("-")
+        .arg(image)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("debugfs: {}", e))?;
+
+    let script = commands.join("\n") + "\n";
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // Feed the script from a thread, debugfs writes while it reads.
+    let feeder = std::thread::spawn(move || stdin.write_all(script.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("debugfs: {}", e))?;
+    feeder
+        .join()
+        .expect("debugfs feeder panicked")
+        .map_err(|e| format!("debugfs: {}", e))?;
+
+    let errors: Vec<String> = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter(|line| !line.starts_with("debugfs ") && !line.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    if !output.status.success() || !errors.is_empty() {
+        return Err(format!(
+            "debugfs on {} failed: {}",
+            image.display(),
+            errors.join("; ")
+        ));
+    }
+
+    // Every command is echoed as `debugfs: <command>` before its output.
+    let mut sections: Vec<String> = vec![];
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if line.starts_with("debugfs: ") {
+            sections.push(String::new());
+        } else if let Some(section) = sections.last_mut() {
+            section.push_str(line);
+            section.push('\n');
+        }
+    }
+    Ok(sections)
+}
+
+// One line of `ls -p`: `/<inode>/<mode>/<uid>/<gid>/<name>/<size>/`.
+fn parse_ls_line(line: &str) -> Option<(u64, u32, u32, u32, &str, u64)> {
+    let fields = line.strip_prefix('/')?.strip_suffix('/')?;
+    let mut head = fields.splitn(5, '/');
+    let ino = head.next()?.parse().ok()?;
+    let mode = u32::from_str_radix(head.next()?, 8).ok()?;
+    let uid = head.next()?.parse().ok()?;
+    let gid = head.next()?.parse().ok()?;
+    // The name may contain anything but the size after it never has a slash.
+    let rest = head.next()?;
+    let (name, size) = rest.rsplit_once('/')?;
+    Some((ino, mode, uid, gid, name, size.parse().unwrap_or(0)))
+}
+
+// `Device major/minor number: 04:64 (hex 04:40)`
+fn parse_rdev(stat: &str) -> Option<(u32, u32)> {
+    let value = stat
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Device major/minor number:"))?;
+    let (major, minor) = value.split_whitespace().next()?.split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+// Paths of every non-directory inode, and the size of all regular files.
+type Links = (HashMap<u64, Vec<PathBuf>>, u64);
+
+/// Read every inode of `image` below the root.
+fn read_metadata(image: &Path) -> Result<(Metadata, Links), String> {
+    let mut metadata = Metadata::new();
+    let mut file_bytes = 0;
+    // Paths of every non-directory inode, for hard links.
+    let mut links: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    let mut devices = vec![];
+
+    // One debugfs run per directory level.
+    let mut level = vec![PathBuf::new()];
+    while !level.is_empty() {
+        let commands: Vec<String> = level
+            .iter()
+            .map(|dir| format!("ls -p {}", quote(dir)))
+            .collect();
+        let listings = debugfs(image, None, &commands)?;
+        let mut next = vec![];
+        for (dir, listing) in level.iter().zip(&listings) {
+            for line in listing.lines() {
+                let Some((ino, mode, uid, gid, name, size)) = parse_ls_line(line) else {
+                    continue;
+                };
+                let inode = Inode {
+                    mode,
+                    uid,
+                    gid,
+                    rdev: None,
+                    xattrs: BTreeMap::new(),
+                };
+                if name == "." {
+                    if dir.as_os_str().is_empty() {
+                        metadata.insert(PathBuf::new(), inode);
+                    }
+                    continue;
+                }
+                // Unused slots, lost+found is full of them.
+                if name == ".." || ino == 0 || name.is_empty() {
+                    continue;
+                }
+                let path = dir.join(name);
+                match mode & S_IFMT {
+                    S_IFDIR => next.push(path.clone()),
+                    S_IFCHR | S_IFBLK => {
+                        devices.push(path.clone());
+                        links.entry(ino).or_default().push(path.clone());
+                    }
+                    S_IFREG => {
+                        file_bytes += size;
+                        links.entry(ino).or_default().push(path.clone());
+                    }
+                    _ => links.entry(ino).or_default().push(path.clone()),
+                }
+                metadata.insert(path, inode);
+            }
+        }
+        level = next;
+    }
+
+    let commands: Vec<String> = devices
+        .iter()
+        .map(|path| format!("stat {}", quote(path)))
+        .collect();
+    for (path, stat) in devices.iter().zip(debugfs(image, None, &commands)?) {
+        if let Some(inode) = metadata.get_mut(path) {
+            inode.rdev = parse_rdev(&stat);
+        }
+    }
+    read_xattrs(image, &mut metadata)?;
+    Ok((metadata, (links, file_bytes)))
+}
+
+// `ea_list` names every attribute and `ea_get -x` dumps its value in hex.
+fn read_xattrs(image: &Path, metadata: &mut Metadata) -> Result<(), String> {
+    let paths: Vec<PathBuf> = metadata.keys().cloned().collect();
+    let commands: Vec<String> = paths
+        .iter()
+        .map(|path| format!("ea_list {}", quote(path)))
+        .collect();
+    let mut wanted = vec![];
+    for (path, listing) in paths.iter().zip(debugfs(image, None, &commands)?) {
+        // `  user.foo (3) = "bar"`, the value isn't always shown.
+        for line in listing.lines().filter(|line| line.starts_with("  ")) {
+            match line.split_whitespace().next() {
+                // Where ext4 keeps inline file contents, not an attribute.
+                Some("system.data") | None => {}
+                Some(name) => wanted.push((path.clone(), name.to_string())),
+            }
+        }
+    }
+
+    let commands: Vec<String> = wanted
+        .iter()
+        .map(|(path, name)| format!("ea_get -x {} {}", quote(path), name))
+        .collect();
+    for ((path, name), dump) in wanted.into_iter().zip(debugfs(image, None, &commands)?) {
+        let hex = dump.split_once(" = ").map_or("", |(_, hex)| hex);
+        let value = hex
+            .split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| format!("{}: bad value of {}", path.display(), name))?;
+        if let Some(inode) = metadata.get_mut(&path) {
+            inode.xattrs.insert(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Bytes in the regular files at or below `path`, symlinks aren't followed.
+pub fn tree_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Run `command`, reporting `measure()` out of `total` while it runs. An
+/// error from `progress` kills it.
+pub fn run_watched(
+    command: &mut Command,
+    total: u64,
+    measure: &dyn Fn() -> u64,
+    progress: Progress,
+) -> Result<Output, String> {
+    let name = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("{}: {}", name, e))?;
+    // Drained from threads, a full pipe would stall it.
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut data = vec![];
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut data);
+            }
+            data
+        })
+    };
+    let stdout = drain(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+    let stderr = drain(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| format!("{}: {}", name, e))? {
+            break status;
+        }
+        if let Err(e) = progress(measure().min(total), total) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        thread::sleep(Duration::from_millis(250));
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Dump `image` into `dest`, which must not exist yet. Returns what the
+/// copies in `dest` may lack. Progress is in bytes of regular files.
+pub fn extract(image: &Path, dest: &Path, progress: Progress) -> Result<Metadata, String> {
+    let (metadata, (links, file_bytes)) = read_metadata(image)?;
+
+    fs::create_dir_all(dest).map_err(|e| format!("{}: {}", dest.display(), e))?;
+    let output = run_watched(
+        Command::new("debugfs")
+            .arg("-R")
+            .arg(format!("rdump / \"{}\"", dest.display()))
+            .arg(image),
+        file_bytes,
+        &|| tree_size(dest),
+        progress,
+    )?;
+    if !output.status.success() {
+        return Err(format!("can't read {}", image.display()));
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        // Expected without root, ownership is taken from the metadata.
+        if !line.contains("while changing ownership") && !line.starts_with("debugfs ") {
+            debug!("rdump: {}", line);
+        }
+    }
+
+    progress(file_bytes, file_bytes)?;
+    stage_sockets(dest, &metadata)?;
+
+    // rdump writes every name of a hard-linked file as a copy of its own.
+    for paths in links.values().filter(|paths| paths.len() > 1) {
+        let first = dest.join(&paths[0]);
+        for path in &paths[1..] {
+            let path = dest.join(path);
+            let _ = fs::remove_file(&path);
+            if let Err(e) = fs::hard_link(&first, &path) {
+                warn!("Can't link {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    stage_modes(dest, &metadata)?;
+    Ok(metadata)
+}
+
+/// Make every copy in `dest` readable and writable for us, the real modes
+/// are in `metadata` and come back when the image is built.
+pub fn stage_modes(dest: &Path, metadata: &Metadata) -> Result<(), String> {
+    for (path, inode) in metadata {
+        let full = dest.join(path);
+        let Ok(current) = fs::symlink_metadata(&full) else {
+            continue;
+        };
+        let wanted = match inode.mode & S_IFMT {
+            S_IFDIR => staging_mode(inode.mode),
+            S_IFREG | S_IFSOCK => staging_mode(inode.mode),
+            _ => continue,
+        };
+        if current.permissions().mode() & 0o7777 != wanted {
+            fs::set_permissions(&full, fs::Permissions::from_mode(wanted))
+                .map_err(|e| format!("{}: {}", full.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Bind the sockets of `metadata` in `dest`, extracting doesn't copy them.
+/// Nothing listens on them, as on the ones in the image.
+pub fn stage_sockets(dest: &Path, metadata: &Metadata) -> Result<(), String> {
+    for (path, inode) in metadata {
+        if inode.mode & S_IFMT != S_IFSOCK {
+            continue;
+        }
+        let full = dest.join(path);
+        // Whatever the tool left in its place.
+        let _ = fs::remove_file(&full);
+        bind_socket(&full).map_err(|e| format!("{}: {}", full.display(), e))?;
+    }
+    Ok(())
+}
+
+// bind takes paths of up to 108 bytes, the directory goes by its descriptor.
+fn bind_socket(path: &Path) -> io::Result<()> {
+    let dir = fs::File::open(path.parent().unwrap_or(Path::new("/")))?;
+    let short = Path::new("/proc/self/fd")
+        .join(dir.as_raw_fd().to_string())
+        .join(path.file_name().unwrap_or_default());
+    UnixListener::bind(short).map(drop)
+}
+
+/// Permission bits a staged copy of an inode with `mode` gets.
+pub fn staging_mode(mode: u32) -> u32 {
+    let owner = if mode & S_IFMT == S_IFDIR {
+        0o700
+    } else {
+        0o600
+    };
+    (mode & 0o7777) | owner
+}
+
+// Default journal size of mke2fs for a filesystem of `blocks`, in blocks.
+fn journal_blocks(blocks: u64) -> u64 {
+    match blocks {
+        0..2048 => 0,
+        2048..32768 => 1024,
+        32768..262144 => 4096,
+        262144..524288 => 8192,
+        524288..4194304 => 16384,
+        4194304..8388608 => 32768,
+        8388608..16777216 => 65536,
+        16777216..33554432 => 131072,
+        _ => 262144,
+    }
+}
+
+// Blocks and inodes an image of the tree in `staging` needs, with room for
+// what isn't counted exactly: directory growth, extent blocks, backups of
+// the group descriptors.
+fn needed(staging: &Path, params: &Ext4Params) -> (u64, u64) {
+    let block_size = params.block_size;
+    // The first inodes are reserved.
+    let mut inodes = 11;
+    let mut data = 0;
+    let mut seen = HashSet::new();
+    for entry in WalkDir::new(staging)
+        .sort_by_file_name()
+        .into_iter()
+        .flatten()
+    {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_dir() && !seen.insert(metadata.ino()) {
+            continue;
+        }
+        inodes += 1;
+        data += if metadata.is_dir() {
+            metadata.len().div_ceil(block_size).max(1)
+        } else if metadata.is_file() {
+            metadata.len().div_ceil(block_size)
+        } else if metadata.is_symlink() && metadata.len() > 59 {
+            // Shorter targets are kept in the inode.
+            1
+        } else {
+            0
+        };
+    }
+    let inodes = inodes + inodes / 10;
+
+    let mut blocks = data;
+    loop {
+        let groups = blocks.div_ceil(8 * block_size).max(1);
+        let total = data
+            + (inodes * params.inode_size).div_ceil(block_size)
+            + journal_blocks(blocks)
+            + groups * 2
+            + groups.ilog2() as u64 * 256
+            + 256;
+        if total <= blocks {
+            break;
+        }
+        blocks = total;
+    }
+    (blocks + blocks / 10, inodes)
+}
+
+// `params` big enough for the tree in `staging`, but not bigger than
+// `max_size` bytes when that is given.
+fn grow(staging: &Path, params: &Ext4Params, max_size: Option<u64>) -> Result<Ext4Params, String> {
+    let (blocks, inodes) = needed(staging, params);
+    let mut grown = params.clone();
+    grown.inode_count = params.inode_count.max(inodes);
+    if blocks > params.block_count {
+        // Whole MiB, partitions are laid out in those.
+        let per_mib = (1 << 20) / params.block_size;
+        grown.block_count = blocks.div_ceil(per_mib) * per_mib;
+    }
+    let mib = |blocks: u64| (blocks * params.block_size) >> 20;
+    if let Some(max_size) = max_size {
+        let max_blocks = max_size / params.block_size;
+        if grown.block_count > max_blocks {
+            return Err(format!(
+                "the rootfs needs {} MiB, more than the {} MiB it may grow to",
+                mib(grown.block_count),
+                mib(max_blocks)
+            ));
+        }
+    }
+    if grown.block_count > params.block_count {
+        info!(
+            "Growing the rootfs from {} to {} MiB",
+            mib(params.block_count),
+            mib(grown.block_count)
+        );
+    }
+    if grown.inode_count > params.inode_count {
+        info!(
+            "Growing the rootfs from {} to {} inodes",
+            params.inode_count, grown.inode_count
+        );
+    }
+    Ok(grown)
+}
+
+/// Write the tree in `staging` as a new ext4 image like `params`, grown when
+/// the tree doesn't fit, up to `max_size` bytes if that is given. The
+/// superblock and new inodes get the time `epoch`, staged paths their
+/// modification time for every timestamp. Paths in
+/// `metadata` get their recorded owner, and their recorded mode unless the
+/// staged copy was given another one since `extract`. New paths belong to
+/// root. Progress is in bytes of regular files, as far as mke2fs has
+/// allocated them. Returns what went into the image.
+pub fn build(
+    staging: &Path,
+    metadata: &Metadata,
+    params: &Ext4Params,
+    max_size: Option<u64>,
+    epoch: i64,
+    image: &Path,
+    progress: Progress,
+) -> Result<Vec<Wanted>, String> {
+    let _ = fs::remove_file(image);
+    let root = metadata.get(Path::new("")).cloned().unwrap_or(Inode {
+        mode: S_IFDIR | 0o755,
+        uid: 0,
+        gid: 0,
+        rdev: None,
+        xattrs: BTreeMap::new(),
+    });
+
+    // mke2fs has to read everything, whatever mode the merge gave it.
+    let mut locked = HashMap::new();
+    unlock(staging, staging, &mut locked)?;
+    let total = tree_size(staging);
+    let params = &grow(staging, params, max_size)?;
+
+    let mut extended = format!("root_owner={}:{}", root.uid, root.gid);
+    if !params.hash_seed.is_empty() {
+        extended.push_str(&format!(",hash_seed={}", params.hash_seed));
+    }
+    let mut command = Command::new("mke2fs");
+    command
+        .env("E2FSPROGS_FAKE_TIME", epoch.to_string())
+        .args(["-q", "-F", "-t", "ext4"])
+        .arg("-b")
+        .arg(params.block_size.to_string())
+        .arg("-I")
+        .arg(params.inode_size.to_string())
+        .arg("-N")
+        .arg(params.inode_count.to_string())
+        .arg("-L")
+        .arg(&params.label)
+        .arg("-E")
+        .arg(extended)
+        .arg("-O")
+        .arg(format!("none,{}", params.features.join(",")))
+        .arg("-d")
+        .arg(staging);
+    if !params.uuid.is_empty() {
+        command.arg("-U").arg(&params.uuid);
+    }
+    command.arg(image).arg(params.block_count.to_string());
+    let allocated = || fs::metadata(image).map(|m| m.blocks() * 512).unwrap_or(0);
+    let output = run_watched(&mut command, total, &allocated, progress)?;
+    if !output.status.success() {
+        return Err(format!(
+            "mke2fs failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let mut commands = vec![];
+    let wanted_inodes = wanted_inodes(staging, metadata, &locked)?;
+    for wanted in &wanted_inodes {
+        let (path, inode) = (&wanted.path, &wanted.inode);
+        match wanted.current {
+            Some((uid, gid, mode)) => set_inode(&mut commands, path, inode, uid, gid, mode),
+            // Device nodes and fifos rdump couldn't create.
+            None => {
+                let kind = match inode.mode & S_IFMT {
+                    S_IFCHR => "c",
+                    S_IFBLK => "b",
+                    _ => "p",
+                };
+                let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                    continue;
+                };
+                let mut mknod = format!(
+                    "mknod \"{}\" {}",
+                    name.to_string_lossy().replace('"', "\"\""),
+                    kind
+                );
+                if let Some((major, minor)) = inode.rdev {
+                    mknod.push_str(&format!(" {} {}", major, minor));
+                }
+                // mknod takes a name in the current directory.
+                commands.push(format!("cd {}", quote(parent)));
+                commands.push(mknod);
+                commands.push("cd /".to_string());
+                set_inode(&mut commands, path, inode, 0, 0, inode.mode & S_IFMT);
+            }
+        }
+    }
+
+    // Values go through files, they are binary more often than not.
+    let values = image.with_extension("xattrs");
+    let _ = fs::remove_dir_all(&values);
+    fs::create_dir_all(&values).map_err(|e| format!("{}: {}", values.display(), e))?;
+    for wanted in &wanted_inodes {
+        for (name, value) in &wanted.inode.xattrs {
+            let file = values.join(commands.len().to_string());
+            fs::write(&file, value).map_err(|e| format!("{}: {}", file.display(), e))?;
+            commands.push(format!(
+                "ea_set -f \"{}\" {} {}",
+                file.display(),
+                quote(&wanted.path),
+                name
+            ));
+        }
+    }
+    // mke2fs takes access and change times from the staged copies, which
+    // reading them and `unlock` just touched. Set after mknod and ea_set,
+    // which change them too.
+    for wanted in wanted_inodes
+        .iter()
+        .filter(|wanted| wanted.current.is_some())
+    {
+        let full = staging.join(&wanted.path);
+        let mtime = fs::symlink_metadata(&full)
+            .map_err(|e| format!("{}: {}", full.display(), e))?
+            .mtime();
+        for field in ["mtime", "atime", "ctime"] {
+            commands.push(format!("sif {} {} @{}", quote(&wanted.path), field, mtime));
+        }
+    }
+    let written = debugfs(image, Some(epoch), &commands);
+    let _ = fs::remove_dir_all(&values);
+    written?;
+    progress(total, total)?;
+    Ok(wanted_inodes)
+}
+
+/// What a path of the rootfs is to be in the new image.
+pub struct Wanted {
+    pub path: PathBuf,
+    pub inode: Inode,
+    /// Uid, gid and mode of the staged copy, None for device nodes and fifos
+    /// that are only in the metadata.
+    pub current: Option<(u32, u32, u32)>,
+}
+
+/// Every path of the rootfs staged in `staging`, parents first: staged paths
+/// get their recorded owner and xattrs, and their recorded mode unless the
+/// staged copy was given another one since it was staged. New paths belong
+/// to root. `locked` are the modes `unlock` changed.
+pub fn wanted_inodes(
+    staging: &Path,
+    metadata: &Metadata,
+    locked: &HashMap<PathBuf, u32>,
+) -> Result<Vec<Wanted>, String> {
+    let mut wanted_inodes = vec![];
+    let mut staged = BTreeSet::new();
+    for entry in WalkDir::new(staging).sort_by_file_name() {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let path = entry
+            .path()
+            .strip_prefix(staging)
+            .expect("walk stays below staging")
+            .to_path_buf();
+        let current = entry
+            .metadata()
+            .map_err(|e| format!("{}: {}", entry.path().display(), e))?;
+        let staged_mode = match locked.get(&path) {
+            Some(mode) => current.mode() & S_IFMT | mode,
+            None => current.mode(),
+        };
+        let mut wanted = Inode {
+            mode: staged_mode,
+            uid: 0,
+            gid: 0,
+            rdev: None,
+            xattrs: BTreeMap::new(),
+        };
+        if let Some(recorded) = metadata.get(&path) {
+            wanted.uid = recorded.uid;
+            wanted.gid = recorded.gid;
+            wanted.xattrs = recorded.xattrs.clone();
+            if recorded.mode & S_IFMT == staged_mode & S_IFMT
+                && staged_mode & 0o7777 == staging_mode(recorded.mode)
+            {
+                wanted.mode = recorded.mode;
+            }
+        }
+        staged.insert(path.clone());
+        wanted_inodes.push(Wanted {
+            path,
+            inode: wanted,
+            current: Some((current.uid(), current.gid(), current.mode())),
+        });
+    }
+    for (path, inode) in metadata {
+        if matches!(inode.mode & S_IFMT, S_IFCHR | S_IFBLK | S_IFIFO) && !staged.contains(path) {
+            wanted_inodes.push(Wanted {
+                path: path.clone(),
+                inode: inode.clone(),
+                current: None,
+            });
+        }
+    }
+    Ok(wanted_inodes)
+}
+
+/// Check the filesystem in `image` without changing it. Returns the summary
+/// of e2fsck, e.g. `rootfs: 53/8192 files (0.0% non-contiguous), 6973/32768
+/// blocks`.
+pub fn check(image: &Path) -> Result<String, String> {
+    let output = Command::new("e2fsck")
+        .arg("-fn")
+        .arg(image)
+        .output()
+        .map_err(|e| format!("e2fsck: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // Exit code 0 is a clean filesystem, anything else found errors or
+    // couldn't check it.
+    if !output.status.success() {
+        let found: Vec<&str> = stdout
+            .lines()
+            .chain(stderr.lines())
+            .filter(|line| !line.starts_with("Pass ") && !line.starts_with("e2fsck "))
+            .filter(|line| !line.trim().is_empty())
+            .take(20)
+            .collect();
+        return Err(format!(
+            "e2fsck found errors in {}: {}",
+            image.display(),
+            found.join("; ")
+        ));
+    }
+    Ok(stdout
+        .lines()
+        .rfind(|line| !line.trim().is_empty())
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Blocks of `image` that no file or filesystem structure uses, in order,
+/// from the block bitmaps.
+pub fn unused_blocks(image: &Path) -> Result<Vec<Range<u64>>, String> {
+    let output = Command::new("dumpe2fs")
+        .arg(image)
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| format!("dumpe2fs: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("{} is not an ext4 image", image.display()));
+    }
+    let bad = |range: &str| format!("dumpe2fs: bad block range `{}`", range);
+    let mut unused = vec![];
+    // Per group, `  Free blocks: 2366-8192, 8200`.
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some(ranges) = line.strip_prefix("  Free blocks: ") else {
+            continue;
+        };
+        for range in ranges.split(", ").filter(|range| !range.trim().is_empty()) {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let first: u64 = first.trim().parse().map_err(|_| bad(range))?;
+            let last: u64 = last.trim().parse().map_err(|_| bad(range))?;
+            unused.push(first..last + 1);
+        }
+    }
+    unused.sort_by_key(|range| range.start);
+    Ok(unused)
+}
+
+/// Give us read access to everything below `dir`, remembering the
+/// permission bits of what had to be changed by path below `staging`.
+pub fn unlock(
+    dir: &Path,
+    staging: &Path,
+    locked: &mut HashMap<PathBuf, u32>,
+) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let path = entry.path();
+        let metadata =
+            fs::symlink_metadata(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let needed = if metadata.is_dir() {
+            0o500
+        } else if metadata.is_file() {
+            0o400
+        } else {
+            continue;
+        };
+        let mode = metadata.permissions().mode() & 0o7777;
+        if mode & needed != needed {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode | needed))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let relative = path.strip_prefix(staging).expect("below staging");
+            locked.insert(relative.to_path_buf(), mode);
+        }
+        if metadata.is_dir() {
+            unlock(&path, staging, locked)?;
+        }
+    }
+    Ok(())
+}
+
+fn set_inode(
+    commands: &mut Vec<String>,
+    path: &Path,
+    wanted: &Inode,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+) {
+    let target = quote(path);
+    if wanted.uid != uid {
+        commands.push(format!("sif {} uid {}", target, wanted.uid));
+    }
+    if wanted.gid != gid {
+        commands.push(format!("sif {} gid {}", target, wanted.gid));
+    }
+    if wanted.mode != mode && wanted.mode & S_IFMT != S_IFLNK {
+        commands.push(format!("sif {} mode 0{:o}", target, wanted.mode));
+    }
+}
@@ -1,188 +1,669 @@
-use crate::merge_filesystem::prepare_filesystem;
+use crate::cache;
+use crate::merge_filesystem::{prepare_filesystem, PrepareStep};
+use crate::sparse;
 
+use crate::config::Config;
+use crate::events::{emit, DeviceRef, Event, EventSink, FlashEvent, PlannedStep};
 use crate::DeviceInfo;
 use crate::FlashInfo;
-use log::{debug, error, info, warn};
-use slint::Model;
-use slint::ModelRc;
-use slint::SharedString;
-use slint::Weak;
-//use slint::*;
+use log::{debug, error, info};
+use regex::Regex;
 use slint::ComponentHandle;
-use slint::Window;
-use std::env;
+use slint::Weak;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
-use std::process::{exit, Stdio};
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
 use std::thread;
-use tokio::io::AsyncReadExt;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
-use tokio::time::Duration;
+use tokio::sync::broadcast;
 //use ui::*;
 use crate::ui::*;
 
-pub fn flash_setup(window: &MainWindow, flash: FlashInfo) -> std::thread::JoinHandle<()> {
-    let window_weak = window.as_weak();
-    thread::spawn(move || {
-        tokio::runtime::Runtime::new()
-            .unwrap()
-            .block_on(rk_flash_start(window_weak, flash))
-            .unwrap()
-    })
+// Rough figures used by the dry-run estimate. upgrade_tool writes at roughly
+// 10 MiB/s over USB2, and every invocation costs about two seconds of
+// handshake with the loader.
+const WRITE_BYTES_PER_SEC: u64 = 10 * 1024 * 1024;
+const STEP_OVERHEAD: Duration = Duration::from_secs(2);
+const RESET_DURATION: Duration = Duration::from_secs(3);
+const ERASE_DURATION: Duration = Duration::from_secs(30);
+
+#[derive(Default, Debug, Clone)]
+pub struct FlashOptions {
+    /// Print the plan instead of running it, nothing is written to devices.
+    pub dry_run: bool,
+    /// Don't build the rootfs image, use the expected path as-is.
+    pub skip_prepare: bool,
+    /// Batch values of the rootfs templates, see manifest.rs.
+    pub variables: BTreeMap<String, String>,
 }
-fn update_flash_progress(window_weak: Weak<MainWindow>, progress: &str) {
-    let progress = progress.to_string();
-    window_weak.upgrade_in_event_loop(move |window| {
-        let mut flash = window.global::<ControlsPageAdapter>().get_flash();
-        let mut flash_info: FlashInfo = flash.clone().into();
-        let devices_info = &mut flash_info.devices;
 
-        let index = 0;
-        if let Some(device) = devices_info.get_mut(index) {
-            device.progress = progress;
-        }
-        flash.devices = flash_info.devices_to_model_rc();
+/// One upgrade_tool invocation for one device.
+#[derive(Debug, Clone)]
+pub struct FlashStep {
+    pub label: &'static str,
+    pub args: Vec<String>,
+    pub estimate: Duration,
+}
 
-        window.global::<ControlsPageAdapter>().set_flash(flash);
-    });
+#[derive(Debug, Clone)]
+pub struct DevicePlan {
+    pub device: DeviceInfo,
+    pub steps: Vec<FlashStep>,
 }
 
-//RUST_LOG=debug
-pub async fn rk_flash_start(
-    window_weak: Weak<MainWindow>,
-    flash: FlashInfo,
-) -> tokio::io::Result<()> {
-    // Define the paths
-    let common_dir = fs::canonicalize(env::current_dir().unwrap().to_str().unwrap())
-        .expect("Failed to get common directory");
+impl DevicePlan {
+    pub fn estimate(&self) -> Duration {
+        self.steps.iter().map(|step| step.estimate).sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FlashPlan {
+    pub upgrade_tool: PathBuf,
+    pub devices: Vec<DevicePlan>,
+}
+
+impl FlashPlan {
+    pub fn estimate(&self) -> Duration {
+        self.devices.iter().map(|device| device.estimate()).sum()
+    }
+
+    fn planned_steps(&self, device: &DevicePlan) -> Vec<PlannedStep> {
+        device
+            .steps
+            .iter()
+            .map(|step| PlannedStep {
+                label: step.label.to_string(),
+                command: format!("{} {}", self.upgrade_tool.display(), step.args.join(" ")),
+                estimate_secs: step.estimate.as_secs(),
+            })
+            .collect()
+    }
+}
+
+fn write_estimate(image: &Path) -> Duration {
+    let size = fs::metadata(image).map(|m| m.len()).unwrap_or(0);
+    STEP_OVERHEAD + Duration::from_secs_f64(size as f64 / WRITE_BYTES_PER_SEC as f64)
+}
+
+// `-s <loc_id> <command...> <image> <suffix...>`
+fn image_step(
+    label: &'static str,
+    loc_id: &str,
+    command: &[&str],
+    image: &Path,
+    suffix: &[&str],
+) -> FlashStep {
+    let mut step_args = vec!["-s".to_string(), loc_id.to_string()];
+    step_args.extend(command.iter().map(|arg| arg.to_string()));
+    step_args.push(image.display().to_string());
+    step_args.extend(suffix.iter().map(|arg| arg.to_string()));
+    FlashStep {
+        label,
+        args: step_args,
+        estimate: write_estimate(image),
+    }
+}
 
-    //let sdk_dir = fs::canonicalize(common_dir.join("..")).expect("Failed to get SDK directory");
-    let upgrade_tool = common_dir.join("tools/rk_flash_tools/upgrade_tool");
-    let rockdev_dir = common_dir.join("rockdev");
+/// Resolve every command that a flash of `flash` would run, without running any.
+pub fn build_plan(
+    config: &Config,
+    flash: &FlashInfo,
+    flash_type: &str,
+    rootfs: &Path,
+) -> FlashPlan {
+    let upgrade_tool = config.paths.upgrade_tool.clone();
+    let rockdev_dir = &config.paths.image_dir;
 
     let loader = rockdev_dir.join("loader.bin");
     let parameter = rockdev_dir.join("parameter.txt");
     let uboot = rockdev_dir.join("uboot.img");
     let boot = rockdev_dir.join("boot.img");
 
-    let rootfs = prepare_filesystem(&flash.version_selected, &flash.board_type).unwrap();
-    // Check the flash type argument
-    let flash_type = env::args().nth(1).unwrap_or_else(|| "all".to_string());
-
-    // Ensure upgrade_tool exists and is executable
-    if !upgrade_tool.exists() {
-        eprintln!("{} not found.", upgrade_tool.display());
-        exit(1);
-    }
-
-    // Filter out devices with checked == true
-    let selected_devices: Vec<&DeviceInfo> = flash
+    let devices = flash
         .devices
         .iter()
         .filter(|device| device.checked)
+        .map(|d| {
+            let mut steps = vec![];
+            if flash_type == "all" {
+                steps.push(image_step(
+                    "upgrade loader",
+                    &d.loc_id,
+                    &["ul"],
+                    &loader,
+                    &["-noreset"],
+                ));
+                steps.push(image_step(
+                    "writing parameter",
+                    &d.loc_id,
+                    &["di", "-p"],
+                    &parameter,
+                    &[],
+                ));
+                steps.push(image_step(
+                    "Writing uboot",
+                    &d.loc_id,
+                    &["di", "-uboot"],
+                    &uboot,
+                    &[],
+                ));
+                steps.push(image_step(
+                    "Writing boot",
+                    &d.loc_id,
+                    &["di", "-b"],
+                    &boot,
+                    &[],
+                ));
+                // upgrade_tool writes only the chunks of a sparse image.
+                let label = if sparse::is_sparse(rootfs) {
+                    "Writing sparse rootfs"
+                } else {
+                    "Writing rootfs"
+                };
+                steps.push(image_step(
+                    label,
+                    &d.loc_id,
+                    &["di", "-rootfs"],
+                    rootfs,
+                    &[],
+                ));
+            } else if flash_type == "erase" {
+                steps.push(FlashStep {
+                    label: "Erase flash",
+                    args: vec![
+                        "-s".into(),
+                        d.loc_id.clone(),
+                        "ef".into(),
+                        loader.display().to_string(),
+                    ],
+                    estimate: ERASE_DURATION,
+                });
+            }
+            if !steps.is_empty() {
+                steps.push(FlashStep {
+                    label: "Reset Device",
+                    args: vec!["-s".into(), d.loc_id.clone(), "rd".into()],
+                    estimate: RESET_DURATION,
+                });
+            }
+            DevicePlan {
+                device: d.clone(),
+                steps,
+            }
+        })
         .collect();
-    debug!("Selected devices for flashing: {:?}", selected_devices);
 
-    for d in selected_devices {
-        if flash_type == "all" {
-            println!("Flashing d with LocationID: {}", d.loc_id);
-            // Run the upgrade_tool commands
+    FlashPlan {
+        upgrade_tool,
+        devices,
+    }
+}
 
-            update_flash_progress(window_weak.clone(), "upgrade loader");
-            run_command_with_progress(
-                &upgrade_tool,
-                &["-s", &d.loc_id, "ul", loader.to_str().unwrap(), "-noreset"],
-            );
+#[derive(Debug, Clone)]
+pub struct DeviceResult {
+    pub error: Option<String>,
+}
 
-            update_flash_progress(window_weak.clone(), "writing parameter");
-            run_command(
-                &upgrade_tool,
-                &["-s", &d.loc_id, "di", "-p", parameter.to_str().unwrap()],
-            );
+impl DeviceResult {
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+}
 
-            update_flash_progress(window_weak.clone(), "Writing uboot");
-            run_command(
-                &upgrade_tool,
-                &["-s", &d.loc_id, "di", "-uboot", uboot.to_str().unwrap()],
-            );
+/// Shared stop flag of a running job. Checked between steps, and a running
+/// upgrade_tool is killed when it is set.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
 
-            update_flash_progress(window_weak.clone(), "Writing boot");
-            run_command(
-                &upgrade_tool,
-                &["-s", &d.loc_id, "di", "-b", boot.to_str().unwrap()],
-            );
-            update_flash_progress(window_weak.clone(), "Writing rootfs");
-            run_command(
-                &upgrade_tool,
-                &["-s", &d.loc_id, "di", "-rootfs", rootfs.to_str().unwrap()],
-            );
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 
-            update_flash_progress(window_weak.clone(), "Reset Device");
-            run_command(&upgrade_tool, &["-s", &d.loc_id, "rd"]);
-            update_flash_progress(window_weak.clone(), "SUCCESS");
+    async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
     }
+}
 
-    Ok(())
+/// Mirror job events into the device rows of the Controls page, whoever
+/// started the job.
+pub fn show_job_events(window: &MainWindow, mut events: broadcast::Receiver<Event>) {
+    let window_weak = window.as_weak();
+    thread::spawn(move || loop {
+        let event = match events.blocking_recv() {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let window_weak = window_weak.clone();
+        match &event.event {
+            FlashEvent::JobStarted { .. } => update_dry_run_plan(window_weak, None),
+            FlashEvent::DevicePlanned {
+                device,
+                steps,
+                estimate_secs,
+            } => {
+                update_dry_run_plan(window_weak.clone(), Some(event.event.to_string()));
+                update_flash_progress(
+                    window_weak,
+                    &device.loc_id,
+                    &format!("dry-run: {} steps, ~{}s", steps.len(), estimate_secs),
+                );
+            }
+            FlashEvent::StepStarted { device, label, .. } => {
+                update_flash_progress(window_weak, &device.loc_id, label)
+            }
+            FlashEvent::StepProgress {
+                device,
+                label,
+                percent,
+                ..
+            } => update_flash_progress(
+                window_weak,
+                &device.loc_id,
+                &format!("{} {}%", label, percent),
+            ),
+            FlashEvent::StepFinished {
+                device,
+                label,
+                success: false,
+                ..
+            } => update_flash_progress(window_weak, &device.loc_id, &format!("FAILED: {}", label)),
+            FlashEvent::DeviceResult {
+                device,
+                success: true,
+                ..
+            } => update_flash_progress(window_weak, &device.loc_id, "SUCCESS"),
+            FlashEvent::PrepareStarted { .. } => {
+                update_prepare_progress(window_weak, "Preparing image", 0.0)
+            }
+            FlashEvent::PrepareProgress {
+                step,
+                steps,
+                label,
+                percent,
+                ..
+            } => {
+                let overall = (*step as f32 - 1.0 + *percent as f32 / 100.0) / *steps as f32;
+                update_prepare_progress(
+                    window_weak,
+                    &format!("image ({}/{}) {} {}%", step, steps, label, percent),
+                    overall,
+                );
+            }
+            FlashEvent::PrepareFinished { success: true, .. } => {
+                update_prepare_progress(window_weak, "", 1.0)
+            }
+            FlashEvent::PrepareFinished { error: Some(e), .. } => {
+                update_prepare_progress(window_weak, &format!("image FAILED: {}", e), 0.0)
+            }
+            FlashEvent::Error { message } | FlashEvent::JobFailed { error: message, .. } => {
+                error!("Flash failed: {}", message)
+            }
+            _ => {}
+        }
+    });
 }
 
-// Function to run a command and handle errors
-fn run_command(command: &PathBuf, args: &[&str]) {
-    let status = std::process::Command::new(command)
-        .args(args)
-        .status()
-        .expect("Failed to execute command");
+// The image stage above the device list, and the rows of the devices that
+// wait for it.
+fn update_prepare_progress(window_weak: Weak<MainWindow>, status: &str, progress: f32) {
+    let status = status.to_string();
+    let _ = window_weak.upgrade_in_event_loop(move |window| {
+        let adapter = window.global::<ControlsPageAdapter>();
+        let mut flash = adapter.get_flash();
+        let mut flash_info: FlashInfo = flash.clone().into();
+        for device in flash_info.devices.iter_mut().filter(|d| d.checked) {
+            device.progress = if status.is_empty() {
+                "image ready".to_string()
+            } else {
+                status.clone()
+            };
+        }
+        flash.devices = flash_info.devices_to_model_rc();
+        adapter.set_flash(flash);
+        adapter.set_prepare_status(status.into());
+        adapter.set_prepare_progress(progress);
+    });
+}
 
-    if !status.success() {
-        error!("Command {:?} failed with status: {:?}", args, status);
-        exit(1);
-    }
+// The commands of a dry run below the device rows, one device after the
+// other. `None` clears them for the next job.
+fn update_dry_run_plan(window_weak: Weak<MainWindow>, device: Option<String>) {
+    let _ = window_weak.upgrade_in_event_loop(move |window| {
+        let adapter = window.global::<ControlsPageAdapter>();
+        let plan = match device {
+            Some(device) if adapter.get_dry_run_plan().is_empty() => device,
+            Some(device) => format!("{}\n{}", adapter.get_dry_run_plan(), device),
+            None => String::new(),
+        };
+        adapter.set_dry_run_plan(plan.into());
+    });
 }
 
-fn swicth_to_maskrom(flash: FlashInfo) {
-    let common_dir = fs::canonicalize(env::current_dir().unwrap().to_str().unwrap())
-        .expect("Failed to get common directory");
+fn update_flash_progress(window_weak: Weak<MainWindow>, loc_id: &str, progress: &str) {
+    let loc_id = loc_id.to_string();
+    let progress = progress.to_string();
+    let _ = window_weak.upgrade_in_event_loop(move |window| {
+        let mut flash = window.global::<ControlsPageAdapter>().get_flash();
+        let mut flash_info: FlashInfo = flash.clone().into();
 
-    //let sdk_dir = fs::canonicalize(common_dir.join("..")).expect("Failed to get SDK directory");
-    let upgrade_tool = common_dir.join("tools/rk_flash_tools/upgrade_tool");
+        if let Some(device) = flash_info.devices.iter_mut().find(|d| d.loc_id == loc_id) {
+            device.progress = progress;
+        }
+        flash.devices = flash_info.devices_to_model_rc();
 
-    let selected_devices: Vec<&DeviceInfo> = flash
-        .devices
-        .iter()
-        .filter(|device| device.checked)
-        .collect();
-    debug!("Selected devices for flashing: {:?}", selected_devices);
-    for d in selected_devices {
-        run_command(&upgrade_tool, &["-s", &d.loc_id, "rd", "3"]);
+        window.global::<ControlsPageAdapter>().set_flash(flash);
+    });
+}
+
+/// Build the rootfs image of `board` from `version` like `prepare_filesystem`,
+/// with every step reported as events. Setting `cancel` stops the build.
+pub fn prepare_image(
+    config: &Config,
+    board: &str,
+    version: &str,
+    variables: &BTreeMap<String, String>,
+    cancel: &CancelToken,
+    events: &EventSink,
+) -> Result<PathBuf, String> {
+    let profile = config.board(board)?;
+    let steps = PrepareStep::steps(profile);
+    let start = Instant::now();
+    emit(
+        events,
+        FlashEvent::PrepareStarted {
+            board: board.to_string(),
+            version: version.to_string(),
+            steps: steps.iter().map(|step| step.label().to_string()).collect(),
+        },
+    );
+
+    let mut last = None;
+    let mut progress = |step: PrepareStep, done: u64, total: u64| {
+        if cancel.is_cancelled() {
+            return Err("cancelled".to_string());
+        }
+        let percent = (done * 100).checked_div(total).unwrap_or(100).min(100) as u8;
+        if last == Some((step, percent)) {
+            return Ok(());
+        }
+        last = Some((step, percent));
+        let index = steps.iter().position(|s| *s == step).unwrap_or(0);
+        emit(
+            events,
+            FlashEvent::PrepareProgress {
+                step: index + 1,
+                steps: steps.len(),
+                label: step.label().to_string(),
+                done_bytes: done,
+                total_bytes: total,
+                percent,
+            },
+        );
+        Ok(())
+    };
+    let outcome =
+        prepare_filesystem(config, version, profile, variables, &mut progress).map_err(|e| {
+            if cancel.is_cancelled() {
+                "cancelled".to_string()
+            } else {
+                e.to_string()
+            }
+        });
+
+    let entry = outcome.as_ref().ok().and_then(|image| cache::entry(image));
+    emit(
+        events,
+        FlashEvent::PrepareFinished {
+            board: board.to_string(),
+            version: version.to_string(),
+            success: outcome.is_ok(),
+            duration_ms: start.elapsed().as_millis() as u64,
+            error: outcome.as_ref().err().cloned(),
+            fsck: entry
+                .as_ref()
+                .map(|entry| entry.fsck.clone())
+                .filter(|fsck| !fsck.is_empty()),
+            variables: entry.map(|entry| entry.values).unwrap_or_default(),
+        },
+    );
+    outcome
+}
+
+/// Flash every checked device in `flash`. A failing device doesn't stop the
+/// others, the outcome of each is in the returned list. An `Err` means the
+/// batch couldn't start at all, it is reported as `JobFailed`.
+//RUST_LOG=debug
+pub async fn rk_flash_start(
+    config: &Config,
+    job_id: String,
+    flash: FlashInfo,
+    flash_type: String,
+    options: FlashOptions,
+    cancel: CancelToken,
+    events: EventSink,
+) -> Result<Vec<DeviceResult>, Box<dyn Error + Send + Sync>> {
+    let batch_start = Instant::now();
+    let plan = match plan_batch(config, &flash, &flash_type, &options, &cancel, &events) {
+        Ok(plan) => plan,
+        Err(e) => {
+            emit(
+                &events,
+                FlashEvent::JobFailed {
+                    job_id,
+                    board: flash.board_type.clone(),
+                    version: flash.version_selected.clone(),
+                    recipe: flash_type,
+                    dry_run: options.dry_run,
+                    devices: flash
+                        .devices
+                        .iter()
+                        .filter(|device| device.checked)
+                        .map(DeviceRef::from)
+                        .collect(),
+                    duration_ms: batch_start.elapsed().as_millis() as u64,
+                    error: e.to_string(),
+                },
+            );
+            return Err(e);
+        }
+    };
+
+    let job_start = Instant::now();
+    emit(
+        &events,
+        FlashEvent::JobStarted {
+            job_id: job_id.clone(),
+            board: flash.board_type.clone(),
+            version: flash.version_selected.clone(),
+            recipe: flash_type.clone(),
+            dry_run: options.dry_run,
+            estimate_secs: plan.estimate().as_secs(),
+            devices: plan
+                .devices
+                .iter()
+                .map(|device| DeviceRef::from(&device.device))
+                .collect(),
+        },
+    );
+
+    let mut results = vec![];
+    for device in &plan.devices {
+        let device_ref = DeviceRef::from(&device.device);
+        let device_start = Instant::now();
+        let mut result = DeviceResult { error: None };
+
+        if cancel.is_cancelled() {
+            result.error = Some("cancelled".to_string());
+        } else if options.dry_run {
+            emit(
+                &events,
+                FlashEvent::DevicePlanned {
+                    device: device_ref.clone(),
+                    steps: plan.planned_steps(device),
+                    estimate_secs: device.estimate().as_secs(),
+                },
+            );
+        } else {
+            info!("Flashing device with LocationID: {}", device_ref.loc_id);
+            for (index, step) in device.steps.iter().enumerate() {
+                if cancel.is_cancelled() {
+                    result.error = Some(format!("{}: cancelled", step.label));
+                    break;
+                }
+                let step_no = index + 1;
+                let step_start = Instant::now();
+                emit(
+                    &events,
+                    FlashEvent::StepStarted {
+                        device: device_ref.clone(),
+                        step: step_no,
+                        steps: device.steps.len(),
+                        label: step.label.to_string(),
+                    },
+                );
+
+                let on_percent: PercentFn = {
+                    let events = events.clone();
+                    let device_ref = device_ref.clone();
+                    let label = step.label;
+                    Arc::new(move |percent| {
+                        emit(
+                            &events,
+                            FlashEvent::StepProgress {
+                                device: device_ref.clone(),
+                                step: step_no,
+                                label: label.to_string(),
+                                percent,
+                            },
+                        )
+                    })
+                };
+                let outcome =
+                    run_command_with_progress(&plan.upgrade_tool, &step.args, on_percent, &cancel)
+                        .await;
+                if let Err(e) = &outcome {
+                    error!("Command {:?} failed: {}", step.args, e);
+                }
+                emit(
+                    &events,
+                    FlashEvent::StepFinished {
+                        device: device_ref.clone(),
+                        step: step_no,
+                        label: step.label.to_string(),
+                        success: outcome.is_ok(),
+                        duration_ms: step_start.elapsed().as_millis() as u64,
+                        error: outcome.as_ref().err().map(|e| e.to_string()),
+                    },
+                );
+                if let Err(e) = outcome {
+                    result.error = Some(format!("{}: {}", step.label, e));
+                    break;
+                }
+            }
+        }
+
+        emit(
+            &events,
+            FlashEvent::DeviceResult {
+                job_id: job_id.clone(),
+                device: device_ref,
+                success: result.success(),
+                duration_ms: device_start.elapsed().as_millis() as u64,
+                error: result.error.clone(),
+            },
+        );
+        results.push(result);
     }
+
+    let failed = results.iter().filter(|r| !r.success()).count();
+    emit(
+        &events,
+        FlashEvent::BatchSummary {
+            job_id,
+            total: results.len(),
+            succeeded: results.len() - failed,
+            failed,
+            duration_ms: job_start.elapsed().as_millis() as u64,
+        },
+    );
+
+    Ok(results)
 }
 
-async fn update_progress(
-    progress_sender: &Arc<slint::ModelRc<slint::SharedString>>,
-    progress: &str,
-) -> Result<(), Box<dyn Error>> {
-    // Clone the current SharedString content and append the new progress line
-    let mut new_progress = progress_sender
-        .row_data(0)
-        .as_ref()
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| String::from(""));
-
-    new_progress.push_str(progress);
-    new_progress.push('\n');
-
-    // Update the SharedString in the model
-    progress_sender.set_row_data(0, slint::SharedString::from(new_progress));
-    Ok(())
+// Find or prepare the rootfs image and plan the steps of every device.
+fn plan_batch(
+    config: &Config,
+    flash: &FlashInfo,
+    flash_type: &str,
+    options: &FlashOptions,
+    cancel: &CancelToken,
+    events: &EventSink,
+) -> Result<FlashPlan, Box<dyn Error + Send + Sync>> {
+    let rootfs = if flash_type != "all" {
+        PathBuf::new()
+    } else if options.skip_prepare {
+        let image = cache::cached_image(
+            config,
+            &flash.board_type,
+            &flash.version_selected,
+            &options.variables,
+        )?;
+        cache::mark_used(&image);
+        image
+    } else {
+        prepare_image(
+            config,
+            &flash.board_type,
+            &flash.version_selected,
+            &options.variables,
+            cancel,
+            events,
+        )?
+    };
+    let rootfs = fs::canonicalize(&rootfs).unwrap_or(rootfs);
+
+    let plan = build_plan(config, flash, flash_type, &rootfs);
+    debug!("Flash plan: {:?}", plan);
+
+    // Ensure upgrade_tool exists and is executable
+    if !options.dry_run && !plan.upgrade_tool.exists() {
+        return Err(format!("{} not found.", plan.upgrade_tool.display()).into());
+    }
+    Ok(plan)
+}
+
+type PercentFn = Arc<dyn Fn(u8) + Send + Sync>;
+
+// upgrade_tool reports write progress as e.g. `Download image... (45%)`.
+static PERCENT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\d{1,3})%").unwrap());
+
+fn parse_percent(line: &str) -> Option<u8> {
+    PERCENT
+        .captures_iter(line)
+        .last()
+        .and_then(|caps| caps[1].parse::<u8>().ok())
+        .filter(|percent| *percent <= 100)
 }
 
 async fn run_command_with_progress(
     command: &PathBuf,
-    args: &[&str],
+    args: &[String],
+    on_percent: PercentFn,
+    cancel: &CancelToken,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut child = tokio::process::Command::new(command)
         .args(args)
@@ -199,8 +680,15 @@ async fn run_command_with_progress(
 
     // 使用 tokio::spawn 让标准输出和错误输出并行处理
     let stdout_handle = tokio::spawn(async move {
+        let mut last_percent = None;
         while let Some(line) = stdout_reader.next_line().await? {
             log::info!("STDOUT: {}", line);
+            if let Some(percent) = parse_percent(&line) {
+                if last_percent != Some(percent) {
+                    last_percent = Some(percent);
+                    on_percent(percent);
+                }
+            }
         }
         Ok::<_, Box<dyn Error + Send + Sync>>(())
     });
@@ -213,7 +701,13 @@ async fn run_command_with_progress(
     });
 
     // 等待命令完成
-    let status = child.wait().await?;
+    let status = tokio::select! {
+        status = child.wait() => status?,
+        _ = cancel.cancelled() => {
+            child.kill().await?;
+            return Err("cancelled".into());
+        }
+    };
     stdout_handle.await??;
     stderr_handle.await??;
 
@@ -223,41 +717,3 @@ async fn run_command_with_progress(
 
     Ok(())
 }
-async fn __update_flash_progress(progress: String) -> Result<(), Box<dyn Error>> {
-    Ok(())
-}
-
-async fn flash_worker_loop(window_weak: Weak<MainWindow>) {
-    let common_dir = fs::canonicalize(env::current_dir().unwrap().to_str().unwrap()).unwrap();
-    let upgrade_tool = common_dir.join("tools/rk_flash_tools/upgrade_tool");
-    let rockdev_dir = common_dir.join("rockdev");
-    let loader = rockdev_dir.join("loader.bin");
-    let parameter = rockdev_dir.join("parameter.txt");
-    let uboot = rockdev_dir.join("uboot.img");
-    let boot = rockdev_dir.join("boot.img");
-    let rootfs = rockdev_dir.join("rootfs.img");
-
-    update_flash_progress(window_weak.clone(), "upgrade loader...");
-    run_command_with_progress(&upgrade_tool, &["ul", loader.to_str().unwrap(), "-noreset"]).await;
-
-    update_flash_progress(window_weak.clone(), "writing parameter");
-    run_command_with_progress(&upgrade_tool, &["di", "-p", parameter.to_str().unwrap()]).await;
-
-    update_flash_progress(window_weak.clone(), "Writing uboot image...");
-    run_command_with_progress(&upgrade_tool, &["di", "-uboot", uboot.to_str().unwrap()]).await;
-
-    update_flash_progress(window_weak.clone(), "Writing boot image...");
-    run_command_with_progress(&upgrade_tool, &["di", "-b", boot.to_str().unwrap()]).await;
-
-    update_flash_progress(window_weak.clone(), "Writing rootfs image...");
-    run_command_with_progress(&upgrade_tool, &["di", "-rootfs", rootfs.to_str().unwrap()]).await;
-
-    update_flash_progress(window_weak.clone(), "Reset Device...");
-    run_command_with_progress(&upgrade_tool, &["rd"]).await;
-
-    update_flash_progress(window_weak, "Success.");
-}
-
-fn callback_func_register() -> Result<(), ()> {
-    Ok(())
-}
@@ -0,0 +1,312 @@
+use crate::config::Config;
+use crate::events::{new_job_id, timestamp_now, DeviceRef, Event, EventSink, FlashEvent};
+use crate::flash::{rk_flash_start, CancelToken, FlashOptions};
+use crate::webhook::Reporter;
+use crate::FlashInfo;
+use log::error;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use tokio::sync::broadcast;
+
+// Finished jobs kept for reports.
+const JOB_HISTORY: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelection {
+    All,
+    LocIds(Vec<String>),
+}
+
+impl DeviceSelection {
+    /// `["all"]` or a list of LocationIDs.
+    pub fn from_list(list: Vec<String>) -> Self {
+        if list.iter().any(|d| d == "all") {
+            DeviceSelection::All
+        } else {
+            DeviceSelection::LocIds(list)
+        }
+    }
+}
+
+// Resolve the selection against the connected devices, every requested
+// LocationID must be present.
+pub fn select_devices(config: &Config, selection: &DeviceSelection) -> Result<FlashInfo, String> {
+    let mut flash_info = FlashInfo::default();
+    flash_info.update_device_list(config);
+
+    match selection {
+        DeviceSelection::All => {
+            if flash_info.devices.is_empty() {
+                return Err("no devices connected".to_string());
+            }
+        }
+        DeviceSelection::LocIds(loc_ids) => {
+            for loc_id in loc_ids {
+                if !flash_info.devices.iter().any(|d| &d.loc_id == loc_id) {
+                    return Err(format!("device with LocationID {} not connected", loc_id));
+                }
+            }
+            for device in &mut flash_info.devices {
+                device.checked = loc_ids.contains(&device.loc_id);
+            }
+        }
+    }
+    Ok(flash_info)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    /// Ran to the end, see the device results for the outcome of each device.
+    Finished,
+    /// Couldn't start, e.g. the image build failed.
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceReport {
+    #[serde(flatten)]
+    pub device: DeviceRef,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobReport {
+    pub job_id: String,
+    pub status: JobStatus,
+    pub board: String,
+    pub version: String,
+    pub recipe: String,
+    pub dry_run: bool,
+    /// Template variables of the batch, every value the image was built
+    /// with once it is prepared.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+    pub started_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub devices: Vec<DeviceReport>,
+    /// Everything but the progress events, which are only broadcast.
+    pub events: Vec<Event>,
+}
+
+struct Job {
+    report: JobReport,
+    cancel: CancelToken,
+}
+
+/// Runs flash jobs for the GUI and the API, one at a time per station, and
+/// broadcasts their events to every subscriber.
+pub struct JobManager {
+    config: Arc<Config>,
+    jobs: Mutex<VecDeque<Job>>,
+    events: broadcast::Sender<Event>,
+    // Spools the webhook records, before anything can miss the broadcast.
+    reporter: OnceLock<Arc<Reporter>>,
+}
+
+impl JobManager {
+    pub fn new(config: Arc<Config>) -> Arc<Self> {
+        let (events, _) = broadcast::channel(1024);
+        Arc::new(Self {
+            config,
+            jobs: Mutex::new(VecDeque::new()),
+            events,
+            reporter: OnceLock::new(),
+        })
+    }
+
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Hand every recorded event to `reporter`.
+    pub fn set_reporter(&self, reporter: Arc<Reporter>) {
+        let _ = self.reporter.set(reporter);
+    }
+
+    /// Start flashing the checked devices of `flash` in the background.
+    /// Returns the job id, or an error if a job is already running.
+    pub fn start(
+        self: &Arc<Self>,
+        flash: FlashInfo,
+        flash_type: &str,
+        options: FlashOptions,
+    ) -> Result<String, String> {
+        let job_id = new_job_id();
+        let cancel = CancelToken::default();
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            if jobs
+                .iter()
+                .any(|job| job.report.status == JobStatus::Running)
+            {
+                return Err("a job is already running".to_string());
+            }
+            if jobs.len() >= JOB_HISTORY {
+                jobs.pop_front();
+            }
+            jobs.push_back(Job {
+                report: JobReport {
+                    job_id: job_id.clone(),
+                    status: JobStatus::Running,
+                    board: flash.board_type.clone(),
+                    version: flash.version_selected.clone(),
+                    recipe: flash_type.to_string(),
+                    dry_run: options.dry_run,
+                    variables: options.variables.clone(),
+                    started_at: timestamp_now(),
+                    finished_at: None,
+                    error: None,
+                    devices: vec![],
+                    events: vec![],
+                },
+                cancel: cancel.clone(),
+            });
+        }
+
+        let manager = self.clone();
+        let sink: EventSink = {
+            let manager = self.clone();
+            let job_id = job_id.clone();
+            Arc::new(move |event| manager.record(&job_id, event))
+        };
+        let flash_type = flash_type.to_string();
+        let id = job_id.clone();
+        let config = self.config.clone();
+        thread::spawn(move || {
+            let outcome = tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(rk_flash_start(
+                    &config,
+                    id.clone(),
+                    flash,
+                    flash_type,
+                    options,
+                    cancel.clone(),
+                    sink.clone(),
+                ));
+            if let Err(e) = &outcome {
+                error!("Flash failed: {}", e);
+            }
+            manager.finish(&id, outcome.err().map(|e| e.to_string()), &cancel);
+        });
+
+        Ok(job_id)
+    }
+
+    /// Ask a running job to stop. Returns false if there is no such running job.
+    pub fn stop(&self, job_id: &str) -> bool {
+        let jobs = self.jobs.lock().unwrap();
+        match jobs
+            .iter()
+            .find(|job| job.report.job_id == job_id && job.report.status == JobStatus::Running)
+        {
+            Some(job) => {
+                job.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn stop_running(&self) {
+        for job in self.jobs.lock().unwrap().iter() {
+            if job.report.status == JobStatus::Running {
+                job.cancel.cancel();
+            }
+        }
+    }
+
+    /// Reports without the event log, newest last.
+    pub fn jobs(&self) -> Vec<JobReport> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| JobReport {
+                events: vec![],
+                ..job.report.clone()
+            })
+            .collect()
+    }
+
+    pub fn report(&self, job_id: &str) -> Option<JobReport> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|job| job.report.job_id == job_id)
+            .map(|job| job.report.clone())
+    }
+
+    fn record(&self, job_id: &str, event: &Event) {
+        if let Some(job) = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|job| job.report.job_id == job_id)
+        {
+            match &event.event {
+                FlashEvent::DeviceResult {
+                    device,
+                    success,
+                    error,
+                    ..
+                } => job.report.devices.push(DeviceReport {
+                    device: device.clone(),
+                    success: *success,
+                    error: error.clone(),
+                }),
+                FlashEvent::PrepareFinished { variables, .. } if !variables.is_empty() => {
+                    job.report.variables = variables.clone();
+                }
+                _ => {}
+            }
+            if !matches!(
+                event.event,
+                FlashEvent::StepProgress { .. } | FlashEvent::PrepareProgress { .. }
+            ) {
+                job.report.events.push(event.clone());
+            }
+        }
+        if let Some(reporter) = self.reporter.get() {
+            reporter.handle(event);
+        }
+        // Nobody listening is fine.
+        let _ = self.events.send(event.clone());
+    }
+
+    fn finish(&self, job_id: &str, error: Option<String>, cancel: &CancelToken) {
+        if let Some(job) = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|job| job.report.job_id == job_id)
+        {
+            job.report.status = if cancel.is_cancelled() {
+                JobStatus::Cancelled
+            } else if error.is_some() {
+                JobStatus::Failed
+            } else {
+                JobStatus::Finished
+            };
+            job.report.error = error;
+            job.report.finished_at = Some(timestamp_now());
+        }
+    }
+}
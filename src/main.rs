@@ -3,22 +3,41 @@
 use log::debug;
 
 use slint::*;
-use tokio::sync::watch::error;
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
-use slint::{Model, StandardListViewItem, VecModel};
-use std::env;
-use std::fs;
-use std::path::PathBuf;
+use slint::{Model, VecModel};
 use std::process::{exit, Command};
-use std::thread;
-use tokio::time::Duration;
+use std::sync::Arc;
+mod api;
+mod archive;
+mod board;
+mod cache;
+mod changes;
+mod cli;
+mod config;
+mod events;
+mod ext4;
 mod flash;
+mod jobs;
+mod manifest;
 mod merge_filesystem;
-
-use flash::flash_setup;
-use flash::rk_flash_start;
+mod parameter;
+mod preflight;
+mod reproducible;
+mod rootfs;
+mod scratch;
+mod sparse;
+#[cfg(test)]
+mod testing;
+mod webhook;
+
+use cli::CliCommand;
+use config::Config;
+use flash::show_job_events;
+use flash::FlashOptions;
+use jobs::JobManager;
+use preflight::Finding;
 use regex::Regex;
 
 pub mod ui {
@@ -26,16 +45,16 @@ pub mod ui {
 }
 
 use ui::*;
-//supported board ,add new board here
-const SUPPORTED_BOARDS: &[&str] = &["dc11p626", "dc21scu"];
 
 #[derive(Default, Debug, Clone)]
 struct FlashInfo {
-    supported_board: Vec<String>,
     board_type: String,
     version_list: Vec<String>,
     version_selected: String,
     devices: Vec<DeviceInfo>,
+    dry_run: bool,
+    /// The batch variables form, `name=value` lines.
+    variables: String,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -64,59 +83,50 @@ impl From<device_info> for DeviceInfo {
 impl From<flash_info> for FlashInfo {
     fn from(flash_info: flash_info) -> Self {
         Self {
-            supported_board: flash_info
-                .supported_board
+            board_type: flash_info.board_type.to_string(),
+            version_list: flash_info
+                .version_list
                 .iter()
-                .map(|board| board.to_string()) // 根据实际类型进行转换
+                .map(|version| version.to_string())
                 .collect(),
-            board_type: flash_info.board_type.to_string(),
-            version_list: FlashInfo::load_versions(),
             version_selected: flash_info.version_selected.to_string(),
             devices: flash_info
                 .devices
                 .iter()
                 .map(|device_model| DeviceInfo::from(device_model.clone()))
                 .collect(),
+            dry_run: flash_info.dry_run,
+            variables: flash_info.variables.to_string(),
         }
     }
 }
 
 impl FlashInfo {
     // Function to load versions from the directory
-    fn load_versions() -> Vec<String> {
-        let mut versions = vec![];
-
-        // Get the current directory and append "upgrade"
-        let upgrade_dir = std::env::current_dir().unwrap().join("upgrade");
-
-        if let Ok(entries) = fs::read_dir(upgrade_dir) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    if path.is_file() && path.extension().map_or(false, |ext| ext == "zip") {
-                        if let Some(file_stem) = path.file_stem().and_then(|stem| stem.to_str()) {
-                            versions.push(file_stem.to_string());
-                        }
-                    }
-                }
-            }
-        }
-        versions
+    fn load_versions(config: &Config) -> Vec<String> {
+        // Any archive counts, whatever its format
+        archive::list_packages(&config.paths.version_dir)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
     }
 
-    fn update_device_list(&mut self) {
-        let output = Command::new("tools/rk_flash_tools/upgrade_tool")
-            .arg("LD")
-            .output()
-            .expect("Failed to execute upgrade_tool");
+    fn update_device_list(&mut self, config: &Config) {
+        let output = match Command::new(&config.paths.upgrade_tool).arg("LD").output() {
+            Ok(output) => output,
+            Err(e) => {
+                log::error!("Failed to execute upgrade_tool: {}", e);
+                self.devices.clear();
+                return;
+            }
+        };
 
         let output_str = String::from_utf8_lossy(&output.stdout);
         // println!("Command output:\n{}", output_str); // 打印命令输出
         let devices: Vec<DeviceInfo> = output_str
             .lines()
             .filter(|line| line.starts_with("DevNo="))
-            .enumerate()
-            .filter_map(|(_, line)| parse_device_description(line))
+            .filter_map(parse_device_description)
             .collect();
 
         //打印解析后的设备列表
@@ -127,10 +137,19 @@ impl FlashInfo {
         self.devices = devices;
     }
 
-    fn supported_bd_to_model_rc(&self) -> ModelRc<slint::SharedString> {
-        let supported_boards: Vec<slint::SharedString> =
-            SUPPORTED_BOARDS.iter().map(|&board| board.into()).collect();
-        ModelRc::new(VecModel::from(supported_boards))
+    // Board ids and display names of the configured profiles, in the same order.
+    fn supported_bd_to_model_rc(
+        config: &Config,
+    ) -> (ModelRc<slint::SharedString>, ModelRc<slint::SharedString>) {
+        let (ids, names): (Vec<slint::SharedString>, Vec<slint::SharedString>) = config
+            .boards
+            .values()
+            .map(|board| (board.id.as_str().into(), board.name.as_str().into()))
+            .unzip();
+        (
+            ModelRc::new(VecModel::from(ids)),
+            ModelRc::new(VecModel::from(names)),
+        )
     }
 
     // Convert Vec<String> to ModelRc<SharedString>
@@ -189,10 +208,36 @@ fn parse_device_description(description: &str) -> Option<DeviceInfo> {
 pub async fn main() -> Result<(), slint::PlatformError> {
     env_logger::init();
 
-    cmdline_handle();
+    let args: Vec<String> = std::env::args().collect();
+    let cli = cli::parse_or_exit(&args);
 
+    // Only writing to devices needs root, images are built in user space.
     #[cfg(target_os = "linux")]
-    check_root();
+    if cli.command.needs_root(&cli.options) {
+        check_root();
+    } else if matches!(cli.command, CliCommand::Gui | CliCommand::Serve) {
+        check_device_access();
+    }
+
+    let config = match Config::load(&cli.config) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            exit(cli::EXIT_ERROR);
+        }
+    };
+
+    if cli.command.builds_images() {
+        scratch::clean_leftovers(&config);
+    }
+    scratch::handle_signals();
+
+    if !matches!(cli.command, CliCommand::Gui) {
+        exit(cli::run(cli, config).await);
+    }
+    let flash_type = cli.flash_type.clone();
+    let options = cli.options;
+
     // This provides better error messages in debug mode.
     // It's disabled in release mode so it doesn't bloat up the file size.
     #[cfg(all(debug_assertions, target_arch = "wasm32"))]
@@ -213,35 +258,149 @@ pub async fn main() -> Result<(), slint::PlatformError> {
             tokio::spawn(rk_flash_start(flash_info));
         }
     });*/
-    let _devices_timer = devices_scanf_timer(&window);
+    let _devices_timer = devices_scanf_timer(&window, config.clone());
+    cache::cache_page(&window, config.clone());
+
+    // Jobs started here and through the control API share one manager.
+    let jobs = JobManager::new(config.clone());
+    show_job_events(&window, jobs.subscribe());
+    webhook::Reporter::start_for(&jobs);
+    if let Some(addr) = cli.api.clone().or_else(|| config.api.listen.clone()) {
+        api::spawn(addr, jobs.clone());
+    }
 
     ControlsPageAdapter::get(&window).on_flash_start({
         let window = window.as_weak().upgrade().unwrap();
+        let jobs = jobs.clone();
+        let config = config.clone();
+        let flash_type = flash_type.clone();
+        let options = options.clone();
         move || {
-            _devices_timer.stop();
-            let flash_info: FlashInfo = window.global::<ControlsPageAdapter>().get_flash().into();
-            let setup_join = flash_setup(&window, flash_info);
+            let adapter = window.global::<ControlsPageAdapter>();
+            let flash_info: FlashInfo = adapter.get_flash().into();
+            let started = manifest::parse_variables(&flash_info.variables)
+                .map_err(|e| Finding::error("variables", e))
+                .and_then(|variables| {
+                    let options = FlashOptions {
+                        dry_run: flash_info.dry_run,
+                        variables,
+                        ..options.clone()
+                    };
+                    let flash_type = flash_type
+                        .clone()
+                        .unwrap_or_else(|| config.default_recipe(&flash_info.board_type));
+                    jobs.start(flash_info, &flash_type, options)
+                        .map_err(|e| Finding::error("job", e))
+                });
+            match started {
+                Ok(_) => {
+                    _devices_timer.stop();
+                    true
+                }
+                Err(finding) => {
+                    log::error!("Can't start flashing: {}", finding);
+                    let lines: Vec<SharedString> = vec![finding.to_string().into()];
+                    adapter.set_preflight_findings(ModelRc::new(VecModel::from(lines)));
+                    false
+                }
+            }
+        }
+    });
+
+    ControlsPageAdapter::get(&window).on_preflight({
+        let window_weak = window.as_weak();
+        let config = config.clone();
+        let flash_type = flash_type.clone();
+        let options = options.clone();
+        move |flash, start| {
+            let window = window_weak.unwrap();
+            let adapter = ControlsPageAdapter::get(&window);
+            adapter.set_preflight_failed(false);
+            adapter.set_preflight_running(true);
+            let board = flash.board_type.to_string();
+            let version = flash.version_selected.to_string();
+            let (variables, dry_run) = (flash.variables.to_string(), flash.dry_run);
+            let recipe = flash_type
+                .clone()
+                .unwrap_or_else(|| config.default_recipe(&board));
+            let config = config.clone();
+            let options = options.clone();
+            let window_weak = window_weak.clone();
+            // Hashing the images and reading the version package take a
+            // while, keep the GUI responsive.
+            tokio::task::spawn_blocking(move || {
+                let findings = match manifest::parse_variables(&variables) {
+                    Ok(variables) => {
+                        let options = FlashOptions {
+                            dry_run,
+                            variables,
+                            ..options
+                        };
+                        preflight::check(&config, &board, &version, &recipe, &options)
+                    }
+                    Err(e) => vec![Finding::error("variables", e)],
+                };
+                for finding in &findings {
+                    log::warn!("Preflight {}", finding);
+                }
+                let _ = window_weak.upgrade_in_event_loop(move |window| {
+                    let adapter = ControlsPageAdapter::get(&window);
+                    adapter.set_preflight_running(false);
+                    let lines: Vec<SharedString> =
+                        findings.iter().map(|f| f.to_string().into()).collect();
+                    adapter.set_preflight_findings(ModelRc::new(VecModel::from(lines)));
+                    let ok = !preflight::has_errors(&findings)
+                        && (!start || adapter.invoke_flash_start());
+                    adapter.set_preflight_failed(!ok);
+                });
+            });
         }
     });
 
-    let flash_info_rust: FlashInfo = Default::default();
+    ControlsPageAdapter::get(&window).on_flash_force_stop({
+        let jobs = jobs.clone();
+        move || jobs.stop_running()
+    });
 
+    let flash_info_rust = FlashInfo {
+        board_type: config.defaults.board.clone(),
+        version_list: FlashInfo::load_versions(&config),
+        version_selected: config.defaults.version.clone(),
+        ..Default::default()
+    };
+
+    let (board_ids, board_names) = FlashInfo::supported_bd_to_model_rc(&config);
+    let board_index = config
+        .boards
+        .keys()
+        .position(|id| *id == flash_info_rust.board_type)
+        .map_or(-1, |index| index as i32);
     window
         .global::<ControlsPageAdapter>()
         .set_flash(flash_info {
-            supported_board: flash_info_rust.supported_bd_to_model_rc(),
+            supported_board: board_ids,
+            board_names,
+            board_index,
             board_type: flash_info_rust.board_type.clone().into(),
             version_list: flash_info_rust.to_model_rc(),
             version_selected: flash_info_rust.version_selected.clone().into(),
             devices: flash_info_rust.devices_to_model_rc(),
+            dry_run: options.dry_run,
+            variables: options
+                .variables
+                .iter()
+                .map(|(name, value)| std::format!("{}={}\n", name, value))
+                .collect::<String>()
+                .into(),
         });
 
     window.global::<ControlsPageAdapter>().on_flash_apply({
         let app_weak = window.as_weak();
         let mut flash_info_rust: FlashInfo = Default::default();
+        let config = config.clone();
         move |mut flash| {
-            flash_info_rust.update_device_list();
-            flash_info_rust.version_list = FlashInfo::load_versions();
+            flash_info_rust.update_device_list(&config);
+            flash_info_rust.version_list = FlashInfo::load_versions(&config);
             flash.devices = flash_info_rust.devices_to_model_rc();
             flash.version_list = flash_info_rust.to_model_rc();
             print_flash_info(&flash);
@@ -254,7 +413,7 @@ pub async fn main() -> Result<(), slint::PlatformError> {
     window.run()
 }
 
-pub fn devices_scanf_timer(window: &MainWindow) -> Timer {
+pub fn devices_scanf_timer(window: &MainWindow, config: Arc<Config>) -> Timer {
     let devices_timer = Timer::default();
     devices_timer.start(
         TimerMode::Repeated,
@@ -264,8 +423,8 @@ pub fn devices_scanf_timer(window: &MainWindow) -> Timer {
             move || {
                 let mut flash = ControlsPageAdapter::get(&window_weak.unwrap()).get_flash();
                 let mut flash_info: FlashInfo = Default::default();
-                flash_info.update_device_list();
-                flash_info.version_list = FlashInfo::load_versions();
+                flash_info.update_device_list(&config);
+                flash_info.version_list = FlashInfo::load_versions(&config);
                 flash.devices = flash_info.devices_to_model_rc();
                 flash.version_list = flash_info.to_model_rc();
 
@@ -294,7 +453,18 @@ fn check_root() {
     }
 }
 
-fn print_version() {
+// The GUI and the API server start without root, but upgrade_tool usually
+// can't open the devices then.
+#[cfg(target_os = "linux")]
+fn check_device_access() {
+    use log::warn;
+
+    if unsafe { libc::getuid() } != 0 {
+        warn!("not running as root, flashing fails unless udev rules grant USB access.");
+    }
+}
+
+pub(crate) fn print_version() {
     mod build_info {
         include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
     }
@@ -303,12 +473,3 @@ fn print_version() {
     log::info!("Software Version: {}", VERSION);
     log::info!("Build Date: {}", build_info::BUILD_DATE);
 }
-
-fn cmdline_handle() {
-    let args: Vec<String> = std::env::args().collect();
-
-    if args.contains(&"-v".to_string()) || args.contains(&"--version".to_string()) {
-        print_version();
-        exit(0);
-    }
-}
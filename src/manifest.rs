@@ -0,0 +1,463 @@
+use crate::board::BoardProfile;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// A manifest says what goes into the rootfs of a board, on top of the base
+// image. It comes from the board profile (`manifest = "layouts/scu.toml"`),
+// from `manifest.toml` at the top of the version package, or is the built-in
+// one below, in that order.
+//
+//   [[copy]]                      # a file or the contents of a directory
+//   package = "update-rootfs"     # or "version", the default
+//   from = "{subtree}/etc"
+//   to = "etc"                    # a trailing `/` copies into the directory
+//   optional = true               # skipped if `from` is missing
+//   owner = "root:root"           # instead of the owner of the source
+//   file_mode = 0o644             # of every file copied
+//   dir_mode = 0o755              # of every directory copied
+//
+//   [[file]]                      # written by rk_flash
+//   path = "etc/hostname"
+//   contents = "{hostname}"
+//   mode = 0o644
+//
+//   [[template]]                  # `{name}` filled in from the variables
+//   path = "etc/ntp.conf"         # a file, or every file below a directory
+//   optional = true
+//
+//   [[attr]]                      # applied after everything else
+//   path = "root/.ssh"
+//   mode = 0o700                  # of `path` itself
+//   file_mode = 0o600             # of the files below it
+//   owner = "root"                # of `path` and everything below it
+//
+// Copies go in in order, later ones win. Board overlays go on after them,
+// then the templates are filled in, then the files written and the
+// attributes applied. `{board}`, `{version}`, `{hostname}`, `{subtree}` and
+// `{bin}` are replaced in paths, contents and templates. Owners are
+// `user[:group]`, by name in the rootfs or by number.
+//
+// Templates take further variables from `variables.toml` at the top of the
+// version package, then the `variables` of the board profile, then the ones
+// given for the batch (`--var`, the API or the Controls page), later ones
+// win:
+//
+//   ntp_server = "ntp.example.com"
+//   server_url = "https://mes.example.com/api"
+
+/// Name of the manifest at the top of a version package.
+pub const VERSION_MANIFEST: &str = "manifest.toml";
+
+/// Variables of the version, at the top of the version package.
+pub const VERSION_VARIABLES: &str = "variables.toml";
+
+// Set by rk_flash, no other source may change them.
+const BUILTIN_VARIABLES: [&str; 5] = ["board", "version", "hostname", "subtree", "bin"];
+
+/// Used when neither the board nor the version has a manifest.
+const BUILTIN_MANIFEST: &str = r#"
+[[copy]]
+package = "update-rootfs"
+from = "etc"
+to = "etc"
+optional = true
+
+[[copy]]
+package = "update-rootfs"
+from = "root"
+to = "root"
+optional = true
+
+[[copy]]
+from = "{subtree}/etc"
+to = "etc"
+optional = true
+
+[[copy]]
+from = "{subtree}/mnt"
+to = "mnt"
+optional = true
+
+[[copy]]
+from = "{subtree}/root"
+to = "root"
+optional = true
+
+[[copy]]
+from = "{subtree}/usr"
+to = "usr"
+optional = true
+
+[[copy]]
+from = "{bin}"
+to = "mnt/build/"
+
+[[file]]
+path = "etc/hostname"
+contents = "{hostname}"
+
+[[file]]
+path = "mnt/config/boardtype"
+contents = "{board}"
+
+[[attr]]
+path = "etc/rc.local"
+mode = 0o755
+
+# sshd refuses keys in a directory others can get into.
+[[attr]]
+path = "root/.ssh"
+optional = true
+mode = 0o700
+dir_mode = 0o700
+file_mode = 0o600
+"#;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Package {
+    /// The unpacked version package.
+    #[default]
+    Version,
+    /// The unpacked update-rootfs archive of the image directory.
+    UpdateRootfs,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Copy {
+    #[serde(default)]
+    pub package: Package,
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub optional: bool,
+    pub owner: Option<String>,
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GeneratedFile {
+    pub path: String,
+    pub contents: String,
+    /// The mode and owner of the file it replaces, or root's 0644.
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Attr {
+    pub path: String,
+    #[serde(default)]
+    pub optional: bool,
+    pub mode: Option<u32>,
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Template {
+    pub path: String,
+    #[serde(default)]
+    pub optional: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub copy: Vec<Copy>,
+    #[serde(default)]
+    pub file: Vec<GeneratedFile>,
+    #[serde(default)]
+    pub template: Vec<Template>,
+    #[serde(default)]
+    pub attr: Vec<Attr>,
+}
+
+impl Manifest {
+    pub fn builtin() -> Self {
+        toml::from_str(BUILTIN_MANIFEST).expect("built-in manifest parses")
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The manifest `board` is built with from the version unpacked in
+    /// `version_dir`, and where it came from.
+    pub fn find(board: &BoardProfile, version_dir: &Path) -> Result<(Self, String), String> {
+        if let Some(path) = &board.manifest {
+            return Ok((Self::read(path)?, path.display().to_string()));
+        }
+        let path = version_dir.join(VERSION_MANIFEST);
+        if path.is_file() {
+            return Ok((
+                Self::read(&path)?,
+                format!("the version's {}", VERSION_MANIFEST),
+            ));
+        }
+        Ok((Self::builtin(), "the built-in manifest".to_string()))
+    }
+}
+
+/// The values of `{name}` in manifests: the built-in ones, then those of the
+/// version unpacked in `version_dir`, of the board profile and of the batch.
+pub fn variables(
+    board: &BoardProfile,
+    version: &str,
+    version_dir: &Path,
+    batch: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, String> {
+    let mut variables = BTreeMap::from([
+        ("board".to_string(), board.id.clone()),
+        ("version".to_string(), version.to_string()),
+        ("hostname".to_string(), board.hostname.clone()),
+        ("subtree".to_string(), board.subtree.display().to_string()),
+        ("bin".to_string(), board.bin.display().to_string()),
+    ]);
+    let path = version_dir.join(VERSION_VARIABLES);
+    let from_version = if path.is_file() {
+        let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?
+    } else {
+        BTreeMap::new()
+    };
+    for (origin, layer) in [
+        (
+            format!("the version's {}", VERSION_VARIABLES),
+            &from_version,
+        ),
+        (format!("board `{}`", board.id), &board.variables),
+        ("the batch".to_string(), batch),
+    ] {
+        check_variables(layer).map_err(|e| format!("{}: {}", origin, e))?;
+        variables.extend(layer.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    Ok(variables)
+}
+
+/// Variables that aren't built in need a name `{name}` can refer to.
+pub fn check_variables(variables: &BTreeMap<String, String>) -> Result<(), String> {
+    for name in variables.keys() {
+        if BUILTIN_VARIABLES.contains(&name.as_str()) {
+            return Err(format!("`{}` is a built-in variable", name));
+        }
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            return Err(format!(
+                "bad variable name `{}`, use lowercase letters, digits and `_`",
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `name=value` lines, as in the batch form of the Controls page. Empty lines
+/// and lines starting with `#` are skipped.
+pub fn parse_variables(text: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut variables = BTreeMap::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("`{}` isn't name=value", line))?;
+        variables.insert(name.trim().to_string(), value.trim().to_string());
+    }
+    check_variables(&variables)?;
+    Ok(variables)
+}
+
+/// Replace every `{name}` in `text`. Braces around anything but a lowercase
+/// name are left alone, so JSON and shell contents come through.
+pub fn expand(text: &str, variables: &BTreeMap<String, String>) -> Result<String, String> {
+    substitute(text, variables)
+        .map_err(|name| format!("unknown variable {{{}}} in `{}`", name, text))
+}
+
+/// `expand` for the contents of a template file, the error doesn't quote it.
+pub fn fill(text: &str, variables: &BTreeMap<String, String>) -> Result<String, String> {
+    substitute(text, variables).map_err(|name| format!("unknown variable {{{}}}", name))
+}
+
+// The error is the name without a value.
+fn substitute<'a>(text: &'a str, variables: &BTreeMap<String, String>) -> Result<String, &'a str> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'))
+            .unwrap_or(after.len());
+        let name = &after[..name_len];
+        if name.is_empty() || !after[name_len..].starts_with('}') {
+            out.push('{');
+            rest = after;
+            continue;
+        }
+        out.push_str(variables.get(name).ok_or(name)?);
+        rest = &after[name_len + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// `path` of a manifest as a relative path, a leading `/` is fine, `..` is
+/// not.
+pub fn relative_path(path: &str) -> Result<PathBuf, String> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(format!("`{}` points outside the tree", path))
+            }
+        }
+    }
+    Ok(relative)
+}
+
+// `name` in a passwd or group file of the rootfs. Returns the id and, for
+// passwd, the primary group.
+fn lookup(staging: &Path, file: &str, name: &str) -> Option<(u32, Option<u32>)> {
+    let path = staging.join("etc").join(file);
+    // Only a file of the rootfs itself, not one a symlink points to on
+    // this machine.
+    if !fs::symlink_metadata(&path).is_ok_and(|m| m.is_file()) {
+        return None;
+    }
+    let text = fs::read_to_string(path).ok()?;
+    text.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.first() != Some(&name) {
+            return None;
+        }
+        let id = fields.get(2)?.parse().ok()?;
+        Some((id, fields.get(3).and_then(|gid| gid.parse().ok())))
+    })
+}
+
+/// Uid and gid of `owner`, `user[:group]` by name or number. Names are
+/// looked up in the rootfs staged in `staging`. Without a group it is the
+/// user's primary one, or the same number.
+pub fn resolve_owner(staging: &Path, owner: &str) -> Result<(u32, u32), String> {
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+    let (uid, primary) = match user.parse::<u32>() {
+        Ok(uid) => (uid, None),
+        Err(_) => lookup(staging, "passwd", user)
+            .ok_or_else(|| format!("no user `{}` in the rootfs", user))?,
+    };
+    let gid = match group {
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                lookup(staging, "group", group)
+                    .ok_or_else(|| format!("no group `{}` in the rootfs", group))?
+                    .0
+            }
+        },
+        None => primary.unwrap_or(uid),
+    };
+    Ok((uid, gid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn variables_are_parsed() {
+        let text = "# line\n\n  site = Plant 2 \nline_no=a=b\nempty=\n";
+        let parsed = parse_variables(text).unwrap();
+        let expected = variables(&[("site", "Plant 2"), ("line_no", "a=b"), ("empty", "")]);
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn malformed_variables_are_refused() {
+        for text in [
+            "site",
+            "site=1\nline",
+            "=value",
+            "Site=1",
+            "site name=1",
+            "site-name=1",
+            "board=other",
+            "hostname=other",
+        ] {
+            assert!(parse_variables(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn placeholders_are_substituted_once() {
+        let values = variables(&[("board", "rk3568"), ("site", "{board}")]);
+        assert_eq!(fill("{board}-{site}", &values).unwrap(), "rk3568-{board}");
+        assert_eq!(fill("{{board}}", &values).unwrap(), "{rk3568}");
+        assert_eq!(expand("to/{board}/", &values).unwrap(), "to/rk3568/");
+    }
+
+    #[test]
+    fn other_braces_are_left_alone() {
+        let values = variables(&[("board", "rk3568")]);
+        for text in [
+            r#"{"a": 1}"#,
+            "${HOME}",
+            "{Board}",
+            "{}",
+            "{board",
+            "board}",
+            "{board-x}",
+            "tail {",
+        ] {
+            assert_eq!(fill(text, &values).unwrap(), text);
+        }
+        assert_eq!(fill("{board {board}", &values).unwrap(), "{board rk3568");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_errors() {
+        let values = variables(&[("board", "rk3568")]);
+        assert_eq!(
+            fill("x={site}", &values).unwrap_err(),
+            "unknown variable {site}"
+        );
+        assert_eq!(
+            expand("{board}/{site}", &values).unwrap_err(),
+            "unknown variable {site} in `{board}/{site}`"
+        );
+    }
+
+    #[test]
+    fn relative_paths() {
+        let path = |text: &str| relative_path(text).map(|path| path.display().to_string());
+        assert_eq!(path("/etc/hostname").unwrap(), "etc/hostname");
+        assert_eq!(path("etc/./hostname").unwrap(), "etc/hostname");
+        assert_eq!(path("//mnt//config/").unwrap(), "mnt/config");
+        assert_eq!(path("/").unwrap(), "");
+        for text in ["..", "../etc", "/etc/../../x", "etc/.."] {
+            assert!(path(text).is_err(), "{:?}", text);
+        }
+    }
+}
@@ -1,171 +1,1206 @@
-use flate2::read::GzDecoder;
-use log::{debug, error, info, warn};
-use slint::{Model, StandardListViewItem, VecModel};
-use std::env;
+use crate::archive;
+use crate::board::BoardProfile;
+use crate::cache::{self, CacheKey};
+use crate::changes::{self, KnownHashes};
+use crate::config::Config;
+use crate::ext4::{self, Ext4Params, Inode, Metadata};
+use crate::manifest::{self, Manifest, Package, VERSION_MANIFEST, VERSION_VARIABLES};
+use crate::parameter;
+use crate::reproducible;
+use crate::rootfs::{self, FsType, Settings};
+use crate::scratch::Scratch;
+use crate::sparse;
+use libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG};
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::fs::File;
 use std::fs::Permissions;
-use std::io;
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
-use std::process::{exit, Command};
-use std::{ops::ControlFlow, rc::Rc};
-use tar::Archive;
+use std::io::{self, Read};
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
+
+/// The parts of building an image, reported in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrepareStep {
+    /// The base rootfs.img into the staging directory, and a listing of it.
+    Copy,
+    /// What the manifest copies out of update-rootfs, the version package
+    /// and its filesystem archive, streamed into staging.
+    Extract,
+    /// The overlays of the board, and the templates, files and attributes
+    /// of the manifest.
+    Overlay,
+    /// The staging directory into a new image.
+    Finalize,
+    /// The filesystem check of the new image.
+    Check,
+    /// What the merge changed in the base rootfs.
+    Report,
+    /// The new image as a sparse image, for boards that want one.
+    Sparse,
+}
+
+impl PrepareStep {
+    pub const ALL: [PrepareStep; 7] = [
+        PrepareStep::Copy,
+        PrepareStep::Extract,
+        PrepareStep::Overlay,
+        PrepareStep::Finalize,
+        PrepareStep::Check,
+        PrepareStep::Report,
+        PrepareStep::Sparse,
+    ];
+
+    /// The steps of building the image of `board`.
+    pub fn steps(board: &BoardProfile) -> Vec<PrepareStep> {
+        PrepareStep::ALL
+            .into_iter()
+            .filter(|step| *step != PrepareStep::Sparse || board.sparse)
+            .collect()
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            PrepareStep::Copy => "copy base rootfs",
+            PrepareStep::Extract => "extract packages",
+            PrepareStep::Overlay => "copy overlays",
+            PrepareStep::Finalize => "write image",
+            PrepareStep::Check => "check filesystem",
+            PrepareStep::Report => "list changes",
+            PrepareStep::Sparse => "write sparse image",
+        }
+    }
+}
+
+/// Called with the step, the bytes done and the total of that step. An error
+/// stops the build, e.g. when the job is cancelled.
+pub type PrepareProgress<'a> =
+    &'a mut (dyn FnMut(PrepareStep, u64, u64) -> Result<(), String> + Send);
+
+/// Build the rootfs image of `board` from `version` with the batch
+/// `variables`, or reuse the cached one if it was built from the same inputs.
 pub fn prepare_filesystem(
+    config: &Config,
     version: &str,
-    board_type: &str,
+    board: &BoardProfile,
+    variables: &BTreeMap<String, String>,
+    progress: PrepareProgress,
 ) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let update_rootfs_img = PathBuf::from(format!("tmp/rootfs-{}.img", version));
+    let key = CacheKey::compute(config, board, version, variables)?;
 
     // 如果目标文件已经存在，直接返回
-    if update_rootfs_img.exists() {
+    if let Some(image) = cache::lookup(config, &key) {
+        cache::mark_used(&image);
         info!(
             "File {} already exists, skipping creation.",
-            update_rootfs_img.display()
+            image.display()
         );
-        return Ok(update_rootfs_img);
+        return Ok(image);
     }
 
-    let tmp_dir = PathBuf::from("tmp");
-    if !tmp_dir.exists() {
-        fs::create_dir(&tmp_dir)?;
+    // Removed with everything in it on every way out of here.
+    let scratch = Scratch::create(config, &format!("{}-{}", board.id, version))?;
+    let tmp_dir = scratch.path();
+
+    // The base rootfs is unpacked here, merged, and written to a new image.
+    let staging_dir = tmp_dir.join("rootfs");
+    let rootfs_img = config.paths.image_dir.join("rootfs.img");
+    let temp_rootfs_img = tmp_dir.join("rootfs.img");
+
+    let base_type = FsType::detect(&rootfs_img)?;
+    let fs_type = board.filesystem.unwrap_or(base_type);
+    if board.sparse && fs_type != FsType::Ext4 {
+        return Err(format!(
+            "sparse images are for ext4 root filesystems, not {}",
+            fs_type
+        )
+        .into());
     }
+    info!("Preparing rootfs: {}", PrepareStep::Copy.label());
+    // Copying is the first half of the step, hashing the copies the second.
+    let mut copied = 0;
+    let mut metadata =
+        rootfs::extract(base_type, &rootfs_img, &staging_dir, &mut |done, total| {
+            copied = total;
+            progress(PrepareStep::Copy, done, 2 * total)
+        })?;
+    let mut known = KnownHashes::new();
+    let base_files = changes::listing(
+        &staging_dir,
+        &ext4::wanted_inodes(&staging_dir, &metadata, &HashMap::new())?,
+        &mut known,
+        &mut |done, total| {
+            let hashed = done as f64 / total.max(1) as f64 * copied as f64;
+            progress(PrepareStep::Copy, copied + hashed as u64, 2 * copied)
+        },
+    )?;
+    let base_newest = reproducible::newest_file(&staging_dir)?;
+    info!("Preparing rootfs: {}", PrepareStep::Extract.label());
+    let mut overlay = Overlay {
+        staging_dir: &staging_dir,
+        metadata: &mut metadata,
+        links: HashMap::new(),
+    };
+    let layout = stream_packages(
+        config,
+        version,
+        board,
+        variables,
+        tmp_dir,
+        &mut overlay,
+        &mut |done, total| progress(PrepareStep::Extract, done, total),
+    )?;
+    // Only the base image and the packages count, not what the merge adds.
+    let epoch = match reproducible::configured_epoch(config)? {
+        Some(epoch) => epoch,
+        None => base_newest.max(layout.newest),
+    };
+    info!("SOURCE_DATE_EPOCH: {}", epoch);
+    info!("Preparing rootfs: {}", PrepareStep::Overlay.label());
+    merge_into_staging(board, &layout, &mut overlay, &mut |done, total| {
+        progress(PrepareStep::Overlay, done, total)
+    })?;
+    let values = layout.variables;
+    info!("Preparing rootfs: {}", PrepareStep::Finalize.label());
+    reproducible::clamp_times(&staging_dir, epoch)?;
+    let settings = Settings {
+        max_size: rootfs_partition_size(config, &rootfs_img),
+        epoch,
+        uuid: board
+            .fs_uuid
+            .clone()
+            .unwrap_or_else(|| reproducible::fs_uuid(&board.id, version)),
+        hash_seed: reproducible::hash_seed(&board.id, version),
+    };
+    let written = rootfs::build(
+        fs_type,
+        &rootfs_img,
+        &staging_dir,
+        &metadata,
+        &settings,
+        &temp_rootfs_img,
+        &mut |done, total| progress(PrepareStep::Finalize, done, total),
+    )?;
+    info!("Preparing rootfs: {}", PrepareStep::Check.label());
+    progress(PrepareStep::Check, 0, 1)?;
+    let fsck = rootfs::check(fs_type, &temp_rootfs_img)?;
+    info!("{} check: {}", fs_type, fsck);
+    progress(PrepareStep::Check, 1, 1)?;
+
+    info!("Preparing rootfs: {}", PrepareStep::Report.label());
+    let files = changes::listing(&staging_dir, &written, &mut known, &mut |done, total| {
+        progress(PrepareStep::Report, done, total)
+    })?;
+    let report = changes::diff("rootfs.img", &base_files, &key.image_id(), &files);
+    info!("Rootfs changes: {}", report.summary());
 
-    let temp_mount_dir = tmp_dir.join(format!("rootfs-{}", version));
-    // Create temporary mount directory
-    if !temp_mount_dir.exists() {
-        fs::create_dir(&temp_mount_dir)?;
+    let built = if board.sparse {
+        info!("Preparing rootfs: {}", PrepareStep::Sparse.label());
+        let sparse_img = tmp_dir.join("rootfs.simg");
+        let size = sparse::write(
+            &temp_rootfs_img,
+            &sparse_img,
+            Ext4Params::read(&temp_rootfs_img)?.block_size,
+            &ext4::unused_blocks(&temp_rootfs_img)?,
+            &mut |done, total| progress(PrepareStep::Sparse, done, total),
+        )?;
+        info!(
+            "Sparse rootfs: {} MiB of {} MiB",
+            size >> 20,
+            fs::metadata(&temp_rootfs_img)?.len() >> 20
+        );
+        sparse_img
+    } else {
+        temp_rootfs_img
+    };
+
+    Ok(cache::store(
+        config, &key, &built, &fsck, &values, &files, &report,
+    )?)
+}
+
+// How big the rootfs image may grow, None for a partition that takes the
+// rest of the storage. Without a readable layout it stays the size of the
+// base image.
+fn rootfs_partition_size(config: &Config, base: &Path) -> Option<u64> {
+    let path = config.paths.image_dir.join("parameter.txt");
+    match parameter::partition(&path, "rootfs") {
+        Ok(partition) => partition.size,
+        Err(e) => {
+            warn!("{}, the rootfs image can't grow", e);
+            fs::metadata(base).map(|m| m.len()).ok()
+        }
     }
+}
 
-    // 拷贝 rootfs.img 到临时文件夹
-    let rootfs_img = env::current_dir()?.join("rockdev/rootfs.img");
-    let temp_rootfs_img = tmp_dir.join("rootfs.img");
+// The manifest a rootfs is merged by, and what it was filled in with.
+struct Layout {
+    manifest: Manifest,
+    variables: BTreeMap<String, String>,
+    // Modification time of the newest file in the packages, in seconds.
+    newest: i64,
+}
 
-    // Copy rootfs.img to temporary location
-    fs::copy(&rootfs_img, &temp_rootfs_img)?;
+// Stream update-rootfs, the version package and its board/filesystem archive
+// into the staged rootfs: members at or below the `from` of a manifest copy
+// are written straight to where the copy puts them, the others are skipped.
+// Only the manifest and variables of the version go to `tmp_dir`, in a first
+// pass over the package; a zip one skips the other members without reading
+// them. Progress counts the package once for each pass.
+fn stream_packages(
+    config: &Config,
+    version: &str,
+    board: &BoardProfile,
+    batch: &BTreeMap<String, String>,
+    tmp_dir: &Path,
+    overlay: &mut Overlay,
+    progress: archive::Progress,
+) -> Result<Layout, Box<dyn std::error::Error>> {
+    let update_rootfs_path = archive::find_package(&config.paths.image_dir, "update-rootfs")
+        .ok_or_else(|| {
+            format!(
+                "no update-rootfs archive in {}",
+                config.paths.image_dir.display()
+            )
+        })?;
+    let version_package = archive::find_package(&config.paths.version_dir, version)
+        .ok_or_else(|| format!("version `{}` not found", version))?;
+    let update_size = fs::metadata(&update_rootfs_path)?.len();
+    let package_size = fs::metadata(&version_package)?.len();
+    let total = update_size + 2 * package_size;
 
-    // 挂载 rootfs.img 到临时目录
-    let mount_output = Command::new("mount")
-        .args(&[
-            "-o",
-            "loop",
-            temp_rootfs_img.to_str().unwrap(),
-            temp_mount_dir.to_str().unwrap(),
-        ])
-        .output()?;
+    let version_dir = tmp_dir.join(version);
+    let top = [VERSION_MANIFEST, VERSION_VARIABLES].map(|name| Path::new(version).join(name));
+    archive::extract(
+        &version_package,
+        tmp_dir,
+        &|path| top.iter().any(|top| top == path),
+        &mut |read, _| progress(read, total),
+    )?;
+    let (manifest, origin) = Manifest::find(board, &version_dir)?;
+    info!("Rootfs layout from {}", origin);
+    let variables = manifest::variables(board, version, &version_dir, batch)?;
 
-    if !mount_output.status.success() {
-        return Err(Box::new(io::Error::new(
-            io::ErrorKind::Other,
-            "Failed to mount image",
-        )));
+    // What goes where, in order: later copies win.
+    let mut plans = vec![];
+    for copy in &manifest.copy {
+        let (root, package) = match copy.package {
+            Package::Version => (version, format!("version {}", version)),
+            Package::UpdateRootfs => ("update-rootfs", "update-rootfs".to_string()),
+        };
+        let from = manifest::expand(&copy.from, &variables)?;
+        let to = manifest::expand(&copy.to, &variables)?;
+        let source = Path::new(root).join(manifest::relative_path(&from)?);
+        let mut dst = overlay.staging_dir.join(manifest::relative_path(&to)?);
+        if to.ends_with('/') {
+            dst.push(source.file_name().unwrap_or_default());
+        }
+        plans.push(Plan {
+            copy,
+            from,
+            package,
+            source,
+            dst,
+            found: false,
+            written: vec![],
+        });
     }
 
-    let update_rootfs_path = env::current_dir()?.join("rockdev/update-rootfs.tar.gz");
-    let update_rootfs_file = File::open(&update_rootfs_path)?;
-    let mut update_rootfs_archive = Archive::new(GzDecoder::new(update_rootfs_file));
-    update_rootfs_archive.unpack(&env::current_dir()?.join("rockdev"))?;
+    let mut packages = Packages {
+        overlay,
+        plans,
+        claims: HashMap::new(),
+        filled: HashMap::new(),
+        members: HashMap::new(),
+        dirs: BTreeMap::new(),
+        subtree: Path::new(version).join(&board.subtree),
+        subtree_found: false,
+        newest: 0,
+    };
+    archive::walk(
+        &update_rootfs_path,
+        &mut |entry| packages.member(entry, Path::new("")),
+        &mut |read, _| progress(package_size + read, total),
+    )?;
+    // The filesystem archive is streamed as it is read out of the package,
+    // it isn't copied itself.
+    let board_dir = Path::new(version).join("board");
+    let mut filesystem = false;
+    archive::walk(
+        &version_package,
+        &mut |entry| {
+            let Some(path) = archive::entry_path(&entry.path)? else {
+                return Ok(());
+            };
+            let is_filesystem = path.parent() == Some(board_dir.as_path())
+                && archive::package_name(&path).as_deref() == Some("filesystem")
+                && entry.kind == archive::Kind::File;
+            if !is_filesystem || filesystem {
+                return packages.member(entry, Path::new(""));
+            }
+            filesystem = true;
+            let mtime = entry.mtime.map_or(0, |mtime| mtime as i64);
+            packages.newest = packages.newest.max(mtime);
+            archive::walk_reader(entry.data, &mut |inner| packages.member(inner, &board_dir))
+                .map_err(|e| format!("{}: {}", path.display(), e))
+        },
+        &mut |read, _| progress(package_size + update_size + read, total),
+    )?;
+    // Unpacking into a directory changes its time, so they come last.
+    for (dir, mtime) in &packages.dirs {
+        archive::set_mtime(dir, *mtime, 0)?;
+    }
 
-    let update_rootfs_dir = env::current_dir()?.join("rockdev/update-rootfs");
-    for dir in &["etc", "root"] {
-        let src_dir = update_rootfs_dir.join(dir);
-        let dst_dir = temp_mount_dir.join(dir);
-        if src_dir.exists() {
-            copy_dir_all(&src_dir, &dst_dir)?;
+    if !filesystem {
+        return Err(format!("no board/filesystem archive in version {}", version).into());
+    }
+    if !packages.subtree_found {
+        return Err(format!(
+            "{} not found in version {}",
+            board.subtree.display(),
+            version
+        )
+        .into());
+    }
+    let Packages {
+        overlay,
+        plans,
+        claims,
+        newest,
+        ..
+    } = packages;
+    for plan in &plans {
+        if !plan.found && !plan.copy.optional {
+            return Err(format!("{} not found in {}", plan.from, plan.package).into());
         }
     }
 
-    let version_zip = env::current_dir()?.join(format!("upgrade/{}.zip", version));
-    Command::new("unzip")
-        .arg(&version_zip)
-        .arg("-d")
-        .arg(&tmp_dir)
-        .status()?;
+    // Owners are looked up in the merged rootfs, its passwd may have come
+    // with the packages.
+    for (index, plan) in plans.iter().enumerate() {
+        let copy = plan.copy;
+        if copy.owner.is_none() && copy.file_mode.is_none() && copy.dir_mode.is_none() {
+            continue;
+        }
+        let copy_owner = match &copy.owner {
+            Some(owner) => Some(manifest::resolve_owner(overlay.staging_dir, owner)?),
+            None => None,
+        };
+        // Everything copied and still there, not the directory copied into.
+        for dst in &plan.written {
+            if claims.get(dst) != Some(&index) {
+                continue;
+            }
+            let Ok(stat) = fs::symlink_metadata(dst) else {
+                continue;
+            };
+            let mode = if stat.is_dir() {
+                copy.dir_mode
+            } else {
+                copy.file_mode
+            };
+            overlay.set_inode(dst, mode, copy_owner)?;
+        }
+    }
+    progress(total, total)?;
+    Ok(Layout {
+        manifest,
+        variables,
+        newest,
+    })
+}
 
-    // Extract filesystem.tar.gz from the unzipped version directory
-    let version_dir = tmp_dir.join(version);
-    let tar_gz_path = version_dir.join("board/filesystem.tar.gz");
+// A copy of the manifest: the package members at or below `source` go to
+// `dst` and below it.
+struct Plan<'a> {
+    copy: &'a manifest::Copy,
+    // As the manifest has it, and the package, for errors.
+    from: String,
+    package: String,
+    // Where unpacking would put it below `tmp_dir`, e.g. `v2.0/board/bin`.
+    source: PathBuf,
+    dst: PathBuf,
+    found: bool,
+    // Staged paths written, except the directory copied into.
+    written: Vec<PathBuf>,
+}
 
-    let file = File::open(&tar_gz_path)?;
-    let mut archive = Archive::new(GzDecoder::new(file));
+// An archive member on its way into the staged rootfs.
+struct Member<'a> {
+    // Where unpacking would put it below `tmp_dir`.
+    path: PathBuf,
+    kind: archive::Kind,
+    inode: Inode,
+    mtime: Option<i64>,
+    // The member a hard link links to, by path below `tmp_dir`.
+    link: Option<PathBuf>,
+    data: &'a mut dyn Read,
+    // Where the contents went first, further copies are made from there.
+    written: Option<PathBuf>,
+}
 
-    // Extract the tar.gz archive into the mounted root file system directory
-    archive.unpack(&version_dir.join("board"))?;
+// The packages as they are streamed into the staged rootfs.
+struct Packages<'a, 'b> {
+    overlay: &'a mut Overlay<'b>,
+    plans: Vec<Plan<'a>>,
+    // Which copy wrote each staged path, a later one may replace it.
+    claims: HashMap<PathBuf, usize>,
+    // The last copy that wrote into each directory, an earlier one can't
+    // replace it with a file.
+    filled: HashMap<PathBuf, usize>,
+    // Staged path of every member by copy, for the hard links to it.
+    members: HashMap<(usize, PathBuf), PathBuf>,
+    // Times of the directories written, set when everything is in.
+    dirs: BTreeMap<PathBuf, i64>,
+    // The board subtree below `tmp_dir`, and whether a member is in it.
+    subtree: PathBuf,
+    subtree_found: bool,
+    newest: i64,
+}
 
-    // 拷贝文件到 rootfs.img
-    let version_dir = tmp_dir.join(format!("{}", version));
-    let filesystem_dir = if board_type == "dc11scu" {
-        version_dir.join("scu/filesystem")
-    } else {
-        version_dir.join("board/filesystem")
+impl Packages<'_, '_> {
+    // Write `entry` of the archive unpacked to `prefix` wherever the copies
+    // want it.
+    fn member(&mut self, entry: archive::Entry, prefix: &Path) -> Result<(), String> {
+        let Some(path) = archive::member_path(&entry)? else {
+            return Ok(());
+        };
+        let path = prefix.join(path);
+        if entry.kind == archive::Kind::File {
+            let mtime = entry.mtime.map_or(0, |mtime| mtime as i64);
+            self.newest = self.newest.max(mtime);
+        }
+        self.subtree_found |= path.starts_with(&self.subtree);
+        let targets: Vec<(usize, PathBuf)> = self
+            .plans
+            .iter()
+            .enumerate()
+            .filter_map(|(index, plan)| {
+                let rest = path.strip_prefix(&plan.source).ok()?;
+                let dst = if rest.as_os_str().is_empty() {
+                    plan.dst.clone()
+                } else {
+                    plan.dst.join(rest)
+                };
+                Some((index, dst))
+            })
+            .collect();
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let link =
+            match &entry.kind {
+                archive::Kind::HardLink(link) => {
+                    Some(prefix.join(archive::entry_path(link)?.ok_or_else(|| {
+                        format!("{}: hard link to the archive root", path.display())
+                    })?))
+                }
+                _ => None,
+            };
+        let mut member = Member {
+            inode: entry.inode(),
+            kind: entry.kind,
+            mtime: entry.mtime.map(|mtime| mtime as i64),
+            link,
+            data: entry.data,
+            written: None,
+            path,
+        };
+        for (index, dst) in targets {
+            self.plans[index].found = true;
+            self.write(index, &mut member, &dst)
+                .map_err(|e| format!("{}: {}", member.path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    // Whether copy `index` may write `dst`: not where a later copy wrote it,
+    // or put something other than a directory above it. Nor a file where a
+    // later copy needs a directory.
+    fn may_write(&self, index: usize, dst: &Path, is_dir: bool) -> bool {
+        if !is_dir && self.filled.get(dst).is_some_and(|&filled| filled > index) {
+            return false;
+        }
+        dst.ancestors()
+            .take_while(|path| *path != self.overlay.staging_dir)
+            .enumerate()
+            .all(|(depth, path)| match self.claims.get(path) {
+                Some(&claim) if claim > index => {
+                    depth > 0 && fs::symlink_metadata(path).is_ok_and(|m| m.is_dir())
+                }
+                _ => true,
+            })
+    }
+
+    // Whether the symlink at `path` may be replaced for copy `index`: only
+    // if a copy up to that one put it there. Others are never written
+    // through, their targets are paths of the rootfs, not of this machine.
+    fn check_symlink(&self, index: usize, path: &Path, dst: &Path) -> io::Result<()> {
+        if self.claims.get(path).is_some_and(|&claim| claim <= index) {
+            return Ok(());
+        }
+        Err(io::Error::other(format!(
+            "{} would be written through the symlink {}",
+            dst.display(),
+            path.display()
+        )))
+    }
+
+    // Make `dir` and the directories above it in the staged rootfs for copy
+    // `index`. Files in the way go.
+    fn make_dirs(&mut self, index: usize, dir: &Path) -> io::Result<()> {
+        let staged = self.overlay.staged(dir);
+        let mut current = self.overlay.staging_dir.to_path_buf();
+        for component in staged.components() {
+            current.push(component);
+            let filled = self.filled.entry(current.clone()).or_insert(index);
+            *filled = (*filled).max(index);
+            match fs::symlink_metadata(&current) {
+                Ok(stat) if stat.is_dir() => continue,
+                Ok(stat) => {
+                    if stat.is_symlink() {
+                        self.check_symlink(index, &current, dir)?;
+                    }
+                    self.overlay.remove(&current)?;
+                }
+                Err(_) => {}
+            }
+            fs::create_dir(&current)?;
+        }
+        Ok(())
+    }
+
+    // Write `member` to `dst` for copy `index`, the way tar would unpack it.
+    fn write(&mut self, index: usize, member: &mut Member, dst: &Path) -> io::Result<()> {
+        let is_dir = member.kind == archive::Kind::Dir;
+        if !self.may_write(index, dst, is_dir) {
+            return Ok(());
+        }
+        // The directory copied into keeps its owner and mode.
+        if is_dir && *dst == self.plans[index].dst {
+            return self.make_dirs(index, dst);
+        }
+        if let Some(parent) = dst.parent() {
+            self.make_dirs(index, parent)?;
+        }
+        let staged = self.overlay.staged(dst);
+        let current = fs::symlink_metadata(dst).ok();
+        let mut inode = member.inode.clone();
+        match &member.kind {
+            archive::Kind::Dir => {
+                if current.as_ref().is_some_and(|m| m.is_symlink()) {
+                    self.check_symlink(index, dst, dst)?;
+                }
+                if !current.is_some_and(|m| m.is_dir()) {
+                    self.overlay.remove(dst)?;
+                    fs::create_dir(dst)?;
+                }
+                fs::set_permissions(dst, Permissions::from_mode(ext4::staging_mode(inode.mode)))?;
+                if let Some(mtime) = member.mtime {
+                    self.dirs.insert(dst.to_path_buf(), mtime);
+                }
+            }
+            archive::Kind::File => {
+                self.overlay.remove(dst)?;
+                match &member.written {
+                    Some(first) => {
+                        fs::copy(first, dst)?;
+                    }
+                    None => {
+                        // CRC errors of a damaged archive show up while reading.
+                        io::copy(member.data, &mut fs::File::create(dst)?)?;
+                        member.written = Some(dst.to_path_buf());
+                    }
+                }
+                fs::set_permissions(dst, Permissions::from_mode(ext4::staging_mode(inode.mode)))?;
+            }
+            archive::Kind::Symlink(target) => {
+                self.overlay.remove(dst)?;
+                symlink(target, dst)?;
+            }
+            archive::Kind::HardLink(_) => {
+                let source = member.link.as_ref().expect("hard links have a source");
+                let first = self.members.get(&(index, source.clone())).ok_or_else(|| {
+                    io::Error::other(format!(
+                        "hard link to {}, which isn't copied",
+                        source.display()
+                    ))
+                })?;
+                let first = first.clone();
+                self.overlay.remove(dst)?;
+                fs::hard_link(&first, dst)?;
+                // One inode, whatever the header of the link says.
+                if let Some(linked) = self.overlay.metadata.get(&self.overlay.staged(&first)) {
+                    inode = linked.clone();
+                }
+            }
+            // Made by `ext4::build`, we can't without root.
+            archive::Kind::Special(..) => self.overlay.remove(dst)?,
+        }
+        if let (Some(mtime), archive::Kind::File | archive::Kind::Symlink(_)) =
+            (member.mtime, &member.kind)
+        {
+            archive::set_mtime(dst, mtime, 0)?;
+        }
+        self.overlay.metadata.insert(staged, inode);
+        self.claims.insert(dst.to_path_buf(), index);
+        self.members
+            .insert((index, member.path.clone()), dst.to_path_buf());
+        self.plans[index].written.push(dst.to_path_buf());
+        Ok(())
+    }
+}
+
+// Copy the overlays of the board into the staged rootfs, and fill in, write
+// and fix up files, as the manifest says.
+fn merge_into_staging(
+    board: &BoardProfile,
+    layout: &Layout,
+    overlay: &mut Overlay,
+    progress: archive::Progress,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (manifest, variables) = (&layout.manifest, &layout.variables);
+    let staging_dir = overlay.staging_dir;
+    let owner = |owner: &Option<String>| match owner {
+        Some(owner) => manifest::resolve_owner(staging_dir, owner).map(Some),
+        None => Ok(None),
     };
 
-    for dir in &["etc", "mnt", "root", "usr"] {
-        let src_dir = filesystem_dir.join(dir);
-        let dst_dir = temp_mount_dir.join(dir);
-        if src_dir.exists() {
-            copy_dir_all(&src_dir, &dst_dir)?;
+    let overlays: Vec<&PathBuf> = board.overlays.iter().filter(|dir| dir.exists()).collect();
+    let total = overlays.iter().map(|dir| ext4::tree_size(dir)).sum::<u64>();
+    let mut done = 0;
+    progress(done, total)?;
+    let mut copied = |bytes| {
+        done += bytes;
+        progress(done, total)
+    };
+    for dir in overlays {
+        overlay.copy_tree(dir, staging_dir, &mut copied)?;
+    }
+
+    for template in &manifest.template {
+        let path = manifest::expand(&template.path, variables)?;
+        let target = staging_dir.join(manifest::relative_path(&path)?);
+        overlay.check_parents(&target)?;
+        if fs::symlink_metadata(&target).is_err() {
+            if template.optional {
+                continue;
+            }
+            return Err(format!("template {} not found in the rootfs", path).into());
+        }
+        // Symlinks may point anywhere on this machine, they are neither
+        // followed nor filled in, a template that is one is an error.
+        let walk = WalkDir::new(&target)
+            .follow_root_links(false)
+            .sort_by_file_name();
+        for entry in walk {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                fill_template(staging_dir, entry.path(), variables)?;
+            } else if entry.depth() == 0 && entry.path_is_symlink() {
+                return Err(format!("template {} is a symlink", path).into());
+            } else if entry.depth() == 0 && !entry.file_type().is_dir() {
+                return Err(format!("template {} isn't a regular file", path).into());
+            }
         }
     }
 
-    fs::copy(
-        version_dir.join(format!("board/{}.bin", board_type)),
-        temp_mount_dir.join(format!("mnt/build/{}.bin", board_type)),
-    )?;
+    for file in &manifest.file {
+        let path = manifest::expand(&file.path, variables)?;
+        let target = staging_dir.join(manifest::relative_path(&path)?);
+        overlay.write_file(&target, &manifest::expand(&file.contents, variables)?)?;
+        overlay.set_inode(&target, file.mode, owner(&file.owner)?)?;
+    }
+
+    for attr in &manifest.attr {
+        let path = manifest::expand(&attr.path, variables)?;
+        let target = staging_dir.join(manifest::relative_path(&path)?);
+        overlay.check_parents(&target)?;
+        if fs::symlink_metadata(&target).is_err() {
+            if attr.optional {
+                continue;
+            }
+            return Err(format!("{} not found in the rootfs", path).into());
+        }
+        let attr_owner = owner(&attr.owner)?;
+        overlay.set_inode(&target, attr.mode, attr_owner)?;
+        // A symlink is changed itself, not what it points to.
+        let walk = WalkDir::new(&target)
+            .follow_root_links(false)
+            .min_depth(1)
+            .sort_by_file_name();
+        for entry in walk {
+            let entry = entry?;
+            let mode = if entry.file_type().is_dir() {
+                attr.dir_mode
+            } else {
+                attr.file_mode
+            };
+            overlay.set_inode(entry.path(), mode, attr_owner)?;
+        }
+    }
+
+    Ok(())
+}
 
-    // Write board_type to hostname and mnt/board_type
+// Replace the `{name}`s in the staged file at `path` in place, it keeps its
+// inode and with it its hard links and metadata.
+fn fill_template(
+    staging_dir: &Path,
+    path: &Path,
+    variables: &BTreeMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let name = Path::new("/").join(path.strip_prefix(staging_dir).unwrap_or(path));
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", name.display(), e))?;
+    let filled =
+        manifest::fill(&text, variables).map_err(|e| format!("{}: {}", name.display(), e))?;
+    if filled != text {
+        let metadata = fs::symlink_metadata(path)?;
+        fs::write(path, filled)?;
+        archive::set_mtime(path, metadata.mtime(), metadata.mtime_nsec())?;
+    }
+    Ok(())
+}
 
-    fs::write(&temp_mount_dir.join("etc/hostname"), board_type)?;
-    fs::write(&temp_mount_dir.join("mnt/config/boardtype"), board_type)?;
+// Copies trees into the staged rootfs the way tar would unpack them: symlinks
+// as they are, hard links as links, and every copy with the owner, mode,
+// xattrs and time of its source, recorded in `metadata` for `ext4::build`.
+struct Overlay<'a> {
+    staging_dir: &'a Path,
+    metadata: &'a mut Metadata,
+    // First staged copy of every multiply linked source inode.
+    links: HashMap<(u64, u64), PathBuf>,
+}
 
-    let systemd_rc = temp_mount_dir.join("etc/rc.local");
-    fs::set_permissions(&systemd_rc, Permissions::from_mode(0o755))?;
+impl Overlay<'_> {
+    // Path below the staged rootfs.
+    fn staged(&self, dst: &Path) -> PathBuf {
+        dst.strip_prefix(self.staging_dir)
+            .expect("copies go below staging")
+            .to_path_buf()
+    }
 
-    let ssh_key_path = temp_mount_dir.join("root/.ssh");
-    fs::set_permissions(&ssh_key_path, Permissions::from_mode(0o600))?;
+    // What `src` becomes.
+    fn source_inode(&self, src: &Path, stat: &fs::Metadata) -> io::Result<Inode> {
+        let rdev = stat.rdev();
+        let inode = Inode {
+            mode: stat.mode(),
+            uid: stat.uid(),
+            gid: stat.gid(),
+            rdev: matches!(stat.mode() & S_IFMT, S_IFCHR | S_IFBLK)
+                .then(|| (libc::major(rdev), libc::minor(rdev))),
+            xattrs: archive::read_xattrs(src)?,
+        };
+        Ok(inode)
+    }
 
-    for entry in WalkDir::new(ssh_key_path) {
-        let entry = entry?;
-        let path = entry.path();
+    // Refuse `dst` if a staged directory above it is a symlink. Its target
+    // is a path of the rootfs, here it may lead anywhere on this machine.
+    fn check_parents(&self, dst: &Path) -> io::Result<()> {
+        let staged = self.staged(dst);
+        let mut current = self.staging_dir.to_path_buf();
+        for component in staged.parent().into_iter().flat_map(Path::components) {
+            current.push(component);
+            if fs::symlink_metadata(&current).is_ok_and(|m| m.is_symlink()) {
+                return Err(io::Error::other(format!(
+                    "{} would be written through the symlink {}",
+                    Path::new("/").join(&staged).display(),
+                    Path::new("/").join(self.staged(&current)).display()
+                )));
+            }
+        }
+        Ok(())
+    }
 
-        let metadata = fs::metadata(&path)?;
-        let permissions = metadata.permissions();
-        let mode = permissions.mode();
+    // Make the directories above `dst`, never through a symlink.
+    fn make_parents(&self, dst: &Path) -> io::Result<()> {
+        self.check_parents(dst)?;
+        match dst.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+    }
+
+    // Make room for a copy at `dst`, which may hold anything.
+    fn remove(&mut self, dst: &Path) -> io::Result<()> {
+        let Ok(current) = fs::symlink_metadata(dst) else {
+            return Ok(());
+        };
+        let staged = self.staged(dst);
+        if current.is_dir() {
+            fs::remove_dir_all(dst)?;
+            self.metadata.retain(|path, _| !path.starts_with(&staged));
+        } else {
+            fs::remove_file(dst)?;
+            self.metadata.remove(&staged);
+        }
+        Ok(())
+    }
 
-        if mode & 0o777 != 0o600 {
-            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    // Copy what is in `src` into `dst`. `copied` is told the size of every
+    // file copied.
+    fn copy_tree(
+        &mut self,
+        src: &Path,
+        dst: &Path,
+        copied: &mut dyn FnMut(u64) -> Result<(), String>,
+    ) -> io::Result<()> {
+        if !dst.exists() {
+            fs::create_dir_all(dst)?;
         }
+        let mut entries = fs::read_dir(src)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            self.copy_entry(&entry.path(), &dst.join(entry.file_name()), copied)?;
+        }
+        Ok(())
     }
 
-    // 取消挂载
-    Command::new("umount").arg(&temp_mount_dir).status()?;
+    // Write a file of the staged rootfs, replacing a symlink or another name
+    // of a hard-linked file instead of writing through it. A file it replaces
+    // leaves its mode and owner.
+    fn write_file(&mut self, dst: &Path, contents: &str) -> io::Result<()> {
+        let staged = self.staged(dst);
+        let kept = self
+            .metadata
+            .get(&staged)
+            .filter(|inode| inode.mode & S_IFMT == S_IFREG)
+            .cloned();
+        self.make_parents(dst)?;
+        self.remove(dst)?;
+        fs::write(dst, contents)?;
+        if let Some(inode) = kept {
+            fs::set_permissions(dst, Permissions::from_mode(ext4::staging_mode(inode.mode)))?;
+            self.metadata.insert(staged, inode);
+        }
+        Ok(())
+    }
 
-    // 重命名 rootfs.img
-    fs::rename(&temp_rootfs_img, &update_rootfs_img)?;
+    // Give the staged `dst` the permission bits `mode`, unless it is a
+    // symlink, and the owner `owner`.
+    fn set_inode(
+        &mut self,
+        dst: &Path,
+        mode: Option<u32>,
+        owner: Option<(u32, u32)>,
+    ) -> io::Result<()> {
+        if mode.is_none() && owner.is_none() {
+            return Ok(());
+        }
+        let stat = fs::symlink_metadata(dst)?;
+        let staged = self.staged(dst);
+        let inode = self.metadata.entry(staged).or_insert_with(|| Inode {
+            mode: stat.mode(),
+            uid: 0,
+            gid: 0,
+            rdev: None,
+            xattrs: BTreeMap::new(),
+        });
+        if let Some(mode) = mode.filter(|_| !stat.is_symlink()) {
+            inode.mode = (inode.mode & S_IFMT) | (mode & 0o7777);
+            fs::set_permissions(dst, Permissions::from_mode(ext4::staging_mode(inode.mode)))?;
+        }
+        if let Some((uid, gid)) = owner {
+            inode.uid = uid;
+            inode.gid = gid;
+        }
+        Ok(())
+    }
 
-    // 清理临时文件夹
-    fs::remove_dir_all(&temp_mount_dir)?;
-    fs::remove_dir_all(&version_dir)?;
+    // Copy the file, symlink or directory `src` to `dst`.
+    fn copy_entry(
+        &mut self,
+        src: &Path,
+        dst: &Path,
+        copied: &mut dyn FnMut(u64) -> Result<(), String>,
+    ) -> io::Result<()> {
+        let stat = fs::symlink_metadata(src)?;
+        let inode = self.source_inode(src, &stat)?;
+        let staged = self.staged(dst);
+        let current = fs::symlink_metadata(dst).ok();
 
-    Ok(update_rootfs_img)
+        match stat.mode() & S_IFMT {
+            S_IFDIR => {
+                if current.as_ref().is_some_and(|m| m.is_symlink()) {
+                    return Err(io::Error::other(format!(
+                        "{} would be copied through the symlink {}",
+                        src.display(),
+                        dst.display()
+                    )));
+                }
+                // A directory already in the rootfs keeps its owner and mode.
+                let existing = current.as_ref().is_some_and(|m| m.is_dir());
+                if !existing {
+                    self.remove(dst)?;
+                }
+                self.copy_tree(src, dst, copied)?;
+                if !existing {
+                    fs::set_permissions(
+                        dst,
+                        Permissions::from_mode(ext4::staging_mode(inode.mode)),
+                    )?;
+                    archive::set_mtime(dst, stat.mtime(), stat.mtime_nsec())?;
+                    self.metadata.insert(staged, inode);
+                }
+                return Ok(());
+            }
+            // Symlinks are copied as they are, and never followed on either
+            // side: their targets are paths of the rootfs, not of this machine.
+            S_IFLNK => {
+                self.remove(dst)?;
+                symlink(fs::read_link(src)?, dst)?;
+            }
+            S_IFREG => {
+                self.remove(dst)?;
+                let key = (stat.dev(), stat.ino());
+                match self.links.get(&key) {
+                    Some(first) if stat.nlink() > 1 => fs::hard_link(first, dst)?,
+                    _ => {
+                        fs::copy(src, dst)?;
+                        fs::set_permissions(
+                            dst,
+                            Permissions::from_mode(ext4::staging_mode(inode.mode)),
+                        )?;
+                        if stat.nlink() > 1 {
+                            self.links.insert(key, dst.to_path_buf());
+                        }
+                    }
+                }
+                copied(stat.len()).map_err(io::Error::other)?;
+            }
+            S_IFCHR | S_IFBLK | S_IFIFO => {
+                // Made by `ext4::build`, we can't without root.
+                self.remove(dst)?;
+                self.metadata.insert(staged, inode);
+                return Ok(());
+            }
+            _ => {
+                warn!("Skipping socket {}", src.display());
+                return Ok(());
+            }
+        }
+        archive::set_mtime(dst, stat.mtime(), stat.mtime_nsec())?;
+        self.metadata.insert(staged, inode);
+        Ok(())
+    }
 }
 
-fn copy_dir_all(src: &PathBuf, dst: &PathBuf) -> io::Result<()> {
-    if !dst.exists() {
-        fs::create_dir_all(dst)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{tar_archive, Member, TempDir};
+
+    // Stream `archive` into `staging_dir` for the copies `from` -> `to`, as
+    // `stream_packages` does with the members of the packages.
+    fn stream(staging_dir: &Path, copies: &[(&str, &str)], archive: &[u8]) {
+        let copies: Vec<manifest::Copy> = copies
+            .iter()
+            .map(|&(from, to)| manifest::Copy {
+                package: Package::Version,
+                from: from.to_string(),
+                to: to.to_string(),
+                optional: false,
+                owner: None,
+                file_mode: None,
+                dir_mode: None,
+            })
+            .collect();
+        let mut metadata = Metadata::new();
+        let mut overlay = Overlay {
+            staging_dir,
+            metadata: &mut metadata,
+            links: HashMap::new(),
+        };
+        let plans = copies
+            .iter()
+            .map(|copy| Plan {
+                copy,
+                from: copy.from.clone(),
+                package: "version".to_string(),
+                source: PathBuf::from(&copy.from),
+                dst: staging_dir.join(&copy.to),
+                found: false,
+                written: vec![],
+            })
+            .collect();
+        let mut packages = Packages {
+            overlay: &mut overlay,
+            plans,
+            claims: HashMap::new(),
+            filled: HashMap::new(),
+            members: HashMap::new(),
+            dirs: BTreeMap::new(),
+            subtree: PathBuf::from("v/board"),
+            subtree_found: false,
+            newest: 0,
+        };
+        archive::walk_reader(&mut &archive[..], &mut |entry| {
+            packages.member(entry, Path::new(""))
+        })
+        .unwrap();
+        assert!(packages.plans.iter().all(|plan| plan.found));
     }
 
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let src_path = entry.path();
-        let dst_path = dst.join(entry.file_name());
+    #[test]
+    fn later_copy_wins_a_file() {
+        let one = ("pkg/one", Member::File("one"));
+        let two = ("pkg/two", Member::File("two"));
+        for entries in [[one, two], [two, one]] {
+            let staging_dir = TempDir::new();
+            let copies = [("pkg/one", "etc/conf"), ("pkg/two", "etc/conf")];
+            stream(staging_dir.path(), &copies, &tar_archive(&entries));
+            let conf = fs::read_to_string(staging_dir.path().join("etc/conf")).unwrap();
+            assert_eq!(conf, "two", "members in the order {:?}", entries);
+        }
+    }
 
-        if src_path.is_dir() {
-            copy_dir_all(&src_path, &dst_path)?;
-        } else {
-            fs::copy(&src_path, &dst_path)?;
+    #[test]
+    fn later_copy_wins_in_a_directory() {
+        let a = [
+            ("pkg/a/", Member::Dir),
+            ("pkg/a/x", Member::File("a")),
+            ("pkg/a/only", Member::File("a")),
+        ];
+        let b = [("pkg/b/", Member::Dir), ("pkg/b/x", Member::File("b"))];
+        for entries in [[a.as_slice(), &b].concat(), [b.as_slice(), &a].concat()] {
+            let staging_dir = TempDir::new();
+            let copies = [("pkg/a", "opt/app"), ("pkg/b", "opt/app")];
+            stream(staging_dir.path(), &copies, &tar_archive(&entries));
+            let app = staging_dir.path().join("opt/app");
+            let x = fs::read_to_string(app.join("x")).unwrap();
+            assert_eq!(x, "b", "members in the order {:?}", entries);
+            assert_eq!(fs::read_to_string(app.join("only")).unwrap(), "a");
         }
     }
-    Ok(())
+
+    #[test]
+    fn earlier_file_does_not_replace_later_directory() {
+        let file = [("pkg/file", Member::File("file"))];
+        let dir = [("pkg/dir/", Member::Dir), ("pkg/dir/x", Member::File("x"))];
+        for entries in [
+            [file.as_slice(), &dir].concat(),
+            [dir.as_slice(), &file].concat(),
+        ] {
+            let staging_dir = TempDir::new();
+            let copies = [("pkg/file", "opt/thing"), ("pkg/dir", "opt/thing")];
+            stream(staging_dir.path(), &copies, &tar_archive(&entries));
+            let thing = staging_dir.path().join("opt/thing");
+            assert!(thing.is_dir(), "members in the order {:?}", entries);
+            assert_eq!(fs::read_to_string(thing.join("x")).unwrap(), "x");
+        }
+    }
+
+    // Merge the manifest `manifest` into `staging_dir`, for a board without
+    // overlays.
+    fn merge(staging_dir: &Path, manifest: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let board: BoardProfile = toml::from_str(r#"subtree = "board""#).unwrap();
+        let layout = Layout {
+            manifest: toml::from_str(manifest).unwrap(),
+            variables: BTreeMap::from([("board".to_string(), "rk3568".to_string())]),
+            newest: 0,
+        };
+        let mut metadata = Metadata::new();
+        let mut overlay = Overlay {
+            staging_dir,
+            metadata: &mut metadata,
+            links: HashMap::new(),
+        };
+        merge_into_staging(&board, &layout, &mut overlay, &mut |_, _| Ok(()))
+    }
+
+    #[test]
+    fn files_are_not_written_through_symlinked_directories() {
+        let (staging_dir, host) = (TempDir::new(), TempDir::new());
+        fs::write(host.path().join("boardtype"), "host").unwrap();
+        fs::create_dir(staging_dir.path().join("mnt")).unwrap();
+        symlink(host.path(), staging_dir.path().join("mnt/config")).unwrap();
+
+        let manifest = r#"
+            [[file]]
+            path = "mnt/config/boardtype"
+            contents = "{board}"
+        "#;
+        let error = merge(staging_dir.path(), manifest).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("written through the symlink /mnt/config"),
+            "{}",
+            error
+        );
+        let host_file = fs::read_to_string(host.path().join("boardtype")).unwrap();
+        assert_eq!(host_file, "host");
+    }
+
+    #[test]
+    fn attrs_do_not_follow_symlinks() {
+        let (staging_dir, host) = (TempDir::new(), TempDir::new());
+        let host_file = host.path().join("app.conf");
+        fs::write(&host_file, "host").unwrap();
+        fs::set_permissions(&host_file, Permissions::from_mode(0o644)).unwrap();
+        fs::create_dir(staging_dir.path().join("etc")).unwrap();
+        symlink(host.path(), staging_dir.path().join("etc/app")).unwrap();
+
+        // The symlink itself is all there is to change.
+        let manifest = r#"
+            [[attr]]
+            path = "etc/app"
+            file_mode = 0o600
+        "#;
+        merge(staging_dir.path(), manifest).unwrap();
+        let mode = fs::metadata(&host_file).unwrap().mode() & 0o7777;
+        assert_eq!(mode, 0o644);
+
+        let manifest = r#"
+            [[attr]]
+            path = "etc/app/app.conf"
+            mode = 0o600
+        "#;
+        let error = merge(staging_dir.path(), manifest).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("written through the symlink /etc/app"),
+            "{}",
+            error
+        );
+        let mode = fs::metadata(&host_file).unwrap().mode() & 0o7777;
+        assert_eq!(mode, 0o644);
+    }
+
+    #[test]
+    fn templates_are_not_filled_through_symlinks() {
+        let (staging_dir, host) = (TempDir::new(), TempDir::new());
+        let host_file = host.path().join("app.conf");
+        fs::write(&host_file, "board={board}").unwrap();
+        fs::create_dir(staging_dir.path().join("etc")).unwrap();
+        symlink(host.path(), staging_dir.path().join("etc/app")).unwrap();
+
+        for path in ["etc/app", "etc/app/app.conf"] {
+            let manifest = format!("[[template]]\npath = \"{}\"", path);
+            let error = merge(staging_dir.path(), &manifest).unwrap_err();
+            assert!(error.to_string().contains("symlink"), "{}: {}", path, error);
+            let host_text = fs::read_to_string(&host_file).unwrap();
+            assert_eq!(host_text, "board={board}");
+        }
+    }
+
+    #[test]
+    fn templates_are_filled_in() {
+        let staging_dir = TempDir::new();
+        let etc = staging_dir.path().join("etc/app");
+        fs::create_dir_all(&etc).unwrap();
+        fs::write(etc.join("app.conf"), "board={board}").unwrap();
+        symlink("/etc/passwd", etc.join("passwd")).unwrap();
+
+        merge(staging_dir.path(), "[[template]]\npath = \"etc/app\"").unwrap();
+        let text = fs::read_to_string(etc.join("app.conf")).unwrap();
+        assert_eq!(text, "board=rk3568");
+        assert_eq!(
+            fs::read_link(etc.join("passwd")).unwrap(),
+            Path::new("/etc/passwd")
+        );
+    }
 }
@@ -0,0 +1,163 @@
+use std::fs;
+use std::path::Path;
+
+// Rockchip parameter files lay out the storage in the kernel command line:
+//
+//   CMDLINE: mtdparts=rk29xxnand:0x00002000@0x00004000(uboot),...,
+//            0x00200000@0x00038000(rootfs),-@0x00238000(userdata:grow)
+//
+// Sizes and offsets are in 512-byte sectors, `-` takes the rest of the
+// storage.
+
+const SECTOR: u64 = 512;
+
+/// One partition of the `mtdparts` layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    /// In bytes, None when it takes the rest of the storage.
+    pub size: Option<u64>,
+    pub offset: u64,
+}
+
+fn sectors(value: &str) -> Option<u64> {
+    let value = value.trim();
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// The partitions of the parameter file at `path`, in layout order.
+pub fn partitions(path: &Path) -> Result<Vec<Partition>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mtdparts = text
+        .lines()
+        .filter(|line| line.trim_start().starts_with("CMDLINE:"))
+        .find_map(|line| line.split_once("mtdparts=").map(|(_, parts)| parts))
+        .and_then(|parts| parts.split_whitespace().next())
+        .ok_or_else(|| format!("{}: no mtdparts in CMDLINE", path.display()))?;
+    // The storage name comes first, `rk29xxnand:`.
+    let list = mtdparts.split_once(':').map_or(mtdparts, |(_, list)| list);
+
+    let mut partitions = vec![];
+    for part in list.split(',') {
+        let bad = || format!("{}: bad partition `{}`", path.display(), part);
+        let (size, rest) = part.split_once('@').ok_or_else(bad)?;
+        let (offset, name) = rest.split_once('(').ok_or_else(bad)?;
+        let name = name.strip_suffix(')').ok_or_else(bad)?;
+        // Flags after the name, e.g. `userdata:grow`.
+        let name = name.split(':').next().unwrap_or(name);
+        let size = match size.trim() {
+            "-" => None,
+            size => Some(sectors(size).ok_or_else(bad)? * SECTOR),
+        };
+        partitions.push(Partition {
+            name: name.to_string(),
+            size,
+            offset: sectors(offset).ok_or_else(bad)? * SECTOR,
+        });
+    }
+    Ok(partitions)
+}
+
+/// The partition called `name` in the parameter file at `path`.
+pub fn partition(path: &Path, name: &str) -> Result<Partition, String> {
+    partitions(path)?
+        .into_iter()
+        .find(|partition| partition.name == name)
+        .ok_or_else(|| format!("{}: no `{}` partition", path.display(), name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn parse(text: &str) -> Result<Vec<Partition>, String> {
+        let dir = TempDir::new();
+        let path = dir.path().join("parameter.txt");
+        fs::write(&path, text).unwrap();
+        partitions(&path)
+    }
+
+    fn part(name: &str, size: Option<u64>, offset: u64) -> Partition {
+        Partition {
+            name: name.to_string(),
+            size,
+            offset,
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let text = "FIRMWARE_VER: 1.0\n\
+            MACHINE: 0xffffffff\n\
+            CMDLINE: console=ttyFIQ0 mtdparts=rk29xxnand:0x00002000@0x00004000(uboot),\
+            0x00200000@0x00038000(rootfs),-@0x00238000(userdata:grow) rootwait\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                part("uboot", Some(0x2000 * 512), 0x4000 * 512),
+                part("rootfs", Some(0x20_0000 * 512), 0x3_8000 * 512),
+                part("userdata", None, 0x23_8000 * 512),
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_and_flags() {
+        let text = "CMDLINE: mtdparts=rk29xxnand:2048@8192(misc:bootable),0X10@0x2800(boot)";
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                part("misc", Some(2048 * 512), 8192 * 512),
+                part("boot", Some(0x10 * 512), 0x2800 * 512),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_the_cmdline_counts() {
+        let text = "# mtdparts=x:0x1@0x0(old)\n\
+            CMDLINE: mtdparts=rk29xxnand:0x1@0x2(rootfs)\n";
+        assert_eq!(parse(text).unwrap(), [part("rootfs", Some(512), 1024)]);
+        assert!(parse("CMDLINE: console=ttyFIQ0 rootwait\n").is_err());
+        assert!(parse("# mtdparts=x:0x1@0x0(old)\n").is_err());
+    }
+
+    #[test]
+    fn malformed_partitions() {
+        for parts in [
+            "0x1(rootfs)",
+            "0x1@0x2rootfs",
+            "0x1@0x2(rootfs",
+            "0xg@0x2(rootfs)",
+            "0x1@-(rootfs)",
+            "@0x2(rootfs)",
+            "0x1@0x2(uboot),,0x1@0x3(rootfs)",
+        ] {
+            let text = format!("CMDLINE: mtdparts=rk29xxnand:{}\n", parts);
+            assert!(parse(&text).is_err(), "{}", parts);
+        }
+    }
+
+    #[test]
+    fn partition_by_name() {
+        let dir = TempDir::new();
+        let path = dir.path().join("parameter.txt");
+        fs::write(
+            &path,
+            "CMDLINE: mtdparts=rk29xxnand:0x1@0x2(boot),-@0x3(rootfs:grow)\n",
+        )
+        .unwrap();
+        assert_eq!(
+            partition(&path, "rootfs").unwrap(),
+            part("rootfs", None, 3 * 512)
+        );
+        assert!(partition(&path, "userdata").is_err());
+    }
+}
@@ -0,0 +1,526 @@
+use crate::archive;
+use crate::board::BoardProfile;
+use crate::cache::{self, CacheKey};
+use crate::config::Config;
+use crate::flash::FlashOptions;
+use crate::manifest::{self, Manifest};
+use crate::reproducible;
+use crate::rootfs::FsType;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// Everything a job needs is checked before it starts, so a missing image or
+// a full disk is reported up front instead of halfway through the build.
+
+// Headroom on top of the estimated space needed to build the rootfs.
+const SPACE_MARGIN: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The job can't run.
+    Error,
+    /// Worth knowing, doesn't stop the job.
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Which check, e.g. `tool` or `disk_space`.
+    pub check: &'static str,
+    pub message: String,
+}
+
+impl Finding {
+    /// An error found outside the checks here, e.g. in a form.
+    pub fn error(check: &'static str, message: impl ToString) -> Self {
+        Self {
+            severity: Severity::Error,
+            check,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.check, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn add(&mut self, severity: Severity, check: &'static str, message: impl ToString) {
+        self.0.push(Finding {
+            severity,
+            check,
+            message: message.to_string(),
+        });
+    }
+
+    fn error(&mut self, check: &'static str, message: impl ToString) {
+        self.add(Severity::Error, check, message);
+    }
+}
+
+pub fn has_errors(findings: &[Finding]) -> bool {
+    findings.iter().any(|f| f.severity == Severity::Error)
+}
+
+/// Check that a `recipe` job for `board` and `version` can run.
+pub fn check(
+    config: &Config,
+    board: &str,
+    version: &str,
+    recipe: &str,
+    options: &FlashOptions,
+) -> Vec<Finding> {
+    let mut findings = Findings::default();
+    // A dry run doesn't run the tool or write images, so what only the
+    // devices need is a warning there.
+    let device_severity = if options.dry_run {
+        Severity::Warning
+    } else {
+        Severity::Error
+    };
+
+    let images: &[&str] = match recipe {
+        "all" => &["loader.bin", "parameter.txt", "uboot.img", "boot.img"],
+        "erase" => &["loader.bin"],
+        _ => {
+            findings.error("recipe", format!("unknown recipe `{}`", recipe));
+            return findings.0;
+        }
+    };
+
+    if let Err(e) = check_tool(&config.paths.upgrade_tool) {
+        findings.add(device_severity, "tool", e);
+    }
+    for image in images {
+        let path = config.paths.image_dir.join(image);
+        if !path.is_file() {
+            findings.add(
+                device_severity,
+                "images",
+                format!("{} not found", path.display()),
+            );
+        }
+    }
+
+    if recipe == "all" {
+        if options.skip_prepare {
+            match config.board(board) {
+                Ok(_) => {
+                    if let Err(e) = cache::cached_image(config, board, version, &options.variables)
+                    {
+                        findings.add(
+                            device_severity,
+                            "images",
+                            format!("{}, it isn't built with --skip-prepare", e),
+                        );
+                    }
+                }
+                Err(e) => findings.error("board", e),
+            }
+        } else {
+            check_prepare_into(config, board, version, &options.variables, &mut findings);
+        }
+    }
+    findings.0
+}
+
+/// Check that the rootfs of `board` can be built from `version` with the
+/// batch `variables`.
+pub fn check_prepare(
+    config: &Config,
+    board: &str,
+    version: &str,
+    variables: &BTreeMap<String, String>,
+) -> Vec<Finding> {
+    let mut findings = Findings::default();
+    check_prepare_into(config, board, version, variables, &mut findings);
+    findings.0
+}
+
+fn check_prepare_into(
+    config: &Config,
+    board: &str,
+    version: &str,
+    variables: &BTreeMap<String, String>,
+    findings: &mut Findings,
+) {
+    let profile = match config.board(board) {
+        Ok(profile) => profile,
+        Err(e) => {
+            findings.error("board", e);
+            return;
+        }
+    };
+    let package = archive::find_package(&config.paths.version_dir, version);
+    if package.is_none() {
+        findings.error(
+            "version",
+            format!(
+                "version `{}` not found in {}",
+                version,
+                config.paths.version_dir.display()
+            ),
+        );
+    }
+    check_chip(config, profile, findings);
+    if let Err(e) = manifest::check_variables(&profile.variables) {
+        findings.error("variables", format!("board `{}`: {}", profile.id, e));
+    }
+    if let Err(e) = manifest::check_variables(variables) {
+        findings.error("variables", format!("the batch: {}", e));
+    }
+    if let Err(e) = reproducible::configured_epoch(config) {
+        findings.error("build", e);
+    }
+    if let Some(uuid) = profile
+        .fs_uuid
+        .as_deref()
+        .filter(|uuid| !reproducible::is_uuid(uuid))
+    {
+        findings.error(
+            "board",
+            format!("fs_uuid `{}` of board `{}` isn't a UUID", uuid, profile.id),
+        );
+    }
+
+    // An image that is already built from the same inputs isn't built again.
+    let cached = CacheKey::compute(config, profile, version, variables)
+        .ok()
+        .and_then(|key| cache::lookup(config, &key));
+    if cached.is_some() {
+        return;
+    }
+
+    let mut needed = SPACE_MARGIN;
+    let rootfs = config.paths.image_dir.join("rootfs.img");
+    match fs::metadata(&rootfs) {
+        // Unpacked to a staging directory and written to a new image, and
+        // that again as a sparse one.
+        Ok(metadata) if metadata.is_file() => {
+            needed += 2 * metadata.len();
+            if profile.sparse {
+                needed += metadata.len();
+            }
+            check_filesystem(&rootfs, profile, findings);
+        }
+        _ => findings.error("images", format!("{} not found", rootfs.display())),
+    }
+    if archive::find_package(&config.paths.image_dir, "update-rootfs").is_none() {
+        findings.error(
+            "images",
+            format!(
+                "no update-rootfs archive in {}",
+                config.paths.image_dir.display()
+            ),
+        );
+    }
+    if let Some(manifest) = &profile.manifest {
+        if let Err(e) = Manifest::read(manifest) {
+            findings.error("board", e);
+        }
+    }
+    for overlay in &profile.overlays {
+        if !overlay.is_dir() {
+            findings.error(
+                "board",
+                format!(
+                    "overlay {} of board `{}` not found",
+                    overlay.display(),
+                    profile.id
+                ),
+            );
+        }
+    }
+
+    if let Some(package) = &package {
+        match VersionContents::read(package, version) {
+            Ok(contents) => {
+                needed += contents.unpacked_size;
+                check_version_contents(&contents, profile, version, findings);
+            }
+            Err(e) => findings.error("version", e),
+        }
+    }
+
+    match available_space(&config.paths.work_dir) {
+        Ok(available) if available < needed => findings.error(
+            "disk_space",
+            format!(
+                "{} MiB free in {}, building the rootfs needs about {} MiB",
+                available / (1024 * 1024),
+                config.paths.work_dir.display(),
+                needed / (1024 * 1024)
+            ),
+        ),
+        Ok(_) => {}
+        Err(e) => findings.add(
+            Severity::Warning,
+            "disk_space",
+            format!(
+                "can't tell the free space in {}: {}",
+                config.paths.work_dir.display(),
+                e
+            ),
+        ),
+    }
+}
+
+fn check_tool(tool: &Path) -> Result<(), String> {
+    let metadata = fs::metadata(tool).map_err(|_| format!("{} not found", tool.display()))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file", tool.display()));
+    }
+    if metadata.permissions().mode() & 0o111 == 0 {
+        return Err(format!("{} is not executable", tool.display()));
+    }
+    Ok(())
+}
+
+// The rootfs of `profile` can be built from the base image at `rootfs` with
+// the tools installed.
+fn check_filesystem(rootfs: &Path, profile: &BoardProfile, findings: &mut Findings) {
+    let base_type = match FsType::detect(rootfs) {
+        Ok(base_type) => base_type,
+        Err(e) => {
+            findings.error("filesystem", e);
+            return;
+        }
+    };
+    let fs_type = profile.filesystem.unwrap_or(base_type);
+    if base_type == FsType::Ext4 || fs_type == FsType::Ext4 {
+        check_e2fsprogs(findings);
+    }
+    if fs_type == FsType::Ubifs && base_type != FsType::Ubifs {
+        findings.error(
+            "filesystem",
+            format!(
+                "board `{}` wants ubifs, which takes the flash geometry of a ubifs base image, not {}",
+                profile.id, base_type
+            ),
+        );
+    }
+    if profile.sparse && fs_type != FsType::Ext4 {
+        findings.error(
+            "filesystem",
+            format!(
+                "sparse images are for ext4 root filesystems, not {}",
+                fs_type
+            ),
+        );
+    }
+    // e2fsprogs are looked for above.
+    let mut tools = BTreeMap::new();
+    for fs_type in [base_type, fs_type] {
+        if fs_type != FsType::Ext4 {
+            for tool in fs_type.tools() {
+                tools.entry(*tool).or_insert(fs_type);
+            }
+        }
+    }
+    for (tool, fs_type) in tools {
+        if !in_path(tool) {
+            findings.error(
+                "filesystem",
+                format!("{} not found, {} images need it", tool, fs_type),
+            );
+        }
+    }
+}
+
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|path| {
+        std::env::split_paths(&path).any(|dir| {
+            fs::metadata(dir.join(program))
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+    })
+}
+
+// Rockchip parameter files name the chip they are for, e.g. `MACHINE_MODEL: RK3568`.
+fn check_chip(config: &Config, profile: &BoardProfile, findings: &mut Findings) {
+    let Some(chip) = &profile.chip else {
+        return;
+    };
+    let parameter = config.paths.image_dir.join("parameter.txt");
+    let Ok(text) = fs::read_to_string(&parameter) else {
+        return;
+    };
+    let model = text
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("MACHINE_MODEL:"))
+        .map(str::trim);
+    if let Some(model) = model {
+        if !model.eq_ignore_ascii_case(chip) {
+            findings.error(
+                "board",
+                format!(
+                    "board `{}` is a {} board, {} is for {}",
+                    profile.id,
+                    chip,
+                    parameter.display(),
+                    model
+                ),
+            );
+        }
+    }
+}
+
+// Paths in the version package relative to its top directory, including what
+// the board/filesystem archive unpacks to.
+struct VersionContents {
+    paths: BTreeSet<PathBuf>,
+    // Bytes of the members, the most the copies can add to the rootfs.
+    unpacked_size: u64,
+    filesystem: bool,
+}
+
+impl VersionContents {
+    fn read(package: &Path, version: &str) -> Result<Self, String> {
+        let mut contents = VersionContents {
+            paths: BTreeSet::new(),
+            unpacked_size: 0,
+            filesystem: false,
+        };
+        let mut visit = |entry: archive::Entry| {
+            let path = entry.path.strip_prefix(".").unwrap_or(&entry.path);
+            let Ok(path) = path.strip_prefix(version) else {
+                return Ok(());
+            };
+            let path = path.to_path_buf();
+            if path.parent() == Some(Path::new("board"))
+                && archive::package_name(&path).as_deref() == Some("filesystem")
+            {
+                contents.filesystem = true;
+                let mut inner = |entry: archive::Entry| {
+                    contents.unpacked_size += entry.size;
+                    let path = entry.path.strip_prefix(".").unwrap_or(&entry.path);
+                    contents.paths.insert(Path::new("board").join(path));
+                    Ok(())
+                };
+                archive::walk_reader(entry.data, &mut inner)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+            } else {
+                contents.unpacked_size += entry.size;
+            }
+            contents.paths.insert(path);
+            Ok(())
+        };
+        archive::walk(package, &mut visit, &mut |_, _| Ok(()))?;
+        Ok(contents)
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        self.paths.contains(path)
+    }
+
+    fn contains_dir(&self, dir: &Path) -> bool {
+        self.paths
+            .range(dir.to_path_buf()..)
+            .next()
+            .is_some_and(|path| path.starts_with(dir))
+    }
+}
+
+fn check_version_contents(
+    contents: &VersionContents,
+    profile: &BoardProfile,
+    version: &str,
+    findings: &mut Findings,
+) {
+    if contents.paths.is_empty() {
+        findings.error(
+            "version",
+            format!("the version package doesn't unpack to {}/", version),
+        );
+        return;
+    }
+    if !contents.filesystem {
+        findings.error(
+            "version",
+            format!("board/filesystem archive missing from version {}", version),
+        );
+    }
+    if !contents.contains_dir(&profile.subtree) {
+        findings.error(
+            "board",
+            format!(
+                "version {} has no {} for board `{}`",
+                version,
+                profile.subtree.display(),
+                profile.id
+            ),
+        );
+    }
+    if !contents.contains(&profile.bin) {
+        findings.error(
+            "board",
+            format!(
+                "version {} has no {} for board `{}`",
+                version,
+                profile.bin.display(),
+                profile.id
+            ),
+        );
+    }
+}
+
+// Free space of the filesystem `dir` is on, or would be created on.
+fn available_space(dir: &Path) -> Result<u64, String> {
+    let existing = dir
+        .ancestors()
+        .find(|path| path.exists())
+        .ok_or("no existing parent directory")?;
+    let path = CString::new(existing.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// The rootfs is unpacked and rebuilt with e2fsprogs, see ext4.rs.
+fn check_e2fsprogs(findings: &mut Findings) {
+    for tool in ["debugfs", "dumpe2fs", "mke2fs"] {
+        let Ok(output) = Command::new(tool).arg("-V").stdin(Stdio::null()).output() else {
+            findings.error(
+                "e2fsprogs",
+                format!("{} not found, building the rootfs needs e2fsprogs", tool),
+            );
+            continue;
+        };
+        // `mke2fs 1.46.5 (30-Dec-2021)`
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let version = stderr.split_whitespace().nth(1).unwrap_or_default();
+        let release: Vec<u32> = version.split('.').map_while(|n| n.parse().ok()).collect();
+        if tool == "mke2fs" && release.len() >= 2 && release[..2] < [1, 47][..] {
+            findings.add(
+                Severity::Warning,
+                "e2fsprogs",
+                format!(
+                    "mke2fs {} adds directories in the order it reads them, images of \
+                     the same inputs may differ, 1.47 or newer sorts them",
+                    version
+                ),
+            );
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::archive;
+use crate::config::Config;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use walkdir::WalkDir;
+
+// Identical inputs give a byte-identical image. Everything that would
+// otherwise come from the clock, the order of `read_dir` or a random number
+// generator is fixed:
+//
+//   timestamps   clamped to SOURCE_DATE_EPOCH, access and change times set to
+//                the modification time, new inodes and the superblock get it
+//   order        trees are walked by file name, mke2fs -d adds directory
+//                entries in name order since e2fsprogs 1.47
+//   UUID, seed   derived from the board and the version, unless the board
+//                sets `fs_uuid`, e.g. because its fstab mounts by UUID
+//
+// SOURCE_DATE_EPOCH is taken from the environment, the station config or,
+// without either, the newest file of the base image and the packages:
+//
+//   [build]
+//   source_date_epoch = 1700000000
+//
+// ubifs images still differ, mkfs.ubifs always picks a random UUID.
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BuildConfig {
+    /// Seconds since 1970 that timestamps in the image are clamped to.
+    pub source_date_epoch: Option<i64>,
+}
+
+/// SOURCE_DATE_EPOCH from the environment or the station config, None if it
+/// is to be derived from the inputs.
+pub fn configured_epoch(config: &Config) -> Result<Option<i64>, String> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if !value.is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("SOURCE_DATE_EPOCH `{}` isn't a number of seconds", value)),
+        _ => Ok(config.build.source_date_epoch),
+    }
+}
+
+/// Modification time of the newest regular file below `dir`, in seconds.
+pub fn newest_file(dir: &Path) -> Result<i64, String> {
+    let mut newest = 0;
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.map_err(|e| e.to_string())?;
+        if entry.file_type().is_file() {
+            let metadata = entry
+                .metadata()
+                .map_err(|e| format!("{}: {}", entry.path().display(), e))?;
+            newest = newest.max(metadata.mtime());
+        }
+    }
+    Ok(newest)
+}
+
+/// Set modification times below `dir` that are later than `epoch` to it,
+/// and access times to the modification times.
+pub fn clamp_times(dir: &Path, epoch: i64) -> Result<(), String> {
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path();
+        let metadata = entry
+            .metadata()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mtime = (metadata.mtime(), metadata.mtime_nsec());
+        let clamped = mtime.min((epoch, 0));
+        if clamped != mtime || (metadata.atime(), metadata.atime_nsec()) != clamped {
+            archive::set_mtime(path, clamped.0, clamped.1)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+// A UUID from the first bytes of SHA-256 over `purpose`, the board and the
+// version, marked as a custom (version 8) UUID.
+fn derived_uuid(purpose: &str, board: &str, version: &str) -> String {
+    let mut hasher = Sha256::new();
+    for field in [purpose, board, version] {
+        hasher.update(field.as_bytes());
+        hasher.update([0]);
+    }
+    let mut bytes: [u8; 16] = hasher.finalize()[..16].try_into().expect("16 bytes");
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Whether `text` is a UUID, e.g. `0b7e5d3c-4f1a-8e2b-9c6d-1a2b3c4d5e6f`.
+pub fn is_uuid(text: &str) -> bool {
+    let groups: Vec<&str> = text.split('-').collect();
+    groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|group| group.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Filesystem UUID of the image of `board` and `version`.
+pub fn fs_uuid(board: &str, version: &str) -> String {
+    derived_uuid("uuid", board, version)
+}
+
+/// Directory hash seed of the image of `board` and `version`.
+pub fn hash_seed(board: &str, version: &str) -> String {
+    derived_uuid("hash_seed", board, version)
+}
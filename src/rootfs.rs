@@ -0,0 +1,614 @@
+use crate::archive::Progress;
+use crate::ext4::{self, Ext4Params, Inode, Metadata, Wanted};
+use libc::{S_IFBLK, S_IFCHR, S_IFIFO, S_IFLNK, S_IFMT};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Root filesystems besides ext4: squashfs and erofs for read-only roots,
+// ubifs for NAND. Their tools only keep owners and device nodes when run as
+// root, so they run under fakeroot instead:
+//
+//   squashfs  unsquashfs / mksquashfs            (squashfs-tools)
+//   erofs     fsck.erofs --extract / mkfs.erofs  (erofs-utils)
+//   ubifs     ubireader_extract_files / mkfs.ubifs (ubi_reader, mtd-utils)
+//
+// fakeroot only keeps xattrs for as long as its session, so an image is
+// extracted and listed, owners, modes, device nodes and xattrs with stat and
+// getfattr, in one session. A new one is set up from the metadata, with
+// chown, chmod, mknod and setfattr, and written in one session. The
+// settings of the new image, e.g. compression and block size, are taken
+// from the base image's superblock when it is of the same type, they are
+// the tools' defaults otherwise. ubifs has no defaults for the flash it is
+// for, so it is only built from a ubifs base image.
+
+const EROFS_MAGIC: u32 = 0xe0f5_e1e2;
+const UBIFS_MAGIC: u32 = 0x0610_1831;
+
+/// Filesystem of a rootfs image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsType {
+    Ext4,
+    Squashfs,
+    Erofs,
+    Ubifs,
+}
+
+impl fmt::Display for FsType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FsType::Ext4 => "ext4",
+            FsType::Squashfs => "squashfs",
+            FsType::Erofs => "erofs",
+            FsType::Ubifs => "ubifs",
+        })
+    }
+}
+
+// The first `len` bytes of `image`, fewer if it is shorter.
+fn head(image: &Path, len: u64) -> Result<Vec<u8>, String> {
+    let mut data = vec![];
+    File::open(image)
+        .and_then(|file| file.take(len).read_to_end(&mut data))
+        .map_err(|e| format!("{}: {}", image.display(), e))?;
+    Ok(data)
+}
+
+fn le16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn le32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+impl FsType {
+    /// The filesystem in `image`, by the magic number of its superblock.
+    pub fn detect(image: &Path) -> Result<Self, String> {
+        let data = head(image, 2048)?;
+        if data.starts_with(b"hsqs") {
+            Ok(FsType::Squashfs)
+        } else if data.starts_with(b"UBI#") {
+            Err(format!(
+                "{} is a UBI image, use the UBIFS image of the rootfs volume",
+                image.display()
+            ))
+        } else if le32(&data, 0) == Some(UBIFS_MAGIC) {
+            Ok(FsType::Ubifs)
+        } else if le32(&data, 1024) == Some(EROFS_MAGIC) {
+            Ok(FsType::Erofs)
+        } else if le16(&data, 1080) == Some(0xef53) {
+            Ok(FsType::Ext4)
+        } else {
+            Err(format!(
+                "{} is none of ext4, squashfs, erofs or ubifs",
+                image.display()
+            ))
+        }
+    }
+
+    /// Programs that read and write images of this type.
+    pub fn tools(self) -> &'static [&'static str] {
+        match self {
+            FsType::Ext4 => &["debugfs", "mke2fs", "e2fsck", "dumpe2fs"],
+            FsType::Squashfs => &[
+                "unsquashfs",
+                "mksquashfs",
+                "fakeroot",
+                "getfattr",
+                "setfattr",
+            ],
+            FsType::Erofs => &[
+                "fsck.erofs",
+                "mkfs.erofs",
+                "fakeroot",
+                "getfattr",
+                "setfattr",
+            ],
+            FsType::Ubifs => &[
+                "ubireader_extract_files",
+                "mkfs.ubifs",
+                "fakeroot",
+                "getfattr",
+                "setfattr",
+            ],
+        }
+    }
+}
+
+// `path` quoted for sh.
+fn sh_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+fn fakeroot(
+    script: &Path,
+    total: u64,
+    measure: &dyn Fn() -> u64,
+    progress: Progress,
+) -> Result<(), String> {
+    let output = ext4::run_watched(
+        Command::new("fakeroot")
+            // The options say which time the tools give the image.
+            .env_remove("SOURCE_DATE_EPOCH")
+            .arg("--")
+            .arg("sh")
+            .arg("-e")
+            .arg(script),
+        total,
+        measure,
+        progress,
+    )?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let last: Vec<&str> = stderr.lines().rev().take(5).collect();
+        return Err(format!(
+            "{} failed: {}",
+            script.display(),
+            last.into_iter().rev().collect::<Vec<_>>().join("; ")
+        ));
+    }
+    Ok(())
+}
+
+// `\ooo` escapes of getfattr names and paths.
+fn unescape(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn hex_value(value: &str) -> Option<Vec<u8>> {
+    let hex = value.strip_prefix("0x")?;
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Read `stat` records `<mode> <uid> <gid> <major> <minor> <path>\0` and the
+// `getfattr -d -e hex` dump of the tree at `dest`.
+fn read_listing(dest: &Path, listing: &[u8], xattrs: &str) -> Result<Metadata, String> {
+    let mut metadata = Metadata::new();
+    let relative = |path: &[u8]| -> Option<PathBuf> {
+        let path = Path::new(std::ffi::OsStr::from_bytes(path));
+        path.strip_prefix(dest).ok().map(Path::to_path_buf)
+    };
+    for record in listing.split(|&b| b == 0).filter(|r| !r.is_empty()) {
+        let bad = || format!("bad stat record `{}`", String::from_utf8_lossy(record));
+        let mut fields = record.splitn(6, |&b| b == b' ');
+        let mut field = || {
+            fields
+                .next()
+                .and_then(|field| std::str::from_utf8(field).ok())
+                .ok_or_else(bad)
+        };
+        let mode = u32::from_str_radix(field()?, 16).map_err(|_| bad())?;
+        let uid = field()?.parse().map_err(|_| bad())?;
+        let gid = field()?.parse().map_err(|_| bad())?;
+        let major = u32::from_str_radix(field()?, 16).map_err(|_| bad())?;
+        let minor = u32::from_str_radix(field()?, 16).map_err(|_| bad())?;
+        let path = fields.next().and_then(relative).ok_or_else(bad)?;
+        let rdev = matches!(mode & S_IFMT, S_IFCHR | S_IFBLK).then_some((major, minor));
+        metadata.insert(
+            path,
+            Inode {
+                mode,
+                uid,
+                gid,
+                rdev,
+                xattrs: BTreeMap::new(),
+            },
+        );
+    }
+
+    // # file: /abs/path
+    // security.capability=0x0100000200200000...
+    let mut current = None;
+    for line in xattrs.lines() {
+        if let Some(path) = line.strip_prefix("# file: ") {
+            current = relative(&unescape(path));
+        } else if let (Some(path), Some((name, value))) = (&current, line.split_once('=')) {
+            let value = hex_value(value)
+                .ok_or_else(|| format!("bad xattr value of {}: {}", path.display(), value))?;
+            if let Some(inode) = metadata.get_mut(path) {
+                let name = String::from_utf8_lossy(&unescape(name)).into_owned();
+                inode.xattrs.insert(name, value);
+            }
+        }
+    }
+    Ok(metadata)
+}
+
+/// Dump `image` of type `fs_type` into `dest`, which must not exist yet.
+/// Returns what the copies in `dest` lack. Progress is in bytes of regular
+/// files, as far as the image size tells.
+pub fn extract(
+    fs_type: FsType,
+    image: &Path,
+    dest: &Path,
+    progress: Progress,
+) -> Result<Metadata, String> {
+    if fs_type == FsType::Ext4 {
+        return ext4::extract(image, dest, progress);
+    }
+    fs::create_dir_all(dest).map_err(|e| format!("{}: {}", dest.display(), e))?;
+    let (image_arg, dest_arg) = (sh_quote(image), sh_quote(dest));
+    let tool = match fs_type {
+        FsType::Squashfs => format!("unsquashfs -n -f -d {} {}", dest_arg, image_arg),
+        FsType::Erofs => format!("fsck.erofs --preserve --extract={} {}", dest_arg, image_arg),
+        FsType::Ubifs => format!("ubireader_extract_files -k -o {} {}", dest_arg, image_arg),
+        FsType::Ext4 => unreachable!(),
+    };
+    let listing = dest.with_extension("list");
+    let xattrs = dest.with_extension("xattrs");
+    let script = dest.with_extension("sh");
+    let text = format!(
+        "{tool} >&2\n\
+         find {dest} -exec stat --printf '%f %u %g %t %T %n\\0' {{}} + > {listing}\n\
+         getfattr -R -h -d -m - -e hex --absolute-names {dest} > {xattrs}\n",
+        tool = tool,
+        dest = dest_arg,
+        listing = sh_quote(&listing),
+        xattrs = sh_quote(&xattrs),
+    );
+    fs::write(&script, text).map_err(|e| format!("{}: {}", script.display(), e))?;
+    let total = fs::metadata(image).map(|m| m.len()).unwrap_or(0);
+    fakeroot(&script, total, &|| ext4::tree_size(dest), progress)?;
+    progress(total, total)?;
+
+    let read = |path: &Path| fs::read(path).map_err(|e| format!("{}: {}", path.display(), e));
+    let metadata = read_listing(
+        dest,
+        &read(&listing)?,
+        &String::from_utf8_lossy(&read(&xattrs)?),
+    )?;
+    for path in [&listing, &xattrs, &script] {
+        let _ = fs::remove_file(path);
+    }
+
+    // Device nodes are plain files and fifos real ones outside of the
+    // session, the metadata has them. Sockets are plain files too, real ones
+    // take their place.
+    for (path, inode) in &metadata {
+        if matches!(inode.mode & S_IFMT, S_IFCHR | S_IFBLK | S_IFIFO) {
+            let full = dest.join(path);
+            fs::remove_file(&full).map_err(|e| format!("{}: {}", full.display(), e))?;
+        }
+    }
+    ext4::stage_sockets(dest, &metadata)?;
+    ext4::stage_modes(dest, &metadata)?;
+    Ok(metadata)
+}
+
+// Settings of a squashfs image: compressor and block size.
+fn squashfs_options(base: &Path) -> Result<Vec<String>, String> {
+    let data = head(base, 96)?;
+    let bad = || format!("{}: bad squashfs superblock", base.display());
+    let block_size = le32(&data, 12).ok_or_else(bad)?;
+    let compressor = match le16(&data, 20).ok_or_else(bad)? {
+        1 => "gzip",
+        2 => "lzma",
+        3 => "lzo",
+        4 => "xz",
+        5 => "lz4",
+        6 => "zstd",
+        other => return Err(format!("{}: unknown compressor {}", base.display(), other)),
+    };
+    Ok(vec![
+        "-comp".into(),
+        compressor.into(),
+        "-b".into(),
+        block_size.to_string(),
+    ])
+}
+
+// Settings of an erofs image: block size, label and compressor.
+fn erofs_options(base: &Path) -> Result<Vec<String>, String> {
+    let data = head(base, 2048)?;
+    let sb = &data.get(1024..).unwrap_or_default();
+    let bad = || format!("{}: bad erofs superblock", base.display());
+    let block_bits = *sb.get(12).ok_or_else(bad)?;
+    let label = sb.get(64..80).ok_or_else(bad)?;
+    let incompat = le32(sb, 80).ok_or_else(bad)?;
+    let mut options = vec!["-b".to_string(), (1u32 << block_bits).to_string()];
+    let label = String::from_utf8_lossy(label);
+    let label = label.trim_end_matches('\0');
+    if !label.is_empty() {
+        options.push(format!("-L{}", label));
+    }
+    // Compression configs list the algorithms used, lz4 is the only one
+    // older images can have.
+    const COMPR_CFGS: u32 = 0x2;
+    const LZ4_0PADDING: u32 = 0x1;
+    let algorithms = if incompat & COMPR_CFGS != 0 {
+        le16(sb, 84).ok_or_else(bad)?
+    } else if incompat & LZ4_0PADDING != 0 {
+        1
+    } else {
+        0
+    };
+    let compressor = [(1, "lz4hc"), (2, "lzma"), (4, "deflate"), (8, "zstd")]
+        .into_iter()
+        .find(|(bit, _)| algorithms & bit != 0);
+    if let Some((_, compressor)) = compressor {
+        options.push(format!("-z{}", compressor));
+    }
+    Ok(options)
+}
+
+// Settings of a ubifs image: I/O unit, erase block size, the most erase
+// blocks it may grow to and the compressor, from the superblock node.
+fn ubifs_options(base: &Path) -> Result<Vec<String>, String> {
+    let data = head(base, 4096)?;
+    let bad = || format!("{}: bad ubifs superblock", base.display());
+    // The common header is 24 bytes, the superblock node follows.
+    const SB_NODE: u8 = 6;
+    if data.get(20) != Some(&SB_NODE) {
+        return Err(bad());
+    }
+    let min_io_size = le32(&data, 32).ok_or_else(bad)?;
+    let leb_size = le32(&data, 36).ok_or_else(bad)?;
+    let max_leb_count = le32(&data, 44).ok_or_else(bad)?;
+    let compressor = match le16(&data, 84).ok_or_else(bad)? {
+        0 => "none",
+        1 => "lzo",
+        2 => "zlib",
+        3 => "zstd",
+        other => return Err(format!("{}: unknown compressor {}", base.display(), other)),
+    };
+    Ok(vec![
+        "-m".into(),
+        min_io_size.to_string(),
+        "-e".into(),
+        leb_size.to_string(),
+        "-c".into(),
+        max_leb_count.to_string(),
+        "-x".into(),
+        compressor.into(),
+    ])
+}
+
+/// What a new image gets besides the tree.
+pub struct Settings {
+    /// The most bytes the image may take, None for no limit.
+    pub max_size: Option<u64>,
+    /// Seconds since 1970 of the superblock and of the inodes the tree
+    /// doesn't give a time.
+    pub epoch: i64,
+    pub uuid: String,
+    /// Directory hash seed of ext4 images.
+    pub hash_seed: String,
+}
+
+/// Write the tree in `staging` as a new `fs_type` image like `base`, or with
+/// the tools' defaults if `base` is of another type, with the
+/// owners, modes, device nodes and xattrs of `metadata` as `ext4::build`
+/// gives them, and the time, UUID and size limit of `settings`. Progress is
+/// in bytes of regular files, as far as the image size tells. Returns what
+/// went into the image.
+pub fn build(
+    fs_type: FsType,
+    base: &Path,
+    staging: &Path,
+    metadata: &Metadata,
+    settings: &Settings,
+    image: &Path,
+    progress: Progress,
+) -> Result<Vec<Wanted>, String> {
+    let base_type = FsType::detect(base)?;
+    let like_base = base_type == fs_type;
+    if fs_type == FsType::Ubifs && !like_base {
+        return Err(format!(
+            "a ubifs rootfs takes the flash geometry of a ubifs base image, {} is {}",
+            base.display(),
+            base_type
+        ));
+    }
+    if fs_type == FsType::Ext4 {
+        let mut params = if like_base {
+            Ext4Params::read(base)?
+        } else {
+            Ext4Params::standard()
+        };
+        params.uuid = settings.uuid.clone();
+        params.hash_seed = settings.hash_seed.clone();
+        return ext4::build(
+            staging,
+            metadata,
+            &params,
+            settings.max_size,
+            settings.epoch,
+            image,
+            progress,
+        );
+    }
+    let _ = fs::remove_file(image);
+
+    // The tools have to read everything, whatever mode the merge gave it.
+    let mut locked = HashMap::new();
+    ext4::unlock(staging, staging, &mut locked)?;
+    let total = ext4::tree_size(staging);
+
+    let mut script = String::new();
+    let wanted_inodes = ext4::wanted_inodes(staging, metadata, &locked)?;
+    for wanted in &wanted_inodes {
+        let inode = &wanted.inode;
+        let path = sh_quote(&staging.join(&wanted.path));
+        if wanted.current.is_none() {
+            // Times of the new node and of its directory stay as they were.
+            let parent = wanted.path.parent().unwrap_or(Path::new(""));
+            let parent_mtime = fs::symlink_metadata(staging.join(parent))
+                .map_err(|e| format!("{}: {}", staging.join(parent).display(), e))?
+                .mtime();
+            match inode.mode & S_IFMT {
+                S_IFCHR | S_IFBLK => {
+                    let (major, minor) = inode.rdev.unwrap_or_default();
+                    let kind = if inode.mode & S_IFMT == S_IFCHR {
+                        "c"
+                    } else {
+                        "b"
+                    };
+                    script.push_str(&format!("mknod {} {} {} {}\n", path, kind, major, minor));
+                }
+                _ => script.push_str(&format!("mkfifo {}\n", path)),
+            }
+            script.push_str(&format!(
+                "touch -h -d @{} {}\ntouch -h -d @{} {}\n",
+                settings.epoch,
+                path,
+                parent_mtime,
+                sh_quote(&staging.join(parent))
+            ));
+        }
+        // chown drops setuid bits, chmod comes after it.
+        script.push_str(&format!("chown -h {}:{} {}\n", inode.uid, inode.gid, path));
+        if inode.mode & S_IFMT != S_IFLNK {
+            script.push_str(&format!("chmod {:o} {}\n", inode.mode & 0o7777, path));
+        }
+        for (name, value) in &inode.xattrs {
+            let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+            script.push_str(&format!(
+                "setfattr -h -n {} -v 0x{} {}\n",
+                sh_quote(Path::new(name)),
+                hex,
+                path
+            ));
+        }
+    }
+    let (staging_arg, image_arg) = (sh_quote(staging), sh_quote(image));
+    let options = match fs_type {
+        FsType::Squashfs => {
+            let mut options = if like_base {
+                squashfs_options(base)?
+            } else {
+                vec![]
+            };
+            options.extend(["-mkfs-time".to_string(), settings.epoch.to_string()]);
+            options
+        }
+        FsType::Erofs => {
+            let mut options = if like_base {
+                erofs_options(base)?
+            } else {
+                vec![]
+            };
+            options.push(format!("-T{}", settings.epoch));
+            options.push(format!("-U{}", settings.uuid));
+            options
+        }
+        FsType::Ubifs => ubifs_options(base)?,
+        FsType::Ext4 => unreachable!(),
+    }
+    .iter()
+    .map(|option| sh_quote(Path::new(option)))
+    .collect::<Vec<_>>()
+    .join(" ");
+    script.push_str(&match fs_type {
+        FsType::Squashfs => format!(
+            "mksquashfs {} {} -noappend -no-progress -quiet {} >&2\n",
+            staging_arg, image_arg, options
+        ),
+        FsType::Erofs => format!("mkfs.erofs {} {} {} >&2\n", options, image_arg, staging_arg),
+        FsType::Ubifs => format!(
+            "mkfs.ubifs -r {} -o {} {} >&2\n",
+            staging_arg, image_arg, options
+        ),
+        FsType::Ext4 => unreachable!(),
+    });
+    let script_path = image.with_extension("sh");
+    fs::write(&script_path, script).map_err(|e| format!("{}: {}", script_path.display(), e))?;
+    let built = fakeroot(
+        &script_path,
+        total,
+        &|| fs::metadata(image).map(|m| m.len()).unwrap_or(0),
+        progress,
+    );
+    let _ = fs::remove_file(&script_path);
+    built?;
+
+    let size = fs::metadata(image)
+        .map_err(|e| format!("{}: {}", image.display(), e))?
+        .len();
+    info!("{} rootfs: {} MiB", fs_type, size >> 20);
+    if let Some(max_size) = settings.max_size.filter(|max_size| size > *max_size) {
+        return Err(format!(
+            "the rootfs is {} MiB, more than the {} MiB it may take",
+            size.div_ceil(1 << 20),
+            max_size >> 20
+        ));
+    }
+    progress(total, total)?;
+    Ok(wanted_inodes)
+}
+
+/// Check the filesystem in `image` without changing it. Returns a summary of
+/// what the check found.
+pub fn check(fs_type: FsType, image: &Path) -> Result<String, String> {
+    let mut command = match fs_type {
+        FsType::Ext4 => return ext4::check(image),
+        // Reads every directory and inode table.
+        FsType::Squashfs => {
+            let mut command = Command::new("unsquashfs");
+            command.arg("-lln").arg(image);
+            command
+        }
+        FsType::Erofs => {
+            let mut command = Command::new("fsck.erofs");
+            command.arg(image);
+            command
+        }
+        // There is no checker for an image that isn't on flash.
+        FsType::Ubifs => return Ok("ubifs, not checked".to_string()),
+    };
+    let output = command
+        .output()
+        .map_err(|e| format!("{}: {}", command.get_program().to_string_lossy(), e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        return Err(format!(
+            "{} isn't a valid {} image: {}",
+            image.display(),
+            fs_type,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(match fs_type {
+        FsType::Squashfs => format!("squashfs, {} entries", stdout.lines().count()),
+        _ => stdout
+            .lines()
+            .rfind(|line| !line.trim().is_empty())
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
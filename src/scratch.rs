@@ -0,0 +1,141 @@
+use crate::cache;
+use crate::config::Config;
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Everything an image preparation unpacks or writes goes below its own
+// scratch directory, `work_dir/scratch/{pid}-{n}-{board}-{version}`. The
+// directory is removed when the preparation ends, whether it failed or not,
+// and on Ctrl-C or SIGTERM. A directory whose process is gone was left by a
+// crash and is removed the next time rk_flash builds an image.
+
+// Scratch directories in use by this process.
+static ACTIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+fn scratch_root(config: &Config) -> PathBuf {
+    config.paths.work_dir.join("scratch")
+}
+
+/// A scratch directory, removed with everything in it when dropped.
+pub struct Scratch {
+    path: PathBuf,
+}
+
+impl Scratch {
+    pub fn create(config: &Config, name: &str) -> io::Result<Self> {
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = scratch_root(config).join(format!("{}-{}-{}", std::process::id(), n, name));
+        fs::create_dir_all(&path)?;
+        ACTIVE.lock().unwrap().push(path.clone());
+        Ok(Scratch { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        remove_tree(&self.path);
+        ACTIVE.lock().unwrap().retain(|path| *path != self.path);
+    }
+}
+
+/// Remove `path`, also when the staged rootfs has locked us out of some of
+/// its directories.
+pub fn remove_tree(path: &Path) {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return;
+    };
+    let removed = if metadata.is_dir() {
+        fs::remove_dir_all(path).or_else(|_| {
+            unlock(path);
+            fs::remove_dir_all(path)
+        })
+    } else {
+        fs::remove_file(path)
+    };
+    if let Err(e) = removed {
+        warn!("Can't remove {}: {}", path.display(), e);
+    }
+}
+
+fn unlock(dir: &Path) {
+    let Ok(metadata) = fs::symlink_metadata(dir) else {
+        return;
+    };
+    if !metadata.is_dir() {
+        return;
+    }
+    let mode = metadata.permissions().mode();
+    if mode & 0o700 != 0o700 {
+        let _ = fs::set_permissions(dir, fs::Permissions::from_mode(mode | 0o700));
+    }
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            unlock(&entry.path());
+        }
+    }
+}
+
+/// Remove the scratch directories of this process and exit on Ctrl-C or
+/// SIGTERM.
+pub fn handle_signals() {
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!("Can't handle SIGTERM: {}", e);
+                return;
+            }
+        };
+        let code = tokio::select! {
+            _ = tokio::signal::ctrl_c() => 130,
+            _ = terminate.recv() => 143,
+        };
+        let active = std::mem::take(&mut *ACTIVE.lock().unwrap());
+        if !active.is_empty() {
+            warn!("Interrupted, removing scratch files");
+        }
+        for path in active {
+            remove_tree(&path);
+        }
+        exit(code);
+    });
+}
+
+/// Remove what crashed or killed runs left in the scratch directory and the
+/// cache.
+pub fn clean_leftovers(config: &Config) {
+    let mut leftovers = vec![];
+
+    // Scratch directories of processes that are gone.
+    if let Ok(entries) = fs::read_dir(scratch_root(config)) {
+        for path in entries.flatten().map(|entry| entry.path()) {
+            let pid = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split('-').next())
+                .and_then(|pid| pid.parse::<u32>().ok());
+            if pid.is_none_or(|pid| !Path::new("/proc").join(pid.to_string()).exists()) {
+                leftovers.push(path);
+            }
+        }
+    }
+
+    for path in leftovers {
+        info!("Removing leftover {}", path.display());
+        remove_tree(&path);
+    }
+    cache::remove_incomplete(config);
+}
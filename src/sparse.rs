@@ -0,0 +1,321 @@
+use crate::archive::Progress;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+// Android sparse images, as upgrade_tool and fastboot take them. A header,
+// then chunks of whole blocks that are either raw data, one 32-bit value
+// repeated, or left as they are on the device:
+//
+//   file header   magic, version 1.0, header sizes, block size,
+//                 blocks in the image, chunks in the file, checksum 0
+//   chunk header  type, reserved, blocks, bytes including the header
+//
+// Blocks the filesystem doesn't use are left as they are, so only what is
+// in use goes over USB. Used blocks of one repeated value, e.g. zeroed inode
+// tables, are sent as a fill chunk and written on the device.
+
+const MAGIC: u32 = 0xed26_ff3a;
+const FILE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+
+// Raw chunks are split at this size, their byte count has to fit in 32 bits
+// and they are buffered whole.
+const MAX_RAW_CHUNK: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    Raw,
+    Fill(u32),
+    DontCare,
+}
+
+struct Writer {
+    out: BufWriter<File>,
+    block_size: usize,
+    chunks: u32,
+    kind: Option<Chunk>,
+    blocks: u32,
+    raw: Vec<u8>,
+}
+
+impl Writer {
+    // Append `blocks` blocks of `kind`, `data` is their contents for raw
+    // blocks.
+    fn push(&mut self, kind: Chunk, blocks: u32, data: &[u8]) -> std::io::Result<()> {
+        if self.kind != Some(kind) || self.raw.len() >= MAX_RAW_CHUNK {
+            self.flush_chunk()?;
+            self.kind = Some(kind);
+        }
+        self.blocks += blocks;
+        if kind == Chunk::Raw {
+            self.raw.extend_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self) -> std::io::Result<()> {
+        let Some(kind) = self.kind.take() else {
+            return Ok(());
+        };
+        let (chunk_type, body): (u16, &[u8]) = match kind {
+            Chunk::Raw => (CHUNK_RAW, &self.raw),
+            Chunk::Fill(value) => (CHUNK_FILL, &value.to_le_bytes()),
+            Chunk::DontCare => (CHUNK_DONT_CARE, &[]),
+        };
+        let mut header = Vec::with_capacity(CHUNK_HEADER_SIZE as usize);
+        header.extend_from_slice(&chunk_type.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&self.blocks.to_le_bytes());
+        header.extend_from_slice(&(CHUNK_HEADER_SIZE as u32 + body.len() as u32).to_le_bytes());
+        self.out.write_all(&header)?;
+        self.out.write_all(body)?;
+        self.chunks += 1;
+        self.blocks = 0;
+        self.raw.clear();
+        Ok(())
+    }
+}
+
+fn file_header(block_size: u32, blocks: u32, chunks: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(FILE_HEADER_SIZE as usize);
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&FILE_HEADER_SIZE.to_le_bytes());
+    header.extend_from_slice(&CHUNK_HEADER_SIZE.to_le_bytes());
+    header.extend_from_slice(&block_size.to_le_bytes());
+    header.extend_from_slice(&blocks.to_le_bytes());
+    header.extend_from_slice(&chunks.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header
+}
+
+// The value `block` repeats, if it is one 32-bit value over and over.
+fn fill_value(block: &[u8]) -> Option<u32> {
+    let first: [u8; 4] = block[..4].try_into().ok()?;
+    block
+        .chunks_exact(4)
+        .all(|word| word == first)
+        .then(|| u32::from_le_bytes(first))
+}
+
+fn error(path: &Path) -> impl Fn(std::io::Error) -> String + '_ {
+    move |e| format!("{}: {}", path.display(), e)
+}
+
+/// Whether `path` is a sparse image.
+pub fn is_sparse(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| u32::from_le_bytes(magic) == MAGIC)
+}
+
+/// Write the raw image `raw` as a sparse image to `out`, leaving out the
+/// `unused` block ranges. Progress is in bytes of the raw image. Returns the
+/// size of the sparse image.
+pub fn write(
+    raw: &Path,
+    out: &Path,
+    block_size: u64,
+    unused: &[Range<u64>],
+    progress: Progress,
+) -> Result<u64, String> {
+    let size = fs::metadata(raw).map_err(error(raw))?.len();
+    if block_size == 0 || size % block_size != 0 {
+        return Err(format!(
+            "{} isn't made of whole {} byte blocks",
+            raw.display(),
+            block_size
+        ));
+    }
+    let total_blocks = size / block_size;
+    let too_big = || format!("{} has too many blocks for a sparse image", raw.display());
+    let total_blocks_u32 = u32::try_from(total_blocks).map_err(|_| too_big())?;
+
+    let mut input = File::open(raw).map_err(error(raw))?;
+    let mut writer = Writer {
+        out: BufWriter::new(File::create(out).map_err(error(out))?),
+        block_size: block_size as usize,
+        chunks: 0,
+        kind: None,
+        blocks: 0,
+        raw: Vec::new(),
+    };
+    // Filled in once the chunks are counted.
+    writer
+        .out
+        .write_all(&file_header(block_size as u32, total_blocks_u32, 0))
+        .map_err(error(out))?;
+
+    let mut unused = unused.iter().peekable();
+    let mut block = vec![0u8; writer.block_size];
+    let mut index = 0;
+    while index < total_blocks {
+        while unused.next_if(|range| range.end <= index).is_some() {}
+        if let Some(range) = unused.peek().filter(|range| range.start <= index) {
+            let end = range.end.min(total_blocks);
+            writer
+                .push(Chunk::DontCare, (end - index) as u32, &[])
+                .map_err(error(out))?;
+            index = end;
+            input
+                .seek(SeekFrom::Start(index * block_size))
+                .map_err(error(raw))?;
+        } else {
+            input.read_exact(&mut block).map_err(error(raw))?;
+            match fill_value(&block) {
+                Some(value) => writer.push(Chunk::Fill(value), 1, &[]),
+                None => writer.push(Chunk::Raw, 1, &block),
+            }
+            .map_err(error(out))?;
+            index += 1;
+        }
+        progress(index * block_size, size)?;
+    }
+    writer.flush_chunk().map_err(error(out))?;
+
+    let chunks = writer.chunks;
+    let mut file = writer
+        .out
+        .into_inner()
+        .map_err(|e| format!("{}: {}", out.display(), e.error()))?;
+    file.seek(SeekFrom::Start(0)).map_err(error(out))?;
+    file.write_all(&file_header(block_size as u32, total_blocks_u32, chunks))
+        .map_err(error(out))?;
+    file.sync_all().map_err(error(out))?;
+    Ok(fs::metadata(out).map_err(error(out))?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const BLOCK: usize = 4096;
+
+    fn le16(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn le32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    // The chunks of `sparse` as type and blocks, and the raw image it makes
+    // on a zeroed device.
+    fn unsparse(sparse: &[u8]) -> (Vec<(u16, u32)>, Vec<u8>) {
+        assert_eq!(le32(sparse, 0), MAGIC);
+        assert_eq!((le16(sparse, 4), le16(sparse, 6)), (1, 0));
+        assert_eq!(le16(sparse, 8), FILE_HEADER_SIZE);
+        assert_eq!(le16(sparse, 10), CHUNK_HEADER_SIZE);
+        assert_eq!(le32(sparse, 12) as usize, BLOCK);
+        let (total_blocks, total_chunks) = (le32(sparse, 16), le32(sparse, 20));
+
+        let mut chunks = vec![];
+        let mut image = vec![];
+        let mut at = FILE_HEADER_SIZE as usize;
+        while at < sparse.len() {
+            let (kind, blocks, size) =
+                (le16(sparse, at), le32(sparse, at + 4), le32(sparse, at + 8));
+            let body = &sparse[at + CHUNK_HEADER_SIZE as usize..at + size as usize];
+            let bytes = blocks as usize * BLOCK;
+            match kind {
+                CHUNK_RAW => {
+                    assert_eq!(body.len(), bytes);
+                    image.extend_from_slice(body);
+                }
+                CHUNK_FILL => {
+                    assert_eq!(body.len(), 4);
+                    image.extend(body.iter().cycle().take(bytes));
+                }
+                CHUNK_DONT_CARE => {
+                    assert!(body.is_empty());
+                    image.resize(image.len() + bytes, 0);
+                }
+                _ => panic!("chunk type {:#x}", kind),
+            }
+            chunks.push((kind, blocks));
+            at += size as usize;
+        }
+        assert_eq!(chunks.len() as u32, total_chunks);
+        assert_eq!(image.len(), total_blocks as usize * BLOCK);
+        (chunks, image)
+    }
+
+    #[test]
+    fn fill_values() {
+        assert_eq!(fill_value(&[0; BLOCK]), Some(0));
+        assert_eq!(
+            fill_value(&[1, 2, 3, 4].repeat(BLOCK / 4)),
+            Some(0x0403_0201)
+        );
+        let mut block = vec![0; BLOCK];
+        block[BLOCK - 1] = 1;
+        assert_eq!(fill_value(&block), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new();
+        // Two raw blocks, three zeroed, five unused in two ranges, one of a
+        // repeated value, then more raw blocks than fit in one chunk.
+        let raw_blocks = MAX_RAW_CHUNK / BLOCK + 2;
+        let raw = |count: usize, seed: usize| -> Vec<u8> {
+            (0..count * BLOCK)
+                .map(|i| (i * 7 + i / 251 + seed) as u8)
+                .collect()
+        };
+        let mut image = raw(2, 1);
+        image.extend(vec![0; 3 * BLOCK]);
+        image.extend(vec![0xab; 5 * BLOCK]);
+        image.extend([1, 2, 3, 4].repeat(BLOCK / 4));
+        image.extend(raw(raw_blocks, 2));
+        let raw_path = dir.path().join("rootfs.img");
+        let sparse_path = dir.path().join("rootfs.sparse.img");
+        fs::write(&raw_path, &image).unwrap();
+
+        let size = write(
+            &raw_path,
+            &sparse_path,
+            BLOCK as u64,
+            &[5..7, 7..10],
+            &mut |_, _| Ok(()),
+        )
+        .unwrap();
+        let sparse = fs::read(&sparse_path).unwrap();
+        assert_eq!(size, sparse.len() as u64);
+        assert!(is_sparse(&sparse_path));
+        assert!(!is_sparse(&raw_path));
+
+        let (chunks, unpacked) = unsparse(&sparse);
+        assert_eq!(
+            chunks,
+            [
+                (CHUNK_RAW, 2),
+                (CHUNK_FILL, 3),
+                (CHUNK_DONT_CARE, 5),
+                (CHUNK_FILL, 1),
+                (CHUNK_RAW, (MAX_RAW_CHUNK / BLOCK) as u32),
+                (CHUNK_RAW, 2),
+            ]
+        );
+        // The unused blocks are left as they are on the device.
+        image[5 * BLOCK..10 * BLOCK].fill(0);
+        assert!(unpacked == image);
+    }
+
+    #[test]
+    fn partial_blocks_are_refused() {
+        let dir = TempDir::new();
+        let raw_path = dir.path().join("rootfs.img");
+        fs::write(&raw_path, vec![0; BLOCK + 1]).unwrap();
+        let out = dir.path().join("rootfs.sparse.img");
+        assert!(write(&raw_path, &out, BLOCK as u64, &[], &mut |_, _| Ok(())).is_err());
+    }
+}
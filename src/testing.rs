@@ -0,0 +1,74 @@
+//! Fixtures shared by the unit tests.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// An empty directory of its own below the system's temporary directory,
+/// removed with everything in it when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "rk_flash-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// What a member of a test archive is.
+#[derive(Debug, Clone, Copy)]
+pub enum Member<'a> {
+    Dir,
+    File(&'a str),
+    Symlink(&'a str),
+}
+
+/// A tar archive of `entries` in that order. Names and link targets go in
+/// as they are, `tar::Builder` would refuse the bad ones.
+pub fn tar_archive(entries: &[(&str, Member)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(vec![]);
+    for &(name, member) in entries {
+        let mut header = tar::Header::new_ustar();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_mtime(0);
+        let data = match member {
+            Member::Dir => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                ""
+            }
+            Member::File(contents) => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(0o644);
+                contents
+            }
+            Member::Symlink(target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_mode(0o777);
+                header.as_old_mut().linkname[..target.len()].copy_from_slice(target.as_bytes());
+                ""
+            }
+        };
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, data.as_bytes()).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
@@ -0,0 +1,538 @@
+use crate::config::Config;
+use crate::events::{Event, FlashEvent};
+use crate::jobs::JobManager;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Results are pushed to the MES as JSON records, one per finished device and
+// one per batch, also one that failed before any device was flashed. Every
+// record is written to the spool directory first and only removed once the
+// endpoint accepted it, so nothing is lost while the network is down.
+//
+// Configured in the `[webhooks]` table of the station config:
+//
+//   [webhooks]
+//   retry_interval_secs = 30
+//
+//   [[webhooks.endpoints]]
+//   url = "http://mes.local/api/flash-results"
+//   on = ["device"]
+//   auth_header = "Bearer <token>"
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    Device,
+    Batch,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Endpoint {
+    pub url: String,
+    /// Which records go to this endpoint, both by default.
+    #[serde(default = "all_kinds")]
+    pub on: Vec<RecordKind>,
+    /// Value of the `Authorization` header, e.g. `Bearer <token>`.
+    #[serde(default)]
+    pub auth_header: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Request body with `{{field}}` placeholders for the record fields, and
+    /// `{{record}}` for the whole record as JSON. The record itself is sent
+    /// when there's no template.
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    /// `webhook-spool` in the work directory by default.
+    #[serde(default)]
+    pub spool_dir: PathBuf,
+    /// Pause between delivery rounds while records are pending.
+    #[serde(default = "default_retry_interval_secs")]
+    pub retry_interval_secs: u64,
+    /// Give up on a record after this many failed attempts, 0 retries forever.
+    #[serde(default)]
+    pub max_attempts: u32,
+}
+
+fn all_kinds() -> Vec<RecordKind> {
+    vec![RecordKind::Device, RecordKind::Batch]
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+fn default_retry_interval_secs() -> u64 {
+    30
+}
+
+impl WebhookConfig {
+    /// The webhook configuration, `None` when no endpoint is configured.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config
+            .webhooks
+            .clone()
+            .filter(|webhooks| !webhooks.endpoints.is_empty())
+    }
+}
+
+/// What gets reported about a finished device or batch.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResultRecord {
+    pub kind: RecordKind,
+    pub job_id: String,
+    pub board: String,
+    pub version: String,
+    pub recipe: String,
+    pub dry_run: bool,
+    pub finished_at: String,
+    pub success: bool,
+    pub duration_ms: u64,
+    // Device records only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dev_no: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loc_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_no: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Batch records only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub succeeded: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed: Option<usize>,
+}
+
+/// A record waiting in the spool directory for one endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SpooledRecord {
+    url: String,
+    body: String,
+    #[serde(default)]
+    attempts: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+struct JobContext {
+    board: String,
+    version: String,
+    recipe: String,
+    dry_run: bool,
+}
+
+pub struct Reporter {
+    config: WebhookConfig,
+    jobs: Mutex<HashMap<String, JobContext>>,
+    // One delivery round at a time.
+    delivery: Mutex<()>,
+    sequence: AtomicU64,
+}
+
+// Fill `{{field}}` placeholders in one pass, so placeholders in the values
+// are left as they are. Strings are JSON-escaped without the quotes, so
+// templates put them inside their own quotes. Fields the record doesn't have
+// (e.g. `serial_no` in a batch record) become empty.
+fn render_template(template: &str, record: &ResultRecord) -> String {
+    let value = serde_json::to_value(record).expect("records always serialize");
+    let mut body = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        body.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let name_len = after
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        let name = &after[..name_len];
+        if name.is_empty() || !after[name_len..].starts_with("}}") {
+            body.push('{');
+            rest = &rest[start + 1..];
+            continue;
+        }
+        match (name, value.get(name)) {
+            ("record", _) => body.push_str(&value.to_string()),
+            (_, Some(Value::String(s))) => {
+                let quoted = serde_json::to_string(s).unwrap();
+                body.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            (_, Some(other)) => body.push_str(&other.to_string()),
+            (_, None) => {}
+        }
+        rest = &after[name_len + 2..];
+    }
+    body.push_str(rest);
+    body
+}
+
+// Write a record so that a crash leaves either the old file or the new one.
+fn write_record(path: &Path, record: &SpooledRecord) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string(record).unwrap())?;
+    fs::rename(&tmp, path)
+}
+
+impl Reporter {
+    pub fn new(config: WebhookConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            jobs: Mutex::new(HashMap::new()),
+            delivery: Mutex::new(()),
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    /// Spool records for the finished devices and batches in `event`, and
+    /// for batches that failed before flashing.
+    pub fn handle(&self, event: &Event) {
+        let record = match &event.event {
+            FlashEvent::JobStarted {
+                job_id,
+                board,
+                version,
+                recipe,
+                dry_run,
+                ..
+            } => {
+                self.jobs.lock().unwrap().insert(
+                    job_id.clone(),
+                    JobContext {
+                        board: board.clone(),
+                        version: version.clone(),
+                        recipe: recipe.clone(),
+                        dry_run: *dry_run,
+                    },
+                );
+                return;
+            }
+            FlashEvent::DeviceResult {
+                job_id,
+                device,
+                success,
+                duration_ms,
+                error,
+            } => {
+                let job = self.job_context(job_id);
+                ResultRecord {
+                    kind: RecordKind::Device,
+                    job_id: job_id.clone(),
+                    board: job.board,
+                    version: job.version,
+                    recipe: job.recipe,
+                    dry_run: job.dry_run,
+                    finished_at: event.timestamp.clone(),
+                    success: *success,
+                    duration_ms: *duration_ms,
+                    dev_no: Some(device.dev_no.clone()),
+                    loc_id: Some(device.loc_id.clone()),
+                    serial_no: Some(device.serial_no.clone()),
+                    error: error.clone(),
+                    total: None,
+                    succeeded: None,
+                    failed: None,
+                }
+            }
+            FlashEvent::BatchSummary {
+                job_id,
+                total,
+                succeeded,
+                failed,
+                duration_ms,
+            } => {
+                let job = self.job_context(job_id);
+                self.jobs.lock().unwrap().remove(job_id);
+                ResultRecord {
+                    kind: RecordKind::Batch,
+                    job_id: job_id.clone(),
+                    board: job.board,
+                    version: job.version,
+                    recipe: job.recipe,
+                    dry_run: job.dry_run,
+                    finished_at: event.timestamp.clone(),
+                    success: *failed == 0,
+                    duration_ms: *duration_ms,
+                    dev_no: None,
+                    loc_id: None,
+                    serial_no: None,
+                    error: None,
+                    total: Some(*total),
+                    succeeded: Some(*succeeded),
+                    failed: Some(*failed),
+                }
+            }
+            FlashEvent::JobFailed {
+                job_id,
+                board,
+                version,
+                recipe,
+                dry_run,
+                devices,
+                duration_ms,
+                error,
+            } => ResultRecord {
+                kind: RecordKind::Batch,
+                job_id: job_id.clone(),
+                board: board.clone(),
+                version: version.clone(),
+                recipe: recipe.clone(),
+                dry_run: *dry_run,
+                finished_at: event.timestamp.clone(),
+                success: false,
+                duration_ms: *duration_ms,
+                dev_no: None,
+                loc_id: None,
+                serial_no: None,
+                error: Some(error.clone()),
+                total: Some(devices.len()),
+                succeeded: Some(0),
+                failed: Some(devices.len()),
+            },
+            _ => return,
+        };
+        self.spool(&record);
+    }
+
+    fn job_context(&self, job_id: &str) -> JobContext {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(job_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn spool(&self, record: &ResultRecord) {
+        if let Err(e) = fs::create_dir_all(&self.config.spool_dir) {
+            warn!(
+                "Can't create webhook spool {}: {}",
+                self.config.spool_dir.display(),
+                e
+            );
+            return;
+        }
+        for endpoint in &self.config.endpoints {
+            if !endpoint.on.contains(&record.kind) {
+                continue;
+            }
+            let body = match &endpoint.template {
+                Some(template) => render_template(template, record),
+                None => serde_json::to_string(record).unwrap(),
+            };
+            let spooled = SpooledRecord {
+                url: endpoint.url.clone(),
+                body,
+                attempts: 0,
+            };
+            // Names sort in creation order, so records are delivered in order.
+            let name = format!(
+                "{}-{:06}.json",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+                self.sequence.fetch_add(1, Ordering::SeqCst)
+            );
+            let path = self.config.spool_dir.join(name);
+            if let Err(e) = write_record(&path, &spooled) {
+                warn!("Can't spool webhook record {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn pending(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(&self.config.spool_dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        files.sort();
+        files
+    }
+
+    fn send(&self, record: &SpooledRecord) -> Result<(), String> {
+        let endpoint = self
+            .config
+            .endpoints
+            .iter()
+            .find(|endpoint| endpoint.url == record.url);
+        let timeout =
+            Duration::from_secs(endpoint.map_or(default_timeout_secs(), |e| e.timeout_secs));
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(timeout))
+            .build()
+            .into();
+
+        let mut request = agent.post(&record.url).header(
+            "Content-Type",
+            endpoint.map_or("application/json", |e| e.content_type.as_str()),
+        );
+        if let Some(endpoint) = endpoint {
+            if let Some(auth) = &endpoint.auth_header {
+                request = request.header("Authorization", auth);
+            }
+            for (name, value) in &endpoint.headers {
+                request = request.header(name, value);
+            }
+        }
+        request
+            .send(record.body.as_str())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Try to deliver every spooled record once, oldest first. Returns the
+    /// number of records still pending.
+    pub fn flush(&self) -> usize {
+        let _round = self.delivery.lock().unwrap();
+        let mut pending = 0;
+        for path in self.pending() {
+            let mut record: SpooledRecord = match fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+            {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!(
+                        "Dropping unreadable webhook record {}: {}",
+                        path.display(),
+                        e
+                    );
+                    let _ = fs::rename(&path, path.with_extension("bad"));
+                    continue;
+                }
+            };
+            match self.send(&record) {
+                Ok(()) => {
+                    debug!("Delivered webhook record {}", path.display());
+                    let _ = fs::remove_file(&path);
+                }
+                Err(e) => {
+                    record.attempts += 1;
+                    warn!(
+                        "Webhook {} failed (attempt {}): {}",
+                        record.url, record.attempts, e
+                    );
+                    if self.config.max_attempts != 0 && record.attempts >= self.config.max_attempts
+                    {
+                        warn!("Giving up on webhook record {}", path.display());
+                        let _ = fs::rename(&path, path.with_extension("failed"));
+                    } else {
+                        let _ = write_record(&path, &record);
+                        pending += 1;
+                    }
+                }
+            }
+        }
+        pending
+    }
+
+    /// Deliver spooled records in the background, including ones left over
+    /// from earlier runs.
+    pub fn spawn_worker(self: &Arc<Self>) {
+        let reporter = self.clone();
+        thread::spawn(move || loop {
+            let pending = reporter.flush();
+            if pending > 0 {
+                info!("{} webhook record(s) pending", pending);
+            }
+            thread::sleep(Duration::from_secs(
+                reporter.config.retry_interval_secs.max(1),
+            ));
+        });
+    }
+
+    /// If webhooks are configured, report every job run by `jobs`. Records
+    /// are spooled as the job records its events, and delivered by the
+    /// worker.
+    pub fn start_for(jobs: &JobManager) {
+        if let Some(config) = WebhookConfig::from_config(jobs.config()) {
+            let reporter = Reporter::new(config);
+            jobs.set_reporter(reporter.clone());
+            reporter.spawn_worker();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::DeviceRef;
+    use crate::testing::TempDir;
+
+    fn spooled(spool_dir: &Path) -> Vec<Value> {
+        let mut files: Vec<PathBuf> = fs::read_dir(spool_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        files
+            .iter()
+            .map(|path| {
+                let record: SpooledRecord =
+                    serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+                serde_json::from_str(&record.body).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batches_failing_before_they_start_are_spooled() {
+        let spool_dir = TempDir::new();
+        let mut config: WebhookConfig =
+            toml::from_str("[[endpoints]]\nurl = \"http://mes.local/results\"").unwrap();
+        config.spool_dir = spool_dir.path().to_path_buf();
+        let reporter = Reporter::new(config);
+
+        let device = |loc_id: &str| DeviceRef {
+            dev_no: "1".to_string(),
+            loc_id: loc_id.to_string(),
+            serial_no: String::new(),
+        };
+        reporter.handle(&Event::new(FlashEvent::JobFailed {
+            job_id: "job".to_string(),
+            board: "rk3568".to_string(),
+            version: "v1.0".to_string(),
+            recipe: "all".to_string(),
+            dry_run: false,
+            devices: vec![device("1-1"), device("1-2")],
+            duration_ms: 5,
+            error: "no cached image".to_string(),
+        }));
+
+        let records = spooled(spool_dir.path());
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record["kind"], "batch");
+        assert_eq!(record["job_id"], "job");
+        assert_eq!(record["board"], "rk3568");
+        assert_eq!(record["version"], "v1.0");
+        assert_eq!(record["success"], false);
+        assert_eq!(record["error"], "no cached image");
+        assert_eq!(record["total"], 2);
+        assert_eq!(record["failed"], 2);
+    }
+}